@@ -28,7 +28,7 @@ use mail_parser::{
     decoders::html::html_to_text,
     parsers::{fields::thread::thread_name, preview::preview_text},
     Addr, Address, GetHeader, Group, Header, HeaderName, HeaderValue, Message, MessagePart,
-    PartType,
+    MimeHeaders, PartType,
 };
 use nlp::language::Language;
 use store::{
@@ -50,6 +50,7 @@ pub const MAX_ID_LENGTH: usize = 100;
 pub const MAX_SORT_FIELD_LENGTH: usize = 255;
 pub const MAX_STORED_FIELD_LENGTH: usize = 512;
 pub const PREVIEW_LENGTH: usize = 256;
+pub const MAX_EMBEDDED_DEPTH: usize = 3;
 
 #[derive(Debug)]
 pub struct SortedAddressBuilder {
@@ -288,6 +289,7 @@ impl IndexMessage for BatchBuilder {
 impl<'x> IndexMessageText<'x> for FtsDocument<'x, HeaderName<'x>> {
     fn index_message(mut self, message: &'x Message<'x>) -> Self {
         let mut language = Language::Unknown;
+        let mut envelope_to = Vec::new();
 
         for (part_id, part) in message.parts.iter().take(MAX_MESSAGE_PARTS).enumerate() {
             let part_language = part.language().unwrap_or(language);
@@ -295,6 +297,21 @@ impl<'x> IndexMessageText<'x> for FtsDocument<'x, HeaderName<'x>> {
                 language = part_language;
 
                 for header in part.headers.iter().rev() {
+                    // Envelope addresses added at delivery, the topmost Return-Path
+                    // is the MAIL FROM of the last hop
+                    match &header.name {
+                        HeaderName::ReturnPath => {
+                            if let Some(address) = header.value.as_text() {
+                                self = self.with_envelope_from(address);
+                            }
+                        }
+                        HeaderName::Other(name) if name.eq_ignore_ascii_case("Delivered-To") => {
+                            if let Some(address) = header.value.as_text() {
+                                envelope_to.push(address.trim());
+                            }
+                        }
+                        _ => {}
+                    }
                     if matches!(header.name, HeaderName::Other(_)) {
                         continue;
                     }
@@ -345,6 +362,19 @@ impl<'x> IndexMessageText<'x> for FtsDocument<'x, HeaderName<'x>> {
                 }
             }
 
+            if message.attachments.contains(&part_id) {
+                self.index_attachment(
+                    part.attachment_name().map(Cow::from),
+                    part.content_type().map(|ct| {
+                        ct.subtype()
+                            .map(|st| format!("{}/{}", ct.ctype(), st))
+                            .unwrap_or_else(|| ct.ctype().to_string())
+                            .to_ascii_lowercase()
+                            .into()
+                    }),
+                );
+            }
+
             match &part.body {
                 PartType::Text(text) => {
                     if message.text_body.contains(&part_id) || message.html_body.contains(&part_id)
@@ -365,33 +395,72 @@ impl<'x> IndexMessageText<'x> for FtsDocument<'x, HeaderName<'x>> {
                     }
                 }
                 PartType::Message(nested_message) => {
-                    let nested_message_language = nested_message
-                        .root_part()
-                        .language()
-                        .unwrap_or(Language::Unknown);
-                    if let Some(HeaderValue::Text(subject)) =
-                        nested_message.header(HeaderName::Subject)
-                    {
-                        self.index(Field::Attachment, subject.as_ref(), nested_message_language);
-                    }
+                    self.set_embedded(true);
+                    index_embedded_message(&mut self, nested_message, 1);
+                    self.set_embedded(false);
+                }
+                PartType::Binary(data) | PartType::InlineBinary(data)
+                    if message.attachments.contains(&part_id) =>
+                {
+                    self.index_attachment_data(data.as_ref());
+                }
+                _ => {}
+            }
+        }
 
-                    for sub_part in nested_message.parts.iter().take(MAX_MESSAGE_PARTS) {
-                        let language = sub_part.language().unwrap_or(nested_message_language);
-                        match &sub_part.body {
-                            PartType::Text(text) => {
-                                self.index(Field::Attachment, text.as_ref(), language);
-                            }
-                            PartType::Html(html) => {
-                                self.index(Field::Attachment, html_to_text(html), language);
-                            }
-                            _ => (),
-                        }
+        if envelope_to.is_empty() {
+            self
+        } else {
+            self.with_envelope_to(envelope_to)
+        }
+    }
+}
+
+fn index_embedded_message<'x>(
+    document: &mut FtsDocument<'x, HeaderName<'x>>,
+    message: &'x Message<'x>,
+    depth: usize,
+) {
+    let language = message.root_part().language().unwrap_or(Language::Unknown);
+
+    for header in message.root_part().headers.iter().rev() {
+        match &header.name {
+            HeaderName::MessageId => {
+                header.value.visit_text(|id| {
+                    if id.len() < MAX_TOKEN_LENGTH {
+                        document.index_keyword(Field::Header(header.name.clone()), id.to_string());
                     }
+                });
+            }
+            HeaderName::From | HeaderName::To | HeaderName::Cc => {
+                header.value.visit_addresses(|_, value| {
+                    document.index_tokenized(Field::Header(header.name.clone()), value.to_string());
+                });
+            }
+            HeaderName::Subject => {
+                if let Some(subject) = header.value.as_text() {
+                    document.index(Field::Header(HeaderName::Subject), subject, language);
                 }
-                _ => {}
             }
+            _ => (),
+        }
+    }
+
+    for sub_part in message.parts.iter().take(MAX_MESSAGE_PARTS) {
+        let language = sub_part.language().unwrap_or(language);
+        match &sub_part.body {
+            PartType::Text(text) => {
+                document.index(Field::Body, text.as_ref(), language);
+            }
+            PartType::Html(html) => {
+                document.index(Field::Body, html_to_text(html), language);
+            }
+            // Deeper nesting is ignored to bound the work done on hostile messages
+            PartType::Message(nested_message) if depth < MAX_EMBEDDED_DEPTH => {
+                index_embedded_message(document, nested_message, depth + 1);
+            }
+            _ => (),
         }
-        self
     }
 }
 
@@ -558,6 +558,14 @@ impl JMAP {
                         );
                         MethodError::ServerUnavailable
                     }
+                    store::Error::Timeout => {
+                        tracing::error!(
+                            event = "timeout",
+                            context = "write_batch",
+                            "Timed out writing batch."
+                        );
+                        MethodError::ServerUnavailable
+                    }
                 }
             })
     }
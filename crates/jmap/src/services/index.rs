@@ -35,6 +35,7 @@ use utils::{BlobHash, BLOB_HASH_LEN};
 
 use crate::{
     email::{index::IndexMessageText, metadata::MessageMetadata},
+    mailbox::UidMailbox,
     JMAP,
 };
 
@@ -147,14 +148,42 @@ impl JMAP {
                         continue;
                     };
                     let message = metadata.inner.contents.into_message(&raw_message);
+                    let thread_id = self
+                        .get_property::<u32>(
+                            event.account_id,
+                            Collection::Email,
+                            event.document_id,
+                            Property::ThreadId,
+                        )
+                        .await
+                        .unwrap_or_default();
 
                     // Index message
-                    let document =
+                    let mut document =
                         FtsDocument::with_default_language(self.core.jmap.default_language)
                             .with_account_id(event.account_id)
                             .with_collection(Collection::Email)
                             .with_document_id(event.document_id)
+                            .with_received_at(metadata.inner.received_at as i64)
+                            .with_size(metadata.inner.size as u64)
+                            .with_version(event.seq)
                             .index_message(&message);
+                    if let Some(thread_id) = thread_id {
+                        document = document.with_thread_id(thread_id);
+                    }
+                    if let Ok(Some(mailboxes)) = self
+                        .get_property::<Vec<UidMailbox>>(
+                            event.account_id,
+                            Collection::Email,
+                            event.document_id,
+                            Property::MailboxIds,
+                        )
+                        .await
+                    {
+                        document = document.with_mailbox_ids(
+                            mailboxes.into_iter().map(|mailbox| mailbox.mailbox_id),
+                        );
+                    }
                     if let Err(err) = self.core.storage.fts.index(document).await {
                         tracing::error!(
                             context = "fts_index_queued",
@@ -0,0 +1,246 @@
+/*
+ * Copyright (c) 2023 Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Mail Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use elasticsearch::{
+    http::{headers::HeaderMap, request::JsonBody, Method},
+    snapshot::SnapshotCreateParts,
+};
+use serde_json::{json, Value};
+use utils::config::Config;
+
+use crate::write::now;
+
+use super::{
+    assert_success, compat::CompatibleRequest, metrics::Operation, ElasticSearchStore, Flavor,
+};
+
+// Archived collections are snapshotted to a repository on cheap storage and mounted
+// back as searchable snapshot indices named "<index>_archive_<timestamp>", which
+// searches on the local cluster include next to the live index.
+//
+// The repository has to be registered on the cluster before it is configured here,
+// for example with `PUT _snapshot/<name>` using the "s3" type and a bucket, or the
+// "fs" type with a location listed in "path.repo" on every node. Mounting requires
+// a license that includes searchable snapshots and is not supported on OpenSearch,
+// snapshots can still be taken and restored from the cluster.
+//
+// Mounted indices are read-only. Removals and keyword updates only change the live
+// index and lookups by id only read it, so documents should be removed from the
+// live index once their archive is mounted, documents in both are matched twice.
+// An archive is dropped by deleting its mounted index.
+pub(crate) struct SnapshotArchive {
+    pub repository: String,
+    // "full_copy" keeps a local copy of the index, "shared_cache" only caches the
+    // parts searched and needs nodes in the frozen tier
+    storage: &'static str,
+}
+
+impl SnapshotArchive {
+    pub(super) fn parse(config: &mut Config, prefix: &str) -> Option<Self> {
+        let repository = config.value((prefix, "snapshot.repository"))?.to_string();
+        let storage = match config.value((prefix, "snapshot.storage")) {
+            Some("full-copy") | None => "full_copy",
+            Some("shared-cache") => "shared_cache",
+            Some(storage) => {
+                config.new_parse_error(
+                    (prefix, "snapshot.storage"),
+                    format!("Unknown storage {storage:?}, expected full-copy or shared-cache"),
+                );
+                return None;
+            }
+        };
+
+        Some(SnapshotArchive {
+            repository,
+            storage,
+        })
+    }
+}
+
+impl ElasticSearchStore {
+    /// Starts a snapshot of the index of a collection in the configured repository,
+    /// returning the snapshot name to mount with `fts_mount_snapshot` once it has
+    /// completed. Collections stored in data streams are archived by their
+    /// retention instead.
+    pub async fn fts_snapshot_collection(&self, collection: u8) -> crate::Result<String> {
+        let archive = self.archive()?;
+        let index = self.search_index(collection)?;
+        if self.is_data_stream(collection) {
+            return Err(crate::Error::InternalError(format!(
+                "Collection {collection} is stored in a data stream and can't be archived"
+            )));
+        }
+
+        let snapshot = format!("{index}_{}", now());
+        let body = json!({
+            // The alias is resolved to the index it points to
+            "indices": &index,
+            "include_global_state": false
+        });
+        let client = self.client();
+        let snapshots = client.snapshot();
+        let response = self
+            .send_with_retry(Operation::Manage, || {
+                snapshots
+                    .create(SnapshotCreateParts::RepositorySnapshot(
+                        &archive.repository,
+                        &snapshot,
+                    ))
+                    .wait_for_completion(false)
+                    .request_timeout(self.request_timeout)
+                    .body(&body)
+                    .compatible_with(self.compatible_with)
+                    .send()
+            })
+            .await?;
+        assert_success(response, "Failed to snapshot index").await?;
+
+        tracing::info!(
+            context = "elasticsearch",
+            event = "snapshot",
+            index = index,
+            repository = archive.repository,
+            snapshot = snapshot,
+            "Started index snapshot"
+        );
+        Ok(snapshot)
+    }
+
+    /// Mounts a completed snapshot of a collection as a searchable index, returning
+    /// its name. Searches of the collection include it from then on.
+    pub async fn fts_mount_snapshot(
+        &self,
+        collection: u8,
+        snapshot: &str,
+    ) -> crate::Result<String> {
+        let archive = self.archive()?;
+        let index = self.search_index(collection)?;
+        if self.flavor() == Flavor::OpenSearch {
+            return Err(crate::Error::InternalError(
+                "Mounting searchable snapshots is not supported on OpenSearch".to_string(),
+            ));
+        }
+
+        // The snapshot holds the versioned index the alias pointed to
+        let json = self
+            .send_manage(
+                Method::Get,
+                &format!("/_snapshot/{}/{snapshot}", archive.repository),
+                None,
+                "Failed to obtain snapshot",
+            )
+            .await?;
+        let info = &json["snapshots"][0];
+        if info["state"] != "SUCCESS" {
+            return Err(crate::Error::InternalError(format!(
+                "Snapshot {snapshot} can't be mounted in state {}",
+                info["state"].as_str().unwrap_or("unknown")
+            )));
+        }
+        let versioned = format!("{index}_v");
+        let source = info["indices"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(Value::as_str)
+            .find(|name| *name == index || name.starts_with(&versioned))
+            .ok_or_else(|| {
+                crate::Error::InternalError(format!(
+                    "Snapshot {snapshot} does not contain index {index}"
+                ))
+            })?;
+
+        let mounted = format!(
+            "{index}_archive_{}",
+            snapshot
+                .strip_prefix(&index)
+                .and_then(|suffix| suffix.strip_prefix('_'))
+                .unwrap_or(snapshot)
+        );
+        // The repository keeps the data safe, so the mounted index needs no replicas
+        self.send_manage(
+            Method::Post,
+            &format!(
+                "/_snapshot/{}/{snapshot}/_mount?wait_for_completion=true&storage={}",
+                archive.repository, archive.storage
+            ),
+            Some(json!({
+                "index": source,
+                "renamed_index": &mounted,
+                "index_settings": { "index.number_of_replicas": 0 }
+            })),
+            "Failed to mount snapshot",
+        )
+        .await?;
+
+        tracing::info!(
+            context = "elasticsearch",
+            event = "mount",
+            index = mounted,
+            snapshot = snapshot,
+            "Mounted index snapshot"
+        );
+        Ok(mounted)
+    }
+
+    // Local index to search for a collection, along with its mounted archives
+    pub(super) fn with_archives(&self, index: String) -> String {
+        if self.archive.is_some() {
+            format!("{index},{index}_archive_*")
+        } else {
+            index
+        }
+    }
+
+    fn archive(&self) -> crate::Result<&SnapshotArchive> {
+        self.archive.as_ref().ok_or_else(|| {
+            crate::Error::InternalError(
+                "No snapshot repository is configured, set snapshot.repository".to_string(),
+            )
+        })
+    }
+
+    // The snapshot APIs are sent directly, mounting is experimental in the client
+    async fn send_manage(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<Value>,
+        context: &str,
+    ) -> crate::Result<Value> {
+        let client = self.client();
+        let response = self
+            .send_with_retry(Operation::Manage, || {
+                client.send(
+                    method,
+                    path,
+                    HeaderMap::new().compatible_with(self.compatible_with),
+                    None::<&()>,
+                    body.clone().map(JsonBody::new),
+                    Some(self.request_timeout),
+                )
+            })
+            .await?;
+        Ok(assert_success(response, context).await?.json().await?)
+    }
+}
@@ -0,0 +1,147 @@
+/*
+ * Copyright (c) 2023 Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Mail Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::{fmt::Display, future::Future};
+
+use roaring::RoaringBitmap;
+
+use crate::{
+    dispatch::DocumentSet,
+    fts::{index::FtsDocument, FtsFilter},
+    FtsStore,
+};
+
+use super::{ElasticSearchStore, RefreshPolicy};
+
+/// Full-text operations used by consumers of the ElasticSearch store, allowing
+/// search dependent logic to be tested against `MockFtsStore`.
+pub trait FtsBackend: Send + Sync {
+    fn fts_index<T: Into<u8> + Display + Clone + std::fmt::Debug + Send + Sync>(
+        &self,
+        document: FtsDocument<'_, T>,
+    ) -> impl Future<Output = crate::Result<()>> + Send;
+
+    fn fts_query<T: Into<u8> + Display + Clone + std::fmt::Debug + Send + Sync>(
+        &self,
+        account_id: u32,
+        collection: u8,
+        filters: Vec<FtsFilter<T>>,
+        include_attachments: bool,
+    ) -> impl Future<Output = crate::Result<RoaringBitmap>> + Send;
+
+    fn fts_remove(
+        &self,
+        account_id: u32,
+        collection: u8,
+        document_ids: &impl DocumentSet,
+    ) -> impl Future<Output = crate::Result<()>> + Send;
+
+    fn fts_remove_all(&self, account_id: u32) -> impl Future<Output = crate::Result<()>> + Send;
+}
+
+impl FtsBackend for ElasticSearchStore {
+    async fn fts_index<T: Into<u8> + Display + Clone + std::fmt::Debug + Send + Sync>(
+        &self,
+        document: FtsDocument<'_, T>,
+    ) -> crate::Result<()> {
+        ElasticSearchStore::fts_index(self, document, RefreshPolicy::default()).await
+    }
+
+    async fn fts_query<T: Into<u8> + Display + Clone + std::fmt::Debug + Send + Sync>(
+        &self,
+        account_id: u32,
+        collection: u8,
+        filters: Vec<FtsFilter<T>>,
+        include_attachments: bool,
+    ) -> crate::Result<RoaringBitmap> {
+        ElasticSearchStore::fts_query(self, account_id, collection, filters, include_attachments)
+            .await
+    }
+
+    async fn fts_remove(
+        &self,
+        account_id: u32,
+        collection: u8,
+        document_ids: &impl DocumentSet,
+    ) -> crate::Result<()> {
+        ElasticSearchStore::fts_remove(
+            self,
+            account_id,
+            collection,
+            document_ids,
+            RefreshPolicy::default(),
+        )
+        .await
+    }
+
+    async fn fts_remove_all(&self, account_id: u32) -> crate::Result<()> {
+        ElasticSearchStore::fts_remove_all(self, account_id).await
+    }
+}
+
+// Allows the configured full-text store, which may be the internal store, to be
+// combined with ElasticSearch in a `MultiFtsStore`. Only ElasticSearch can exclude
+// attachments from a query.
+impl FtsBackend for FtsStore {
+    async fn fts_index<T: Into<u8> + Display + Clone + std::fmt::Debug + Send + Sync>(
+        &self,
+        document: FtsDocument<'_, T>,
+    ) -> crate::Result<()> {
+        self.index(document).await
+    }
+
+    async fn fts_query<T: Into<u8> + Display + Clone + std::fmt::Debug + Send + Sync>(
+        &self,
+        account_id: u32,
+        collection: u8,
+        filters: Vec<FtsFilter<T>>,
+        include_attachments: bool,
+    ) -> crate::Result<RoaringBitmap> {
+        match self {
+            FtsStore::ElasticSearch(store) => {
+                ElasticSearchStore::fts_query(
+                    store,
+                    account_id,
+                    collection,
+                    filters,
+                    include_attachments,
+                )
+                .await
+            }
+            store => store.query(account_id, collection, filters).await,
+        }
+    }
+
+    async fn fts_remove(
+        &self,
+        account_id: u32,
+        collection: u8,
+        document_ids: &impl DocumentSet,
+    ) -> crate::Result<()> {
+        self.remove(account_id, collection, document_ids).await
+    }
+
+    async fn fts_remove_all(&self, account_id: u32) -> crate::Result<()> {
+        self.remove_all(account_id).await
+    }
+}
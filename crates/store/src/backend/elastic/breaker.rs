@@ -0,0 +1,103 @@
+/*
+ * Copyright (c) 2023 Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Mail Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::{
+    sync::atomic::{AtomicBool, AtomicU32, Ordering},
+    time::{Duration, Instant},
+};
+
+use parking_lot::Mutex;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakerState {
+    Closed,
+    Open,
+    // The cooldown has passed, the next request probes the cluster
+    HalfOpen,
+}
+
+// Stops sending requests to an unreachable cluster after consecutive failures
+pub(crate) struct CircuitBreaker {
+    threshold: u32,
+    cooldown: Duration,
+    failures: AtomicU32,
+    open_until: Mutex<Option<Instant>>,
+    probing: AtomicBool,
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: u32, cooldown: Duration) -> Self {
+        CircuitBreaker {
+            threshold,
+            cooldown,
+            failures: AtomicU32::new(0),
+            open_until: Mutex::new(None),
+            probing: AtomicBool::new(false),
+        }
+    }
+
+    pub fn state(&self) -> BreakerState {
+        match *self.open_until.lock() {
+            None => BreakerState::Closed,
+            Some(until) if Instant::now() < until => BreakerState::Open,
+            Some(_) => BreakerState::HalfOpen,
+        }
+    }
+
+    // Only one request probes the cluster while the breaker is half-open
+    pub fn try_probe(&self) -> bool {
+        !self.probing.swap(true, Ordering::AcqRel)
+    }
+
+    pub fn success(&self) {
+        self.failures.store(0, Ordering::Relaxed);
+        self.probing.store(false, Ordering::Release);
+        if self.open_until.lock().take().is_some() {
+            tracing::info!(
+                context = "elasticsearch",
+                event = "circuit-closed",
+                "ElasticSearch is reachable again, resuming requests"
+            );
+        }
+    }
+
+    pub fn failure(&self) {
+        self.probing.store(false, Ordering::Release);
+        if self.threshold == 0 {
+            return;
+        }
+
+        if self.failures.fetch_add(1, Ordering::Relaxed) + 1 >= self.threshold {
+            let mut open_until = self.open_until.lock();
+            if open_until.is_none() {
+                tracing::warn!(
+                    context = "elasticsearch",
+                    event = "circuit-open",
+                    cooldown = ?self.cooldown,
+                    "ElasticSearch is unreachable, suspending requests"
+                );
+            }
+            *open_until = Some(Instant::now() + self.cooldown);
+        }
+    }
+}
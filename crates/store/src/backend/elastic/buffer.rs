@@ -0,0 +1,157 @@
+/*
+ * Copyright (c) 2023 Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Mail Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::{sync::Arc, time::Duration};
+
+use parking_lot::Mutex;
+use rand::Rng;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+pub(crate) struct BufferedDocument {
+    pub index: String,
+    pub id: String,
+    pub account_id: u32,
+    pub document_id: u32,
+    pub source: String,
+    pub create: bool,
+    pub routing: Option<String>,
+    pub version: Option<u64>,
+    // Released once the document has been flushed
+    pub _permit: OwnedSemaphorePermit,
+}
+
+// Documents waiting to be sent in a single bulk request. The number of documents
+// either buffered or being flushed is bounded, callers wait for space when full.
+pub(crate) struct IndexBuffer {
+    pub batch_size: usize,
+    pub max_wait: Duration,
+    // Random delay added to each flush so instances sharing a cluster drift apart
+    pub jitter: Duration,
+    capacity: Arc<Semaphore>,
+    documents: Mutex<Vec<BufferedDocument>>,
+}
+
+impl IndexBuffer {
+    pub fn new(batch_size: usize, max_wait: Duration, jitter: Duration, capacity: usize) -> Self {
+        let batch_size = batch_size.max(1);
+        IndexBuffer {
+            batch_size,
+            max_wait,
+            jitter,
+            capacity: Arc::new(Semaphore::new(capacity.max(batch_size))),
+            documents: Mutex::new(Vec::with_capacity(batch_size)),
+        }
+    }
+
+    pub async fn reserve(&self) -> OwnedSemaphorePermit {
+        self.capacity
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("Index buffer semaphore is never closed")
+    }
+
+    // Returns a batch to be flushed once enough documents are buffered
+    pub fn push(&self, document: BufferedDocument) -> Option<Vec<BufferedDocument>> {
+        let mut documents = self.documents.lock();
+        documents.push(document);
+        if documents.len() >= self.batch_size {
+            Some(std::mem::replace(
+                &mut documents,
+                Vec::with_capacity(self.batch_size),
+            ))
+        } else {
+            None
+        }
+    }
+
+    // Time to wait before the next timed flush
+    pub fn next_flush(&self) -> Duration {
+        let jitter = self.jitter.as_millis() as u64;
+        self.max_wait + Duration::from_millis(rand::thread_rng().gen_range(0..=jitter))
+    }
+
+    pub fn take(&self) -> Vec<BufferedDocument> {
+        std::mem::take(&mut self.documents.lock())
+    }
+
+    pub fn len(&self) -> usize {
+        self.documents.lock().len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{BufferedDocument, IndexBuffer};
+
+    #[tokio::test]
+    async fn full_batches_are_returned() {
+        let buffer = IndexBuffer::new(2, Duration::from_secs(1), Duration::ZERO, 3);
+        let mut batches = Vec::new();
+        for document_id in 0..3 {
+            let document = BufferedDocument {
+                index: "stalwart_email".to_string(),
+                id: format!("1:{document_id}"),
+                account_id: 1,
+                document_id,
+                source: "{}".to_string(),
+                create: false,
+                routing: None,
+                version: None,
+                _permit: buffer.reserve().await,
+            };
+            batches.extend(buffer.push(document));
+        }
+
+        assert_eq!(batches.len(), 1);
+        assert_eq!(batches[0].len(), 2);
+        assert_eq!(buffer.len(), 1);
+
+        // All permits are held until the documents are flushed
+        assert!(buffer.capacity.clone().try_acquire_owned().is_err());
+        drop(batches);
+        assert!(buffer.capacity.clone().try_acquire_owned().is_ok());
+        assert_eq!(buffer.take().len(), 1);
+    }
+
+    #[test]
+    fn flushes_are_jittered_within_bounds() {
+        let buffer = IndexBuffer::new(
+            10,
+            Duration::from_millis(1000),
+            Duration::from_millis(200),
+            10,
+        );
+        let delays = (0..1000).map(|_| buffer.next_flush()).collect::<Vec<_>>();
+        assert!(delays
+            .iter()
+            .all(|delay| *delay >= Duration::from_millis(1000)
+                && *delay <= Duration::from_millis(1200)));
+        assert!(delays.iter().any(|delay| *delay != delays[0]));
+
+        let buffer = IndexBuffer::new(10, Duration::from_millis(1000), Duration::ZERO, 10);
+        assert_eq!(buffer.next_flush(), Duration::from_millis(1000));
+    }
+}
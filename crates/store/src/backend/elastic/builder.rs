@@ -0,0 +1,282 @@
+/*
+ * Copyright (c) 2023 Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Mail Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::time::Duration;
+
+use utils::config::Config;
+
+use super::{ElasticSearchStore, INDEX_NAMES};
+
+const PREFIX: &str = "store.elastic";
+
+/// Typed alternative to configuring the store through `open`, settings not
+/// covered by the builder use the same defaults.
+#[derive(Debug, Default)]
+pub struct ElasticSearchStoreBuilder {
+    url: Option<String>,
+    cloud_id: Option<String>,
+    auth: Option<Auth>,
+    // Both basic and API key authentication were configured
+    mixed_auth: bool,
+    enabled: Option<Vec<u8>>,
+    keys: Vec<(&'static str, String)>,
+}
+
+#[derive(Debug)]
+enum Auth {
+    Basic { user: String, password: String },
+    ApiKey { id: String, secret: String },
+}
+
+impl ElasticSearchStore {
+    pub fn builder() -> ElasticSearchStoreBuilder {
+        ElasticSearchStoreBuilder::default()
+    }
+}
+
+impl ElasticSearchStoreBuilder {
+    /// Node to connect to, the store connects to a single node so a later call
+    /// replaces the previous URL.
+    pub fn url(mut self, url: impl Into<String>) -> Self {
+        self.url = Some(url.into());
+        self
+    }
+
+    pub fn cloud_id(mut self, cloud_id: impl Into<String>) -> Self {
+        self.cloud_id = Some(cloud_id.into());
+        self
+    }
+
+    pub fn basic_auth(self, user: impl Into<String>, password: impl Into<String>) -> Self {
+        self.with_auth(Auth::Basic {
+            user: user.into(),
+            password: password.into(),
+        })
+    }
+
+    pub fn api_key(self, id: impl Into<String>, secret: impl Into<String>) -> Self {
+        self.with_auth(Auth::ApiKey {
+            id: id.into(),
+            secret: secret.into(),
+        })
+    }
+
+    // Credentials of the same kind replace the previous ones
+    fn with_auth(mut self, auth: Auth) -> Self {
+        if self
+            .auth
+            .as_ref()
+            .is_some_and(|current| std::mem::discriminant(current) != std::mem::discriminant(&auth))
+        {
+            self.mixed_auth = true;
+        }
+        self.auth = Some(auth);
+        self
+    }
+
+    pub fn index_prefix(self, prefix: impl Into<String>) -> Self {
+        self.set("index.prefix", prefix.into())
+    }
+
+    pub fn request_timeout(self, timeout: Duration) -> Self {
+        self.set("timeout.request", format!("{}ms", timeout.as_millis()))
+    }
+
+    pub fn bulk_timeout(self, timeout: Duration) -> Self {
+        self.set("timeout.bulk", format!("{}ms", timeout.as_millis()))
+    }
+
+    pub fn shards(self, shards: u32) -> Self {
+        self.set("index.shards", shards.to_string())
+    }
+
+    pub fn replicas(self, replicas: u32) -> Self {
+        self.set("index.replicas", replicas.to_string())
+    }
+
+    /// Collections to index, all collections are indexed by default.
+    pub fn enabled_collections(mut self, collections: impl IntoIterator<Item = u8>) -> Self {
+        self.enabled = Some(collections.into_iter().collect());
+        self
+    }
+
+    fn set(mut self, key: &'static str, value: String) -> Self {
+        self.keys.retain(|(k, _)| *k != key);
+        self.keys.push((key, value));
+        self
+    }
+
+    /// Connects to the cluster and creates the indices, failing on invalid settings
+    /// or when the indices could not be initialized.
+    pub async fn build(self) -> crate::Result<ElasticSearchStore> {
+        let config = self.into_config()?;
+        let mut config = Config {
+            keys: config,
+            ..Default::default()
+        };
+        let store = ElasticSearchStore::open(&mut config, PREFIX).await;
+        match store {
+            Some(store) if config.errors.is_empty() => Ok(store),
+            _ => {
+                let mut errors = config
+                    .errors
+                    .iter()
+                    .map(|(key, err)| format!("{key}: {err:?}"))
+                    .collect::<Vec<_>>();
+                errors.sort_unstable();
+                Err(crate::Error::InternalError(format!(
+                    "Failed to build ElasticSearch store: {}",
+                    errors.join(", ")
+                )))
+            }
+        }
+    }
+
+    fn into_config(self) -> crate::Result<std::collections::BTreeMap<String, String>> {
+        let err = |message: &str| Err(crate::Error::InternalError(message.to_string()));
+        match (&self.url, &self.cloud_id) {
+            (None, None) => return err("An ElasticSearch URL or cloud id is required"),
+            (Some(_), Some(_)) => return err("Only one of a URL or a cloud id can be configured"),
+            _ => {}
+        }
+        if self.mixed_auth {
+            return err("Only one of basic or API key authentication can be configured");
+        }
+        if self.cloud_id.is_some() && self.auth.is_none() {
+            return err("Cloud connections require authentication");
+        }
+
+        let auth = match self.auth {
+            Some(Auth::Basic { user, password }) => vec![("user", user), ("password", password)],
+            Some(Auth::ApiKey { id, secret }) => {
+                vec![("api-key.id", id), ("api-key.secret", secret)]
+            }
+            None => Vec::new(),
+        };
+        let mut keys = self
+            .url
+            .map(|url| ("url", url))
+            .into_iter()
+            .chain(self.cloud_id.map(|cloud_id| ("cloud-id", cloud_id)))
+            .chain(auth)
+            .chain(self.keys)
+            .map(|(key, value)| (format!("{PREFIX}.{key}"), value))
+            .collect::<std::collections::BTreeMap<_, _>>();
+        if let Some(enabled) = self.enabled {
+            if let Some(collection) = enabled
+                .iter()
+                .find(|collection| **collection as usize >= INDEX_NAMES.len())
+            {
+                return Err(crate::Error::InternalError(format!(
+                    "Unknown collection {collection}"
+                )));
+            }
+            for (collection, name) in INDEX_NAMES.iter().enumerate() {
+                if !enabled.contains(&(collection as u8)) {
+                    keys.insert(
+                        format!("{PREFIX}.index.disable.{collection:04}"),
+                        name.to_string(),
+                    );
+                }
+            }
+        }
+
+        Ok(keys)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::ElasticSearchStore;
+
+    #[test]
+    fn invalid_settings_are_rejected() {
+        for (builder, error) in [
+            (ElasticSearchStore::builder(), "URL or cloud id is required"),
+            (
+                ElasticSearchStore::builder()
+                    .url("http://a:9200")
+                    .cloud_id("cluster:abc"),
+                "URL or a cloud id",
+            ),
+            (
+                ElasticSearchStore::builder()
+                    .url("http://a:9200")
+                    .basic_auth("elastic", "changeme")
+                    .api_key("id", "secret"),
+                "basic or API key",
+            ),
+            (
+                ElasticSearchStore::builder()
+                    .url("http://a:9200")
+                    .api_key("id", "secret")
+                    .basic_auth("elastic", "changeme")
+                    .api_key("id", "secret"),
+                "basic or API key",
+            ),
+            (
+                ElasticSearchStore::builder()
+                    .url("http://a:9200")
+                    .enabled_collections([7]),
+                "Unknown collection 7",
+            ),
+        ] {
+            let err = builder.into_config().unwrap_err().to_string();
+            assert!(err.contains(error), "{err}");
+        }
+    }
+
+    #[test]
+    fn settings_are_mapped_to_keys() {
+        let keys = ElasticSearchStore::builder()
+            .url("http://a:9200")
+            .url("http://localhost:9200")
+            .basic_auth("admin", "secret")
+            .basic_auth("elastic", "changeme")
+            .index_prefix("test_")
+            .index_prefix("tenant_")
+            .request_timeout(Duration::from_secs(10))
+            .shards(1)
+            .enabled_collections([])
+            .into_config()
+            .unwrap();
+
+        assert_eq!(
+            keys.into_iter().collect::<Vec<_>>(),
+            [
+                ("index.disable.0000", "stalwart_email"),
+                ("index.prefix", "tenant_"),
+                ("index.shards", "1"),
+                ("password", "changeme"),
+                ("timeout.request", "10000ms"),
+                ("url", "http://localhost:9200"),
+                ("user", "elastic"),
+            ]
+            .into_iter()
+            .map(|(key, value)| (format!("store.elastic.{key}"), value.to_string()))
+            .collect::<Vec<_>>()
+        );
+    }
+}
@@ -0,0 +1,219 @@
+/*
+ * Copyright (c) 2023 Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Mail Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::{
+    fmt::Display,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use roaring::RoaringBitmap;
+use utils::lru_cache::{LruCache, LruCached};
+use xxhash_rust::xxh3::xxh3_128;
+
+use crate::fts::FtsFilter;
+
+use super::query::QueryOptions;
+
+// Accounts share write counters by bucket, a write to an account also expires the
+// results cached for the other accounts in its bucket
+const GENERATION_BUCKETS: usize = 1024;
+
+// Account, collection and hash of the filters
+pub(crate) type CacheKey = (u32, u8, u128);
+
+#[derive(Clone)]
+struct CachedResult {
+    document_ids: RoaringBitmap,
+    generation: u64,
+    expires: Instant,
+}
+
+// Recent results of identical searches. Each account has a counter bumped before
+// and after every write, results are cached along with the counter read before
+// searching, so results of a search overlapping a write are never served. Writes
+// running on the cluster as background tasks are only bounded by the time to live.
+pub(crate) struct QueryCache {
+    entries: LruCache<CacheKey, CachedResult>,
+    ttl: Duration,
+    generations: Box<[AtomicU64]>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+// Expires the results of the accounts once more when the write is done
+pub(crate) struct WriteGuard<'x> {
+    cache: &'x QueryCache,
+    account_ids: Vec<u32>,
+}
+
+impl QueryCache {
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        QueryCache {
+            entries: LruCache::with_capacity(capacity),
+            ttl,
+            generations: (0..GENERATION_BUCKETS).map(|_| AtomicU64::new(0)).collect(),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub fn key<T: Into<u8> + Display + Clone + std::fmt::Debug>(
+        account_id: u32,
+        collection: u8,
+        filters: &[FtsFilter<T>],
+        options: &QueryOptions<'_>,
+    ) -> CacheKey {
+        // The deadline only bounds the search and does not change its results
+        let filters = format!(
+            "{} {:?} {:?} {filters:?}",
+            options.include_attachments, options.operator, options.minimum_should_match
+        );
+        (account_id, collection, xxh3_128(filters.as_bytes()))
+    }
+
+    pub fn generation(&self, account_id: u32) -> u64 {
+        self.bucket(account_id).load(Ordering::Acquire)
+    }
+
+    pub fn get(&self, key: &CacheKey) -> Option<RoaringBitmap> {
+        let result = self.entries.get(key).filter(|result| {
+            result.expires > Instant::now() && result.generation == self.generation(key.0)
+        });
+        match &result {
+            Some(_) => self.hits.fetch_add(1, Ordering::Relaxed),
+            None => self.misses.fetch_add(1, Ordering::Relaxed),
+        };
+        result.map(|result| result.document_ids)
+    }
+
+    pub fn insert(&self, key: CacheKey, generation: u64, document_ids: RoaringBitmap) {
+        self.entries.insert(
+            key,
+            CachedResult {
+                document_ids,
+                generation,
+                expires: Instant::now() + self.ttl,
+            },
+        );
+    }
+
+    pub fn writing(&self, account_ids: Vec<u32>) -> WriteGuard<'_> {
+        self.invalidate(&account_ids);
+        WriteGuard {
+            cache: self,
+            account_ids,
+        }
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    fn invalidate(&self, account_ids: &[u32]) {
+        for account_id in account_ids {
+            self.bucket(*account_id).fetch_add(1, Ordering::AcqRel);
+        }
+    }
+
+    fn bucket(&self, account_id: u32) -> &AtomicU64 {
+        &self.generations[account_id as usize % GENERATION_BUCKETS]
+    }
+}
+
+impl Drop for WriteGuard<'_> {
+    fn drop(&mut self) {
+        self.cache.invalidate(&self.account_ids);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use roaring::RoaringBitmap;
+
+    use crate::fts::{Field, FtsFilter};
+
+    use super::{
+        super::query::{QueryOperator, QueryOptions},
+        QueryCache,
+    };
+
+    #[test]
+    fn writes_expire_cached_results() {
+        let cache = QueryCache::new(10, Duration::from_secs(60));
+        let filters = [FtsFilter::<u8>::has_english_text(Field::Body, "report")];
+        let options = QueryOptions::default();
+        let key = QueryCache::key(1, 0, &filters, &options);
+        let other = QueryCache::key(2, 0, &filters, &options);
+        assert_ne!(
+            key,
+            QueryCache::key(
+                1,
+                0,
+                &filters,
+                &QueryOptions {
+                    include_attachments: false,
+                    ..options
+                }
+            )
+        );
+        assert_ne!(
+            key,
+            QueryCache::key(
+                1,
+                0,
+                &filters,
+                &QueryOptions {
+                    operator: Some(QueryOperator::And),
+                    ..options
+                }
+            )
+        );
+        let results = RoaringBitmap::from_iter([1, 2]);
+
+        cache.insert(key, cache.generation(1), results.clone());
+        cache.insert(other, cache.generation(2), results.clone());
+        assert_eq!(cache.get(&key), Some(results.clone()));
+
+        // Searches overlapping a write are not served either
+        let generation = cache.generation(1);
+        let guard = cache.writing(vec![1]);
+        assert_eq!(cache.get(&key), None);
+        cache.insert(key, generation, results.clone());
+        drop(guard);
+        assert_eq!(cache.get(&key), None);
+        assert_eq!(cache.get(&other), Some(results));
+        assert_eq!((cache.hits(), cache.misses()), (2, 2));
+
+        // Results expire after their time to live
+        let cache = QueryCache::new(10, Duration::ZERO);
+        cache.insert(key, cache.generation(1), RoaringBitmap::new());
+        assert_eq!(cache.get(&key), None);
+    }
+}
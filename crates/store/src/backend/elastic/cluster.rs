@@ -0,0 +1,360 @@
+/*
+ * Copyright (c) 2023 Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Mail Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::{ops::RangeInclusive, sync::Arc};
+
+use arc_swap::ArcSwap;
+use elasticsearch::{auth::Credentials, http::Url, Elasticsearch};
+use parking_lot::Mutex;
+use serde_json::{json, Value};
+use utils::config::Config;
+
+use super::{
+    assert_success, compat::CompatibleRequest, metrics::Operation, Connection, ElasticSearchStore,
+    Flavor,
+};
+
+// Accounts can be spread across several clusters. Each account has a home cluster
+// holding its documents, the local cluster unless it is routed to a remote one.
+//
+// Searches are sent to the local cluster, which forwards them to the remote clusters
+// with cross-cluster search ("alias:index"). A remote cluster that cannot be reached
+// fails the search unless its skip-unavailable setting is enabled, in which case
+// its documents are left out of the results and a warning is logged. Searching a
+// single account whose home cluster was skipped returns no results.
+//
+// Cross-cluster search is read-only, so documents are written directly to the home
+// cluster, whose indices have to be created by a store opened against it. Requests
+// to all clusters share the circuit breaker and the pending queue, a remote cluster
+// that stays down suspends requests to every cluster until it recovers, and
+// failed writes are replayed to their home cluster.
+#[derive(Default)]
+pub(crate) struct Clusters {
+    remotes: Vec<RemoteCluster>,
+    routing: ClusterRouting,
+}
+
+pub(crate) struct RemoteCluster {
+    pub alias: String,
+    connection: Mutex<Connection>,
+    client: ArcSwap<Elasticsearch>,
+    // Transport addresses registered on the local cluster, remote clusters without
+    // seeds have to be registered by the cluster administrator
+    pub seeds: Vec<String>,
+    pub accounts: Vec<RangeInclusive<u32>>,
+    pub skip_unavailable: bool,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ClusterRouting {
+    // Accounts within the ranges of a remote cluster live on it, others locally
+    #[default]
+    Range,
+    // Accounts are spread evenly by id, the local cluster followed by the remote
+    // clusters in alias order. Adding a cluster moves most accounts.
+    Modulo,
+}
+
+impl Clusters {
+    pub(super) fn parse(
+        config: &mut Config,
+        prefix: &str,
+        local: &Connection,
+        flavor: Flavor,
+    ) -> Option<Self> {
+        let routing = match config.value((prefix, "cluster-routing")) {
+            Some("range") | None => ClusterRouting::Range,
+            Some("modulo") => ClusterRouting::Modulo,
+            Some(routing) => {
+                config.new_parse_error(
+                    (prefix, "cluster-routing"),
+                    format!("Unknown routing {routing:?}, expected range or modulo"),
+                );
+                return None;
+            }
+        };
+        let aliases = config
+            .sub_keys((prefix, "cluster"), ".url")
+            .map(|alias| alias.to_string())
+            .collect::<Vec<_>>();
+
+        let mut remotes = Vec::with_capacity(aliases.len());
+        for alias in aliases {
+            let key = format!("{prefix}.cluster.{alias}");
+            let url = Url::parse(config.value((&key, "url")).unwrap_or_default())
+                .map_err(|e| config.new_parse_error((&key, "url"), format!("Invalid URL: {e}")))
+                .ok()?;
+            // Remote clusters are reached with the credentials and TLS settings of
+            // the local cluster
+            let connection = match local {
+                Connection::Url {
+                    credentials,
+                    ca_cert,
+                    allow_invalid_certs,
+                    ..
+                } => Connection::Url {
+                    url,
+                    credentials: credentials.clone(),
+                    ca_cert: ca_cert.clone(),
+                    allow_invalid_certs: *allow_invalid_certs,
+                },
+                Connection::Cloud { credentials, .. } => Connection::Url {
+                    url,
+                    credentials: Some(credentials.clone()),
+                    ca_cert: None,
+                    allow_invalid_certs: false,
+                },
+            };
+            let client = connection
+                .build(flavor)
+                .map_err(|err| config.new_build_error(key.as_str(), err.to_string()))
+                .ok()?;
+
+            let ranges = config
+                .values((&key, "accounts"))
+                .map(|(_, range)| range.to_string())
+                .collect::<Vec<_>>();
+            let mut accounts = Vec::new();
+            for range in ranges {
+                match range
+                    .split_once('-')
+                    .and_then(|(from, to)| {
+                        Some(from.trim().parse().ok()?..=to.trim().parse().ok()?)
+                    })
+                    .or_else(|| range.trim().parse().ok().map(|id| id..=id))
+                {
+                    Some(range) => accounts.push(range),
+                    None => {
+                        let err = format!("Invalid account range {range:?}");
+                        config.new_parse_error((&key, "accounts"), err);
+                        return None;
+                    }
+                }
+            }
+            if routing == ClusterRouting::Range && accounts.is_empty() {
+                config.new_build_error(
+                    (&key, "accounts"),
+                    "Remote clusters require account ranges with range routing",
+                );
+                return None;
+            }
+
+            remotes.push(RemoteCluster {
+                seeds: config
+                    .values((&key, "seeds"))
+                    .map(|(_, seed)| seed.to_string())
+                    .collect(),
+                skip_unavailable: config
+                    .property_or_default((&key, "skip-unavailable"), "false")
+                    .unwrap_or(false),
+                alias,
+                connection: Mutex::new(connection),
+                client: ArcSwap::from_pointee(Elasticsearch::new(client)),
+                accounts,
+            });
+        }
+        remotes.sort_unstable_by(|a, b| a.alias.cmp(&b.alias));
+
+        Some(Clusters { remotes, routing })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.remotes.is_empty()
+    }
+
+    pub fn remotes(&self) -> &[RemoteCluster] {
+        &self.remotes
+    }
+
+    // Returns the remote cluster holding an account, None for the local cluster
+    pub fn home(&self, account_id: u32) -> Option<&RemoteCluster> {
+        match self.routing {
+            ClusterRouting::Range => self.remotes.iter().find(|remote| {
+                remote
+                    .accounts
+                    .iter()
+                    .any(|range| range.contains(&account_id))
+            }),
+            ClusterRouting::Modulo if !self.remotes.is_empty() => {
+                let pos = account_id as usize % (self.remotes.len() + 1);
+                pos.checked_sub(1).map(|pos| &self.remotes[pos])
+            }
+            ClusterRouting::Modulo => None,
+        }
+    }
+
+    // Clients are built before the flavor is detected
+    pub fn rebuild(&self, flavor: Flavor) -> crate::Result<()> {
+        for remote in &self.remotes {
+            let transport = remote.connection.lock().build(flavor)?;
+            remote.client.store(Arc::new(Elasticsearch::new(transport)));
+        }
+        Ok(())
+    }
+
+    // Remote clusters follow the credentials of the local cluster, the previous
+    // clients are kept if any of the new ones cannot be built
+    pub fn update_credentials(
+        &self,
+        credentials: &Credentials,
+        flavor: Flavor,
+    ) -> crate::Result<()> {
+        let mut updated = Vec::with_capacity(self.remotes.len());
+        for remote in &self.remotes {
+            let mut connection = remote.connection.lock().clone();
+            if let Connection::Url {
+                credentials: current,
+                ..
+            } = &mut connection
+            {
+                *current = Some(credentials.clone());
+            }
+            let transport = connection.build(flavor)?;
+            updated.push((connection, Elasticsearch::new(transport)));
+        }
+        for (remote, (connection, client)) in self.remotes.iter().zip(updated) {
+            *remote.connection.lock() = connection;
+            remote.client.store(Arc::new(client));
+        }
+        Ok(())
+    }
+}
+
+impl RemoteCluster {
+    pub fn client(&self) -> Arc<Elasticsearch> {
+        self.client.load_full()
+    }
+}
+
+impl ElasticSearchStore {
+    /// Returns the alias of the remote cluster holding an account, or None when
+    /// the account lives on the local cluster.
+    pub fn home_cluster(&self, account_id: u32) -> Option<&str> {
+        self.clusters
+            .home(account_id)
+            .map(|remote| remote.alias.as_str())
+    }
+
+    // Client of the home cluster of an account, used for writes and for requests
+    // that do not support cross-cluster search
+    pub(crate) fn client_for(&self, account_id: u32) -> Arc<Elasticsearch> {
+        match self.clusters.home(account_id) {
+            Some(remote) => remote.client(),
+            None => self.client(),
+        }
+    }
+
+    // Indices to search for the documents of the accounts, one per home cluster
+    pub(crate) fn search_indices(
+        &self,
+        collection: u8,
+        account_ids: &[u32],
+    ) -> crate::Result<String> {
+        let index = self.search_index(collection)?;
+        if self.clusters.is_empty() {
+            return Ok(self.with_archives(index));
+        }
+
+        let mut aliases = account_ids
+            .iter()
+            .map(|account_id| self.home_cluster(*account_id))
+            .collect::<Vec<_>>();
+        aliases.sort_unstable();
+        aliases.dedup();
+        Ok(aliases
+            .into_iter()
+            .map(|alias| match alias {
+                Some(alias) => format!("{alias}:{index}"),
+                None => self.with_archives(index.clone()),
+            })
+            .collect::<Vec<_>>()
+            .join(","))
+    }
+
+    // Groups accounts by their home cluster
+    pub(crate) fn group_by_cluster(&self, account_ids: &[u32]) -> Vec<Vec<u32>> {
+        let mut groups: Vec<(Option<&str>, Vec<u32>)> = Vec::new();
+        for account_id in account_ids {
+            let alias = self.home_cluster(*account_id);
+            match groups.iter_mut().find(|(group, _)| *group == alias) {
+                Some((_, accounts)) => accounts.push(*account_id),
+                None => groups.push((alias, vec![*account_id])),
+            }
+        }
+        groups.into_iter().map(|(_, accounts)| accounts).collect()
+    }
+
+    // Remote clusters with seeds are registered on the local cluster so searches
+    // can reach them
+    pub(crate) async fn register_remote_clusters(&self) -> crate::Result<()> {
+        let remotes = self
+            .clusters
+            .remotes()
+            .iter()
+            .filter(|remote| !remote.seeds.is_empty())
+            .map(|remote| {
+                (
+                    remote.alias.clone(),
+                    json!({
+                        "seeds": &remote.seeds,
+                        "skip_unavailable": remote.skip_unavailable
+                    }),
+                )
+            })
+            .collect::<serde_json::Map<_, _>>();
+        if remotes.is_empty() {
+            return Ok(());
+        }
+
+        let body = json!({ "persistent": { "cluster": { "remote": remotes } } });
+        let client = self.client();
+        let cluster = client.cluster();
+        let response = self
+            .send_with_retry(Operation::Manage, || {
+                cluster
+                    .put_settings()
+                    .request_timeout(self.request_timeout)
+                    .body(&body)
+                    .compatible_with(self.compatible_with)
+                    .send()
+            })
+            .await?;
+        assert_success(response, "Failed to register remote clusters")
+            .await
+            .map(|_| ())
+    }
+}
+
+// Remote clusters skipped because they were unreachable, their documents are
+// missing from the results
+pub(crate) fn log_skipped_clusters(json: &Value) {
+    let skipped = json["_clusters"]["skipped"].as_u64().unwrap_or(0);
+    if skipped > 0 {
+        tracing::warn!(
+            context = "elasticsearch",
+            event = "partial",
+            skipped = skipped,
+            "Remote clusters were unreachable, search results are incomplete"
+        );
+    }
+}
@@ -0,0 +1,147 @@
+/*
+ * Copyright (c) 2023 Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Mail Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use elasticsearch::{
+    cat::CatIndices,
+    cluster::{ClusterHealth, ClusterPutSettings},
+    http::{
+        headers::{HeaderMap, HeaderName, HeaderValue, ACCEPT, CONTENT_TYPE},
+        request::Body,
+    },
+    ilm::IlmPutLifecycle,
+    indices::{
+        IndicesClose, IndicesCreate, IndicesCreateDataStream, IndicesDelete,
+        IndicesDeleteDataStream, IndicesExists, IndicesForcemerge, IndicesGetAlias,
+        IndicesGetMapping, IndicesOpen, IndicesPutIndexTemplate, IndicesPutMapping, IndicesRefresh,
+        IndicesStats, IndicesUpdateAliases,
+    },
+    ingest::IngestPutPipeline,
+    snapshot::SnapshotCreate,
+    Bulk, ClearScroll, ClosePointInTime, Count, DeleteByQuery, Exists, Explain, Get, Index, Mget,
+    Msearch, OpenPointInTime, Ping, Reindex, Scroll, Search, Update, UpdateByQuery,
+};
+
+// Requests that can ask the cluster for the REST API of another major version
+pub(crate) trait CompatibleRequest: Sized {
+    // Bulk and multi search bodies are newline delimited
+    const NDJSON: bool = false;
+
+    fn with_header(self, key: HeaderName, value: HeaderValue) -> Self;
+
+    // Requests without a version use the plain JSON media type of the current API
+    fn compatible_with(self, version: Option<u8>) -> Self {
+        match version {
+            Some(version) => {
+                let media_type = |format| {
+                    HeaderValue::from_str(&format!(
+                        "application/vnd.elasticsearch+{format}; compatible-with={version}"
+                    ))
+                    .unwrap()
+                };
+                self.with_header(ACCEPT, media_type("json")).with_header(
+                    CONTENT_TYPE,
+                    media_type(if Self::NDJSON { "x-ndjson" } else { "json" }),
+                )
+            }
+            None => self,
+        }
+    }
+}
+
+impl CompatibleRequest for HeaderMap {
+    fn with_header(mut self, key: HeaderName, value: HeaderValue) -> Self {
+        self.insert(key, value);
+        self
+    }
+}
+
+// The client replaces the headers of the transport with its defaults, so they are
+// set on each request instead
+macro_rules! compatible_request {
+    ($($request:ident),*) => {
+        $(impl CompatibleRequest for $request<'_, '_> {
+            fn with_header(self, key: HeaderName, value: HeaderValue) -> Self {
+                self.header(key, value)
+            }
+        })*
+    };
+    (body: $($request:ident),*) => {
+        $(impl<B: Body> CompatibleRequest for $request<'_, '_, B> {
+            fn with_header(self, key: HeaderName, value: HeaderValue) -> Self {
+                self.header(key, value)
+            }
+        })*
+    };
+    (ndjson: $($request:ident),*) => {
+        $(impl<B: Body> CompatibleRequest for $request<'_, '_, B> {
+            const NDJSON: bool = true;
+
+            fn with_header(self, key: HeaderName, value: HeaderValue) -> Self {
+                self.header(key, value)
+            }
+        })*
+    };
+}
+
+compatible_request!(
+    CatIndices,
+    ClusterHealth,
+    Exists,
+    Get,
+    IndicesDelete,
+    IndicesDeleteDataStream,
+    IndicesExists,
+    IndicesGetAlias,
+    IndicesGetMapping,
+    IndicesStats,
+    Ping
+);
+compatible_request!(
+    body: ClearScroll,
+    ClosePointInTime,
+    ClusterPutSettings,
+    Count,
+    DeleteByQuery,
+    Explain,
+    IlmPutLifecycle,
+    IndicesClose,
+    IndicesCreate,
+    IndicesCreateDataStream,
+    IndicesForcemerge,
+    IndicesOpen,
+    IndicesPutIndexTemplate,
+    IndicesPutMapping,
+    IndicesRefresh,
+    IndicesUpdateAliases,
+    IngestPutPipeline,
+    Index,
+    Mget,
+    OpenPointInTime,
+    Reindex,
+    Scroll,
+    Search,
+    SnapshotCreate,
+    Update,
+    UpdateByQuery
+);
+compatible_request!(ndjson: Bulk, Msearch);
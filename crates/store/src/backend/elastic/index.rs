@@ -21,146 +21,2172 @@
  * for more details.
 */
 
-use std::{borrow::Cow, fmt::Display};
+use std::{borrow::Cow, fmt::Display, sync::Arc, time::Instant};
 
-use elasticsearch::{DeleteByQueryParts, IndexParts};
+use ahash::{AHashMap, AHashSet};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use elasticsearch::{
+    http::StatusCode,
+    params::{Conflicts, OpType, VersionType},
+    BulkParts, DeleteByQueryParts, Elasticsearch, IndexParts, UpdateByQueryParts, UpdateParts,
+};
+use nlp::language::{
+    detect::{LanguageDetector, MIN_LANGUAGE_SCORE},
+    Language,
+};
 use serde::{Deserialize, Serialize};
-use serde_json::json;
+use serde_json::{json, Value};
+use tokio::sync::watch;
 
 use crate::{
-    backend::elastic::INDEX_NAMES,
     dispatch::DocumentSet,
-    fts::{index::FtsDocument, Field},
+    fts::{
+        index::{FtsDocument, Type},
+        Field, FtsFilter, SystemFlag,
+    },
+    write::now,
+};
+
+use super::{
+    assert_removed, assert_success, bare_address,
+    buffer::BufferedDocument,
+    compat::CompatibleRequest,
+    document_key, envelope_address, language_code, message_id,
+    metrics::Operation,
+    pending::{PendingGuard, PendingOperation},
+    time_left, ElasticError, ElasticSearchStore, RefreshPolicy, INDEX_NAMES,
 };
 
-use super::ElasticSearchStore;
+/// A document as sent to and stored by ElasticSearch.
+#[derive(Debug, Serialize, Deserialize, Default)]
+// Fields excluded from the stored source are missing when read back
+#[serde(default)]
+pub struct Document<'x> {
+    pub document_id: u32,
+    pub account_id: u32,
+    pub received_at: i64,
+    #[serde(rename = "@timestamp", skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<i64>,
+    // Unknown sizes are omitted, a size of zero is indexed as is
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub thread_id: Option<u32>,
+    // Messages without a thread are collapsed as single message threads
+    pub thread: String,
+    // Messages in no mailbox are indexed with an empty list
+    pub mailbox_ids: Vec<u32>,
+    // Bare SMTP envelope addresses, omitted when the message has no envelope metadata
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub envelope_from: Option<Cow<'x, str>>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub envelope_to: Vec<Cow<'x, str>>,
+    // Set when the message has attachment text, metadata or contents
+    pub has_attachment: bool,
+    // System flags, also kept in "keywords" along with the other keywords
+    pub seen: bool,
+    pub answered: bool,
+    pub flagged: bool,
+    pub draft: bool,
+    pub deleted: bool,
+    // Message ids from In-Reply-To and References, without duplicates
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub references: Vec<String>,
+    pub body: Vec<Cow<'x, str>>,
+    #[serde(flatten)]
+    pub body_lang: AHashMap<String, Vec<Cow<'x, str>>>,
+    pub attachments: Vec<Cow<'x, str>>,
+    pub attachment: Vec<Attachment<'x>>,
+    pub keywords: Vec<Cow<'x, str>>,
+    pub header: Vec<Header<'x>>,
+    // Base64 encoded attachments, replaced by their text in "attachments" by the
+    // ingest pipeline before the document is stored
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attachment_data: Vec<AttachmentData>,
+    // Messages without a subject omit the field and are sorted last
+    #[serde(rename = "subject.keyword", skip_serializing_if = "Option::is_none")]
+    pub subject: Option<Cow<'x, str>>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ReindexReport {
+    pub indexed: u64,
+    pub failed: Vec<u32>,
+}
+
+// Documents sent so far, reported after each bulk request
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct BulkProgress {
+    pub processed: u64,
+    pub succeeded: u64,
+    pub failed: u64,
+}
+
+const REINDEX_BATCH_SIZE: usize = 500;
+// Default value of the "index.max_terms_count" setting
+const MAX_TERMS_COUNT: usize = 65536;
+const REINDEX_MAX_PAYLOAD_SIZE: usize = 10 * 1024 * 1024;
+// Sorting only needs a prefix of the subject, longer values exceed the keyword limits
+const MAX_SUBJECT_LENGTH: usize = 256;
+// Message ids kept from pathologically long reference chains, the first one is the
+// thread root and the others the most recent ancestors
+const MAX_REFERENCES: usize = 100;
+// Fields only sent to the ingest pipeline, which removes them before the document
+// is stored
+const PIPELINE_FIELDS: &[&str] = &["attachment_data"];
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ValidationReport {
+    pub errors: Vec<String>,
+    pub empty_parts: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Header<'x> {
+    pub name: Cow<'x, str>,
+    pub value: Cow<'x, str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub address: Option<Cow<'x, str>>,
+}
 
-#[derive(Serialize, Deserialize, Default)]
-struct Document<'x> {
-    document_id: u32,
-    account_id: u32,
-    body: Vec<Cow<'x, str>>,
-    attachments: Vec<Cow<'x, str>>,
-    keywords: Vec<Cow<'x, str>>,
-    header: Vec<Header<'x>>,
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AttachmentData {
+    pub data: String,
 }
 
-#[derive(Serialize, Deserialize)]
-struct Header<'x> {
-    name: Cow<'x, str>,
-    value: Cow<'x, str>,
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Attachment<'x> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub filename: Option<Cow<'x, str>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<Cow<'x, str>>,
 }
 
 impl ElasticSearchStore {
+    /// Indexes a document, replacing any previous version.
+    ///
+    /// The request may still be applied by ElasticSearch when the returned future
+    /// is dropped before completing, so cancelled calls are recorded as pending
+    /// and replayed later. Replaying is safe as the document is fully replaced.
     pub async fn fts_index<T: Into<u8> + Display + Clone + std::fmt::Debug>(
         &self,
         document: FtsDocument<'_, T>,
+        refresh: RefreshPolicy,
+    ) -> crate::Result<()> {
+        self.index_document(document, refresh, None).await
+    }
+
+    /// Like `fts_index`, giving up once the deadline has passed. The request and
+    /// cluster timeouts are set to the time left, and nothing is sent when the
+    /// deadline has already passed. Timed out documents are replayed later.
+    pub async fn fts_index_until<T: Into<u8> + Display + Clone + std::fmt::Debug>(
+        &self,
+        document: FtsDocument<'_, T>,
+        refresh: RefreshPolicy,
+        deadline: Instant,
+    ) -> crate::Result<()> {
+        self.index_document(document, refresh, Some(deadline)).await
+    }
+
+    async fn index_document<T: Into<u8> + Display + Clone + std::fmt::Debug>(
+        &self,
+        document: FtsDocument<'_, T>,
+        refresh: RefreshPolicy,
+        deadline: Option<Instant>,
+    ) -> crate::Result<()> {
+        if !self.is_enabled(document.collection) {
+            return Ok(());
+        }
+        let timeout = time_left(deadline, self.request_timeout)?;
+        let _cache = self.cache_writes([document.account_id]);
+        let cluster_timeout = deadline.map(|_| format!("{}ms", timeout.as_millis()));
+        let index = self.index_name(document.collection);
+        let id = document_key(document.account_id, document.document_id);
+        let account_id = document.account_id;
+        let document_id = document.document_id;
+        // Errors only identify the document, never include its contents
+        let context = format!(
+            "Failed to index document {document_id} of account {}",
+            document.account_id
+        );
+        let is_data_stream = self.is_data_stream(document.collection);
+        let routing = self.routing([document.collection], &[account_id]);
+        let version = document.version;
+        let fallback = self.fallback.as_ref().map(|_| document.clone());
+        let document = self.build_document(document);
+
+        // Kept for replay once the cluster is reachable again, and when the task is
+        // cancelled while the request is in flight as it may or may not have been applied.
+        let guard = PendingGuard::new(|| match serde_json::to_string(&document) {
+            Ok(source) => self.pending.insert(
+                &index,
+                &id,
+                PendingOperation::Index {
+                    account_id,
+                    document_id,
+                    source,
+                    create: is_data_stream,
+                    routing: routing.clone(),
+                    version: version.filter(|_| !is_data_stream),
+                },
+            ),
+            Err(err) => tracing::warn!(
+                context = "elasticsearch",
+                event = "error",
+                document_id = document_id,
+                reason = %err,
+                "Failed to serialize pending document"
+            ),
+        });
+        let client = self.client_for(account_id);
+        let result = self
+            .send_with_retry(Operation::Index, || {
+                let request = client.index(IndexParts::IndexId(&index, &id));
+                // Data streams only accept new documents without external versions,
+                // a conflict means the document was already indexed.
+                let request = if is_data_stream {
+                    request.op_type(OpType::Create)
+                } else if let Some(version) = version {
+                    request
+                        .version(version as i64)
+                        .version_type(VersionType::External)
+                } else {
+                    request
+                };
+                let request = match self.ingest_pipeline() {
+                    Some(pipeline) => request.pipeline(pipeline),
+                    None => request,
+                };
+                let request = match &routing {
+                    Some(routing) => request.routing(routing),
+                    None => request,
+                };
+                let request = match &cluster_timeout {
+                    Some(cluster_timeout) => request.timeout(cluster_timeout),
+                    None => request,
+                };
+                request
+                    .refresh(refresh.into())
+                    .request_timeout(timeout)
+                    .body(&document)
+                    .compatible_with(self.compatible_with)
+                    .send()
+            })
+            .await;
+        let response = match result {
+            Ok(response) => response,
+            Err(err) => {
+                if let (Some(fallback), Some(document)) = (&self.fallback, fallback) {
+                    fallback.lock().insert(document);
+                }
+                return Err(err);
+            }
+        };
+        guard.disarm();
+        self.pending.remove(&index, &id);
+        if !self.pending.is_empty() {
+            if let Err(err) = self.drain_pending().await {
+                tracing::debug!(
+                    context = "elasticsearch",
+                    event = "error",
+                    reason = %err,
+                    "Failed to replay pending operations"
+                );
+            }
+        }
+
+        if response.status_code() == StatusCode::CONFLICT {
+            let err = ElasticError::from_response(response).await;
+            if err.is_version_conflict() {
+                // A newer version of the document is already indexed
+                tracing::debug!(
+                    context = "elasticsearch",
+                    event = "skip",
+                    document_id = document_id,
+                    version = version,
+                    "Skipping stale document version"
+                );
+                Ok(())
+            } else {
+                Err(crate::Error::InternalError(format!("{context}: {err}")))
+            }
+        } else {
+            assert_success(response, &context).await.map(|_| ())
+        }
+    }
+
+    pub async fn fts_update_keywords(
+        &self,
+        account_id: u32,
+        collection: u8,
+        document_id: u32,
+        keywords: Vec<String>,
+    ) -> crate::Result<()> {
+        let mut fields = json!({});
+        for flag in SystemFlag::ALL {
+            fields[flag.name()] = keywords
+                .iter()
+                .any(|keyword| SystemFlag::parse(keyword) == Some(flag))
+                .into();
+        }
+        fields["keywords"] = keywords.into();
+        self.update_fields(
+            account_id,
+            collection,
+            document_id,
+            fields,
+            "Failed to update keywords",
+        )
+        .await
+    }
+
+    /// Replaces the mailboxes of an indexed message after it was moved or copied.
+    pub async fn fts_update_mailboxes(
+        &self,
+        account_id: u32,
+        collection: u8,
+        document_id: u32,
+        mailbox_ids: Vec<u32>,
+    ) -> crate::Result<()> {
+        self.update_fields(
+            account_id,
+            collection,
+            document_id,
+            json!({ "mailbox_ids": mailbox_ids }),
+            "Failed to update mailboxes",
+        )
+        .await
+    }
+
+    /// Renames a keyword in every message of an account, returning the number of
+    /// updated messages. Keywords are compared regardless of case, and messages
+    /// that already have the new keyword only lose the old one. Renaming safely
+    /// resumes where it stopped when retried after a failure or a conflict with
+    /// a concurrent update.
+    pub async fn fts_rename_keyword(
+        &self,
+        account_id: u32,
+        old: &str,
+        new: &str,
+    ) -> crate::Result<u64> {
+        let context = format!("Failed to rename keyword of account {account_id}");
+        if old.is_empty() || new.is_empty() {
+            return Err(crate::Error::InternalError(format!(
+                "{context}: keywords cannot be empty"
+            )));
+        }
+        // The script only rewrites "keywords", the flag fields would be left as they are
+        if SystemFlag::parse(old).is_some() || SystemFlag::parse(new).is_some() {
+            return Err(crate::Error::InternalError(format!(
+                "{context}: system flags cannot be renamed"
+            )));
+        }
+        if (0..INDEX_NAMES.len() as u8).any(|collection| self.is_data_stream(collection)) {
+            return Err(crate::Error::InternalError(format!(
+                "{context}: documents in data streams cannot be updated"
+            )));
+        }
+        // Updates are applied to the source, the excluded text would be dropped
+        if self.exclude_text_source {
+            return Err(crate::Error::InternalError(format!(
+                "{context}: documents without their text in the source cannot be updated"
+            )));
+        }
+
+        let index_names = self.index_names();
+        let index_names = index_names.iter().map(String::as_str).collect::<Vec<_>>();
+        let _cache = self.cache_writes([account_id]);
+        let body = json!({
+            "query": {
+                "bool": {
+                    "filter": [
+                        { "term": { "account_id": account_id } },
+                        { "term": { "keywords": old } }
+                    ]
+                }
+            },
+            "script": {
+                "lang": "painless",
+                "source": concat!(
+                    "def keywords = ctx._source.keywords; ",
+                    "if (keywords == null || !keywords.removeIf(k -> k.equalsIgnoreCase(params.from))) { ",
+                    "ctx.op = 'noop'; return; } ",
+                    "if (!keywords.stream().anyMatch(k -> k.equalsIgnoreCase(params.to))) { ",
+                    "keywords.add(params.to); }"
+                ),
+                "params": { "from": old, "to": new }
+            }
+        });
+        let routing = self.routing(0..INDEX_NAMES.len() as u8, &[account_id]);
+        let routing = routing.as_deref();
+        let client = self.client_for(account_id);
+        let response = self
+            .send_with_retry(Operation::Index, || {
+                let request = client.update_by_query(UpdateByQueryParts::Index(&index_names));
+                let request = match &routing {
+                    Some(routing) => request.routing(std::slice::from_ref(routing)),
+                    None => request,
+                };
+                request
+                    .ignore_unavailable(true)
+                    .allow_no_indices(true)
+                    .request_timeout(self.bulk_timeout)
+                    .body(&body)
+                    .compatible_with(self.compatible_with)
+                    .send()
+            })
+            .await?;
+        let json: Value = assert_success(response, &context).await?.json().await?;
+
+        Ok(json["updated"].as_u64().unwrap_or_default())
+    }
+
+    async fn update_fields(
+        &self,
+        account_id: u32,
+        collection: u8,
+        document_id: u32,
+        fields: Value,
+        context: &str,
     ) -> crate::Result<()> {
-        self.index
-            .index(IndexParts::Index(INDEX_NAMES[document.collection as usize]))
-            .body(Document::from(document))
-            .send()
+        if !self.is_enabled(collection) {
+            return Ok(());
+        }
+        if self.is_data_stream(collection) {
+            return Err(crate::Error::InternalError(format!(
+                "{context}: documents in data streams cannot be updated"
+            )));
+        }
+        // Updates are applied to the source, the excluded text would be dropped
+        if self.exclude_text_source {
+            return Err(crate::Error::InternalError(format!(
+                "{context}: documents without their text in the source cannot be updated"
+            )));
+        }
+        let index = self.index_name(collection);
+        let id = document_key(account_id, document_id);
+        let body = json!({ "doc": fields });
+        let routing = self.routing([collection], &[account_id]);
+        let _cache = self.cache_writes([account_id]);
+
+        let client = self.client_for(account_id);
+        let response = self
+            .send_with_retry(Operation::Index, || {
+                let request = client.update(UpdateParts::IndexId(&index, &id));
+                let request = match &routing {
+                    Some(routing) => request.routing(routing),
+                    None => request,
+                };
+                request
+                    .request_timeout(self.request_timeout)
+                    .body(&body)
+                    .compatible_with(self.compatible_with)
+                    .send()
+            })
+            .await?;
+
+        // Partial updates never create documents, a missing document has to be fully indexed
+        if response.status_code() == StatusCode::NOT_FOUND {
+            Err(crate::Error::InternalError(format!(
+                "{context}: document {id} is not indexed in {index}: {}",
+                ElasticError::from_response(response).await
+            )))
+        } else {
+            assert_success(response, context).await.map(|_| ())
+        }
+    }
+
+    /// Converts a document without sending it to ElasticSearch, reporting fields
+    /// missing from the index mapping and parts without any text.
+    pub fn fts_index_validate<T: Into<u8> + Display + Clone + std::fmt::Debug>(
+        &self,
+        document: FtsDocument<'_, T>,
+    ) -> crate::Result<ValidationReport> {
+        let mut report = ValidationReport::default();
+
+        if INDEX_NAMES.get(document.collection as usize).is_none() {
+            report.errors.push(format!(
+                "Collection {} does not map to an index",
+                document.collection
+            ));
+        }
+        for part in &document.parts {
+            if part.text.trim().is_empty() {
+                report.empty_parts.push(part.field.name().into_owned());
+            }
+        }
+
+        let template = self.index_template();
+        let properties = &template["mappings"]["properties"];
+        if let Value::Object(fields) = serde_json::to_value(self.build_document(document))? {
+            for name in fields
+                .keys()
+                .filter(|name| !PIPELINE_FIELDS.contains(&name.as_str()))
+            {
+                // Dotted names such as "subject.keyword" address a property of an object
+                let is_mapped = name
+                    .split('.')
+                    .try_fold(properties, |properties, part| {
+                        properties.get(part).map(|field| &field["properties"])
+                    })
+                    .is_some();
+                if !is_mapped {
+                    report
+                        .errors
+                        .push(format!("Field {name:?} is not part of the index mapping"));
+                }
+            }
+        }
+
+        Ok(report)
+    }
+
+    pub async fn fts_index_bulk<T: Into<u8> + Display + Clone + std::fmt::Debug>(
+        &self,
+        documents: Vec<FtsDocument<'_, T>>,
+        max_payload_size: usize,
+    ) -> crate::Result<Vec<u32>> {
+        self.fts_index_bulk_with_progress(documents, max_payload_size, |_| ())
             .await
-            .map_err(Into::into)
-            .and_then(|response| {
-                if response.status_code().is_success() {
-                    Ok(())
+    }
+
+    /// Like `fts_index_bulk`, calling `progress` with the running totals after each
+    /// bulk request. The callback runs on the indexing task between requests, so it
+    /// should return quickly, for example by sending the totals to a channel.
+    pub async fn fts_index_bulk_with_progress<T: Into<u8> + Display + Clone + std::fmt::Debug>(
+        &self,
+        documents: Vec<FtsDocument<'_, T>>,
+        max_payload_size: usize,
+        mut progress: impl FnMut(BulkProgress),
+    ) -> crate::Result<Vec<u32>> {
+        self.index_bulk(
+            documents,
+            max_payload_size,
+            &mut BulkProgress::default(),
+            &mut progress,
+        )
+        .await
+    }
+
+    async fn index_bulk<T: Into<u8> + Display + Clone + std::fmt::Debug>(
+        &self,
+        documents: Vec<FtsDocument<'_, T>>,
+        max_payload_size: usize,
+        totals: &mut BulkProgress,
+        progress: &mut impl FnMut(BulkProgress),
+    ) -> crate::Result<Vec<u32>> {
+        let mut failed_ids = Vec::new();
+        let mut document_ids = Vec::new();
+        let mut lines = Vec::new();
+        let mut payload_size = 0;
+
+        for document in documents {
+            if !self.is_enabled(document.collection) {
+                continue;
+            }
+            let account_id = document.account_id;
+            let document_id = document.document_id;
+            let is_data_stream = self.is_data_stream(document.collection);
+            let action = if is_data_stream { "create" } else { "index" };
+            let action = serde_json::to_string(&json!({
+                action: bulk_metadata(
+                    &self.index_name(document.collection),
+                    &document_key(document.account_id, document.document_id),
+                    self.routing([document.collection], &[document.account_id]),
+                    document.version.filter(|_| !is_data_stream),
+                )
+            }))?;
+            let source = serde_json::to_string(&self.build_document(document))?;
+            let size = action.len() + source.len() + 2;
+
+            // Flush before exceeding the maximum payload size
+            if payload_size + size > max_payload_size && !lines.is_empty() {
+                let sent = document_ids.len() as u64;
+                let failed = self
+                    .send_bulk(
+                        std::mem::take(&mut lines),
+                        std::mem::take(&mut document_ids),
+                    )
+                    .await?;
+                totals.record(sent, failed.len() as u64);
+                progress(*totals);
+                failed_ids.extend(failed);
+                payload_size = 0;
+            }
+
+            lines.push(action);
+            lines.push(source);
+            document_ids.push((account_id, document_id));
+            payload_size += size;
+        }
+
+        if !lines.is_empty() {
+            let sent = document_ids.len() as u64;
+            let failed = self.send_bulk(lines, document_ids).await?;
+            totals.record(sent, failed.len() as u64);
+            progress(*totals);
+            failed_ids.extend(failed);
+        }
+
+        Ok(failed_ids)
+    }
+
+    /// Replaces all the documents of an account with the provided ones.
+    pub async fn fts_reindex_account<'x, T: Into<u8> + Display + Clone + std::fmt::Debug>(
+        &self,
+        account_id: u32,
+        documents: impl IntoIterator<Item = FtsDocument<'x, T>>,
+        refresh: RefreshPolicy,
+    ) -> crate::Result<ReindexReport> {
+        self.fts_reindex_account_with_progress(account_id, documents, refresh, |_| ())
+            .await
+    }
+
+    /// Like `fts_reindex_account`, calling `progress` with the running totals after
+    /// each bulk request. Like `fts_index_bulk_with_progress`, the callback should
+    /// return quickly. A percentage readout:
+    ///
+    /// ```rust,no_run
+    /// # use store::{backend::elastic::{ElasticSearchStore, RefreshPolicy}, fts::index::FtsDocument};
+    /// # async fn reindex(store: &ElasticSearchStore, documents: Vec<FtsDocument<'_, u8>>) -> store::Result<()> {
+    /// let total = documents.len() as u64;
+    /// store
+    ///     .fts_reindex_account_with_progress(1, documents, RefreshPolicy::default(), |progress| {
+    ///         println!(
+    ///             "{}% reindexed, {} failed",
+    ///             progress.processed * 100 / total.max(1),
+    ///             progress.failed
+    ///         );
+    ///     })
+    ///     .await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn fts_reindex_account_with_progress<
+        'x,
+        T: Into<u8> + Display + Clone + std::fmt::Debug,
+    >(
+        &self,
+        account_id: u32,
+        documents: impl IntoIterator<Item = FtsDocument<'x, T>>,
+        refresh: RefreshPolicy,
+        mut progress: impl FnMut(BulkProgress),
+    ) -> crate::Result<ReindexReport> {
+        let mut report = ReindexReport::default();
+        let mut totals = BulkProgress::default();
+        self.fts_remove_all(account_id).await?;
+
+        let mut documents = documents.into_iter().peekable();
+        while documents.peek().is_some() {
+            // Documents belonging to other accounts are ignored
+            let batch = documents
+                .by_ref()
+                .take(REINDEX_BATCH_SIZE)
+                .filter(|document| {
+                    document.account_id == account_id && self.is_enabled(document.collection)
+                })
+                .collect::<Vec<_>>();
+            let batch_len = batch.len() as u64;
+            let failed = self
+                .index_bulk(batch, REINDEX_MAX_PAYLOAD_SIZE, &mut totals, &mut progress)
+                .await?;
+            report.indexed += batch_len - failed.len() as u64;
+            report.failed.extend(failed);
+        }
+
+        if refresh != RefreshPolicy::NoRefresh {
+            self.refresh_indices().await?;
+        }
+
+        Ok(report)
+    }
+
+    /// Queues a document to be sent along with others in a single bulk request,
+    /// once enough documents are buffered or `buffer.max-wait` has passed. Waits
+    /// when the buffer is full. Documents are indexed immediately when buffering
+    /// is disabled.
+    ///
+    /// Buffered documents are only flushed on a timer if `spawn_index_buffer`
+    /// was called, which also drains the buffer on shutdown.
+    pub async fn fts_index_buffered<T: Into<u8> + Display + Clone + std::fmt::Debug>(
+        &self,
+        document: FtsDocument<'_, T>,
+    ) -> crate::Result<()> {
+        if !self.is_enabled(document.collection) {
+            return Ok(());
+        }
+        let Some(buffer) = &self.buffer else {
+            return self.fts_index(document, RefreshPolicy::default()).await;
+        };
+        let permit = buffer.reserve().await;
+        let create = self.is_data_stream(document.collection);
+        let document = BufferedDocument {
+            index: self.index_name(document.collection),
+            id: document_key(document.account_id, document.document_id),
+            account_id: document.account_id,
+            document_id: document.document_id,
+            create,
+            routing: self.routing([document.collection], &[document.account_id]),
+            version: document.version.filter(|_| !create),
+            source: serde_json::to_string(&self.build_document(document))?,
+            _permit: permit,
+        };
+
+        match buffer.push(document) {
+            Some(batch) => self.flush_batch(batch).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Sends all buffered documents.
+    pub async fn fts_flush_buffer(&self) -> crate::Result<()> {
+        match &self.buffer {
+            Some(buffer) => self.flush_batch(buffer.take()).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Flushes buffered documents every `buffer.max-wait` until shutdown, when
+    /// the remaining documents are flushed before exiting.
+    pub fn spawn_index_buffer(self: Arc<Self>, mut shutdown_rx: watch::Receiver<bool>) {
+        if self.buffer.is_none() {
+            return;
+        }
+        tokio::spawn(async move {
+            while let Some(wait) = self.buffer.as_ref().map(|buffer| buffer.next_flush()) {
+                let shutdown = tokio::time::timeout(wait, shutdown_rx.changed())
+                    .await
+                    .is_ok();
+                if let Err(err) = self.fts_flush_buffer().await {
+                    tracing::warn!(
+                        context = "elasticsearch",
+                        event = "error",
+                        reason = %err,
+                        "Failed to flush buffered documents"
+                    );
+                }
+                if shutdown {
+                    tracing::debug!(
+                        context = "elasticsearch",
+                        event = "shutdown",
+                        "Index buffer drained"
+                    );
+                    return;
+                }
+            }
+        });
+    }
+
+    async fn flush_batch(&self, batch: Vec<BufferedDocument>) -> crate::Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let mut lines = Vec::with_capacity(batch.len() * 2);
+        let mut document_ids = Vec::with_capacity(batch.len());
+        for document in &batch {
+            let action = if document.create { "create" } else { "index" };
+            lines.push(serde_json::to_string(&json!({
+                action: bulk_metadata(
+                    &document.index,
+                    &document.id,
+                    document.routing.clone(),
+                    document.version,
+                )
+            }))?);
+            lines.push(document.source.clone());
+            document_ids.push((document.account_id, document.document_id));
+        }
+
+        match self.send_bulk(lines, document_ids).await {
+            Ok(failed_ids) => {
+                if !failed_ids.is_empty() {
+                    tracing::warn!(
+                        context = "elasticsearch",
+                        event = "error",
+                        failed = failed_ids.len(),
+                        "Failed to index buffered documents"
+                    );
+                }
+                for document in &batch {
+                    self.pending.remove(&document.index, &document.id);
+                }
+                Ok(())
+            }
+            Err(err) => {
+                // Kept for replay once the cluster is reachable again
+                for document in batch {
+                    self.pending.insert(
+                        &document.index,
+                        &document.id,
+                        PendingOperation::Index {
+                            account_id: document.account_id,
+                            document_id: document.document_id,
+                            source: document.source,
+                            create: document.create,
+                            routing: document.routing,
+                            version: document.version,
+                        },
+                    );
+                }
+                Err(err)
+            }
+        }
+    }
+
+    // Documents are sent to the home cluster of their account, each document is an
+    // action line followed by its source. Returns the ids of the rejected documents.
+    async fn send_bulk(
+        &self,
+        lines: Vec<String>,
+        documents: Vec<(u32, u32)>,
+    ) -> crate::Result<Vec<u32>> {
+        let _cache = self.cache_writes(documents.iter().map(|(account_id, _)| *account_id));
+        if self.clusters.is_empty() {
+            let document_ids = documents
+                .into_iter()
+                .map(|(_, document_id)| document_id)
+                .collect();
+            return self.send_bulk_to(self.client(), lines, document_ids).await;
+        }
+
+        // Documents are grouped by cluster along with the first account living on it
+        let mut groups: Vec<(u32, Vec<String>, Vec<u32>)> = Vec::new();
+        let mut lines = lines.into_iter();
+        for (account_id, document_id) in documents {
+            let alias = self.home_cluster(account_id);
+            let pos = match groups
+                .iter()
+                .position(|(first, ..)| self.home_cluster(*first) == alias)
+            {
+                Some(pos) => pos,
+                None => {
+                    groups.push((account_id, Vec::new(), Vec::new()));
+                    groups.len() - 1
+                }
+            };
+            let (_, group_lines, document_ids) = &mut groups[pos];
+            group_lines.extend(lines.by_ref().take(2));
+            document_ids.push(document_id);
+        }
+
+        let mut failed_ids = Vec::new();
+        for (account_id, lines, document_ids) in groups {
+            let client = self.client_for(account_id);
+            failed_ids.extend(self.send_bulk_to(client, lines, document_ids).await?);
+        }
+        Ok(failed_ids)
+    }
+
+    async fn send_bulk_to(
+        &self,
+        client: Arc<Elasticsearch>,
+        lines: Vec<String>,
+        document_ids: Vec<u32>,
+    ) -> crate::Result<Vec<u32>> {
+        let response = self
+            .send_with_retry(Operation::Index, || {
+                let request = client.bulk(BulkParts::None);
+                let request = match self.ingest_pipeline() {
+                    Some(pipeline) => request.pipeline(pipeline),
+                    None => request,
+                };
+                request
+                    .request_timeout(self.bulk_timeout)
+                    .body(lines.clone())
+                    .compatible_with(self.compatible_with)
+                    .send()
+            })
+            .await?;
+        let json: Value = assert_success(response, "Failed to index documents")
+            .await?
+            .json()
+            .await?;
+        if !json["errors"].as_bool().unwrap_or(false) {
+            return Ok(vec![]);
+        }
+
+        let items = json["items"].as_array().ok_or_else(|| {
+            crate::Error::InternalError("Invalid response from ElasticSearch".to_string())
+        })?;
+        Ok(items
+            .iter()
+            .zip(document_ids)
+            .filter_map(|(item, document_id)| {
+                // Items are keyed by their action, either "index" or "create"
+                let result = item
+                    .as_object()
+                    .and_then(|item| item.values().next())
+                    .unwrap_or(&Value::Null);
+                let error = &result["error"];
+                if error.is_null() {
+                    return None;
+                }
+                let status = result["status"].as_u64().unwrap_or_default() as u16;
+                let error = ElasticError::from_value(status, error);
+                if error.is_version_conflict() {
+                    // A newer version of the document is already indexed
+                    tracing::debug!(
+                        context = "elasticsearch",
+                        event = "skip",
+                        document_id = document_id,
+                        "Skipping stale document version"
+                    );
+                    None
                 } else {
-                    Err(crate::Error::InternalError(format!(
-                        "Failed to index document: {:?}",
-                        response
-                    )))
+                    tracing::debug!(
+                        context = "elasticsearch",
+                        event = "error",
+                        document_id = document_id,
+                        reason = %error,
+                        "Failed to index document"
+                    );
+                    Some(document_id)
                 }
             })
+            .collect())
     }
 
+    /// Removes documents of an account. Like `fts_index`, removals that fail or
+    /// are cancelled while in flight are recorded as pending and replayed later.
     pub async fn fts_remove(
         &self,
         account_id: u32,
         collection: u8,
         document_ids: &impl DocumentSet,
+        refresh: RefreshPolicy,
     ) -> crate::Result<()> {
+        if !self.is_enabled(collection) {
+            return Ok(());
+        }
         let document_ids = document_ids.iterate().collect::<Vec<_>>();
+        if let Some(fallback) = &self.fallback {
+            fallback
+                .lock()
+                .remove(account_id, collection, document_ids.iter().copied());
+        }
+
+        let index = self.index_name(collection);
+        let index = [index.as_str()];
+        let mut deleted = 0;
 
-        self.index
-            .delete_by_query(DeleteByQueryParts::Index(&[
-                INDEX_NAMES[collection as usize]
-            ]))
-            .body(json!({
+        // Terms queries are limited to "index.max_terms_count" values, larger removals
+        // are split in several requests. Each chunk is queued for replay on its own,
+        // so chunks removed before a failure are not removed again.
+        let mut chunks = document_ids.chunks(MAX_TERMS_COUNT).peekable();
+        while let Some(chunk) = chunks.next() {
+            let query = json!({
                 "query": {
                     "bool": {
                         "must": [
                             { "match": { "account_id": account_id } },
-                            { "terms": { "document_id": document_ids } }
+                            { "terms": { "document_id": chunk } }
                         ]
                     }
                 }
-            }))
-            .send()
-            .await
-            .map_err(Into::into)
-            .and_then(|response| {
-                if response.status_code().is_success() {
-                    Ok(())
-                } else {
-                    Err(crate::Error::InternalError(format!(
-                        "Failed to remove document: {:?}",
-                        response
-                    )))
+            });
+
+            let guard = PendingGuard::new(|| {
+                for document_id in chunk {
+                    self.pending.insert(
+                        index[0],
+                        &document_key(account_id, *document_id),
+                        PendingOperation::Remove {
+                            account_id,
+                            collection,
+                            document_id: *document_id,
+                        },
+                    );
+                }
+            });
+            // Delete by query does not support "wait_for", both policies refresh
+            // immediately. Refreshing after the last chunk makes all of them visible.
+            deleted += self
+                .delete_by_query(
+                    &[account_id],
+                    self.routing([collection], &[account_id]),
+                    &index,
+                    &query,
+                    refresh != RefreshPolicy::NoRefresh && chunks.peek().is_none(),
+                    &format!("Failed to remove documents of account {account_id}"),
+                )
+                .await?;
+            guard.disarm();
+            for document_id in chunk {
+                self.pending
+                    .remove(index[0], &document_key(account_id, *document_id));
+            }
+        }
+
+        tracing::debug!(
+            context = "elasticsearch",
+            event = "remove",
+            account_id = account_id,
+            requested = document_ids.len(),
+            deleted = deleted,
+            "Removed documents"
+        );
+
+        Ok(())
+    }
+
+    // Documents changed while a delete by query runs are skipped and reported as
+    // version conflicts, the query is repeated until they are deleted as well.
+    // Returns the number of deleted documents. Requests are sent to the home cluster
+    // of the first account.
+    async fn delete_by_query(
+        &self,
+        account_ids: &[u32],
+        routing: Option<String>,
+        index: &[&str],
+        query: &Value,
+        refresh: bool,
+        context: &str,
+    ) -> crate::Result<u64> {
+        let routing = routing.as_deref();
+        let mut deleted = 0;
+        let _cache = self.cache_writes(account_ids.iter().copied());
+        for _ in 0..=self.max_retries {
+            let client = self.client_for(account_ids[0]);
+            let response = self
+                .send_with_retry(Operation::Remove, || {
+                    let request = client.delete_by_query(DeleteByQueryParts::Index(index));
+                    let request = match &routing {
+                        Some(routing) => request.routing(std::slice::from_ref(routing)),
+                        None => request,
+                    };
+                    request
+                        .ignore_unavailable(true)
+                        .allow_no_indices(true)
+                        .conflicts(Conflicts::Proceed)
+                        .request_timeout(self.bulk_timeout)
+                        .refresh(refresh)
+                        .body(query)
+                        .compatible_with(self.compatible_with)
+                        .send()
+                })
+                .await?;
+            let json: Value = match assert_removed(response, context).await? {
+                Some(response) => response.json().await?,
+                None => return Ok(deleted),
+            };
+            deleted += json["deleted"].as_u64().unwrap_or(0);
+
+            if let Some(failures) = json["failures"]
+                .as_array()
+                .filter(|failures| !failures.is_empty())
+            {
+                let failures = failures
+                    .iter()
+                    .map(|failure| {
+                        let status = failure["status"].as_u64().unwrap_or_default() as u16;
+                        format!(
+                            "{} ({})",
+                            failure["id"].as_str().unwrap_or("unknown"),
+                            ElasticError::from_value(status, &failure["cause"])
+                        )
+                    })
+                    .collect::<Vec<_>>();
+                return Err(crate::Error::InternalError(format!(
+                    "{context}: {} documents could not be deleted: {}",
+                    failures.len(),
+                    failures.join(", ")
+                )));
+            }
+
+            let conflicts = json["version_conflicts"].as_u64().unwrap_or(0);
+            if conflicts == 0 {
+                return Ok(deleted);
+            }
+            tracing::debug!(
+                context = "elasticsearch",
+                event = "retry",
+                version_conflicts = conflicts,
+                "Documents changed while being deleted, repeating delete by query"
+            );
+        }
+
+        Err(crate::Error::InternalError(format!(
+            "{context}: documents kept changing while being deleted"
+        )))
+    }
+
+    /// Replays the operations that failed while the cluster was unreachable,
+    /// returning the number of operations replayed. The fallback index is cleared
+    /// once nothing is left to replay.
+    pub async fn drain_pending(&self) -> crate::Result<usize> {
+        if !self.pending.try_drain() {
+            return Ok(0);
+        }
+        let result = self.replay_pending().await;
+        if let (Ok(_), Some(fallback)) = (&result, &self.fallback) {
+            if self.pending.is_empty() {
+                fallback.lock().clear();
+            }
+        }
+        self.pending.drain_done();
+        result
+    }
+
+    async fn replay_pending(&self) -> crate::Result<usize> {
+        let mut replayed = 0;
+
+        loop {
+            let batch = self.pending.take(REINDEX_BATCH_SIZE);
+            if batch.is_empty() {
+                return Ok(replayed);
+            }
+
+            let mut lines = Vec::new();
+            let mut document_ids = Vec::new();
+            let mut removals: AHashMap<(u32, u8), Vec<u32>> = AHashMap::new();
+            for ((index, id), operation) in &batch {
+                match operation {
+                    PendingOperation::Index {
+                        account_id,
+                        document_id,
+                        source,
+                        create,
+                        routing,
+                        version,
+                    } => {
+                        let action = if *create { "create" } else { "index" };
+                        lines.push(serde_json::to_string(&json!({
+                            action: bulk_metadata(index, id, routing.clone(), *version)
+                        }))?);
+                        lines.push(source.clone());
+                        document_ids.push((*account_id, *document_id));
+                    }
+                    PendingOperation::Remove {
+                        account_id,
+                        collection,
+                        document_id,
+                    } => {
+                        removals
+                            .entry((*account_id, *collection))
+                            .or_default()
+                            .push(*document_id);
+                    }
+                }
+            }
+
+            // Failed replays are queued again unless a newer operation was queued meanwhile
+            let mut result = Ok(());
+            if !lines.is_empty() {
+                match self.send_bulk(lines, document_ids).await {
+                    Ok(failed_ids) if !failed_ids.is_empty() => {
+                        tracing::debug!(
+                            context = "elasticsearch",
+                            event = "error",
+                            count = failed_ids.len(),
+                            "Dropping pending documents rejected by ElasticSearch"
+                        );
+                    }
+                    Ok(_) => (),
+                    Err(err) => result = Err(err),
                 }
+            }
+            if result.is_ok() {
+                for ((account_id, collection), mut document_ids) in removals {
+                    document_ids.sort_unstable();
+                    if let Err(err) = self
+                        .fts_remove(
+                            account_id,
+                            collection,
+                            &document_ids,
+                            RefreshPolicy::NoRefresh,
+                        )
+                        .await
+                    {
+                        result = Err(err);
+                        break;
+                    }
+                }
+            }
+            if let Err(err) = result {
+                self.pending.restore(batch);
+                return Err(err);
+            }
+            replayed += batch.len();
+        }
+    }
+
+    pub async fn fts_remove_by_id(
+        &self,
+        account_id: u32,
+        collection: u8,
+        document_ids: &impl DocumentSet,
+    ) -> crate::Result<()> {
+        if !self.is_enabled(collection) {
+            return Ok(());
+        }
+        // Documents in data streams can only be deleted by query
+        if self.is_data_stream(collection) {
+            return self
+                .fts_remove(
+                    account_id,
+                    collection,
+                    document_ids,
+                    RefreshPolicy::NoRefresh,
+                )
+                .await;
+        }
+        let index = self.index_name(collection);
+        let routing = self.routing([collection], &[account_id]);
+        let lines = document_ids
+            .iterate()
+            .map(|document_id| {
+                serde_json::to_string(&json!({
+                    "delete": bulk_metadata(
+                        &index,
+                        &document_key(account_id, document_id),
+                        routing.clone(),
+                        None,
+                    )
+                }))
             })
+            .collect::<Result<Vec<_>, _>>()?;
+        if lines.is_empty() {
+            return Ok(());
+        }
+
+        let _cache = self.cache_writes([account_id]);
+        let client = self.client_for(account_id);
+        let response = self
+            .send_with_retry(Operation::Remove, || {
+                client
+                    .bulk(BulkParts::None)
+                    .request_timeout(self.bulk_timeout)
+                    .body(lines.clone())
+                    .compatible_with(self.compatible_with)
+                    .send()
+            })
+            .await?;
+        let json: Value = assert_success(response, "Failed to remove documents")
+            .await?
+            .json()
+            .await?;
+
+        // Deleting a missing document or a document from a missing index is not an error
+        if json["errors"].as_bool().unwrap_or(false) {
+            for item in json["items"].as_array().into_iter().flatten() {
+                let error = &item["delete"]["error"];
+                if !error.is_null() && error["type"] != "index_not_found_exception" {
+                    let status = item["delete"]["status"].as_u64().unwrap_or_default() as u16;
+                    return Err(crate::Error::InternalError(format!(
+                        "Failed to remove documents of account {account_id}: {}",
+                        ElasticError::from_value(status, error)
+                    )));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Removes the documents of an account matching the filters, for example all
+    /// messages from a sender, returning the number of removed documents. Filters
+    /// without any condition would remove every document of the account, which has
+    /// to be allowed with `allow_all`.
+    pub async fn fts_remove_by_filter<T: Into<u8> + Display + Clone + std::fmt::Debug>(
+        &self,
+        account_id: u32,
+        collection: u8,
+        filters: Vec<FtsFilter<T>>,
+        allow_all: bool,
+    ) -> crate::Result<u64> {
+        if !self.is_enabled(collection) {
+            return Ok(0);
+        }
+        let has_conditions = filters.iter().any(|filter| {
+            !matches!(
+                filter,
+                FtsFilter::And | FtsFilter::Or | FtsFilter::Not | FtsFilter::End
+            )
+        });
+        if !has_conditions && !allow_all {
+            return Err(crate::Error::InternalError(format!(
+                "Refusing to remove all documents of account {account_id} without a filter"
+            )));
+        }
+
+        let index = self.index_name(collection);
+        let query = json!({ "query": self.build_query(&[account_id], filters, true) });
+        self.delete_by_query(
+            &[account_id],
+            self.routing([collection], &[account_id]),
+            &[index.as_str()],
+            &query,
+            false,
+            &format!("Failed to remove documents of account {account_id}"),
+        )
+        .await
+    }
+
+    pub async fn fts_remove_multi(
+        &self,
+        account_id: u32,
+        targets: &[(u8, Vec<u32>)],
+    ) -> crate::Result<u64> {
+        let targets = targets
+            .iter()
+            .filter(|(collection, _)| self.is_enabled(*collection))
+            .collect::<Vec<_>>();
+        if targets.is_empty() {
+            return Ok(0);
+        }
+
+        let index_names = targets
+            .iter()
+            .map(|(collection, _)| self.index_name(*collection))
+            .collect::<Vec<_>>();
+        let collections = index_names
+            .iter()
+            .zip(&targets)
+            .map(|(index, (_, document_ids))| {
+                json!({
+                    "bool": {
+                        "must": [
+                            { "term": { "_index": index } },
+                            { "terms": { "document_id": document_ids } }
+                        ]
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        let mut index_names = index_names.iter().map(String::as_str).collect::<Vec<_>>();
+        index_names.sort_unstable();
+        index_names.dedup();
+        let query = json!({
+            "query": {
+                "bool": {
+                    "must": [
+                        { "match": { "account_id": account_id } },
+                    ],
+                    "should": collections,
+                    "minimum_should_match": 1
+                }
+            }
+        });
+
+        self.delete_by_query(
+            &[account_id],
+            self.routing(
+                targets.iter().map(|(collection, _)| *collection),
+                &[account_id],
+            ),
+            &index_names,
+            &query,
+            false,
+            &format!("Failed to remove documents of account {account_id}"),
+        )
+        .await
+    }
+
+    pub async fn fts_remove_before(&self, account_id: u32, before: i64) -> crate::Result<()> {
+        let index_names = self.index_names();
+        let index_names = index_names.iter().map(String::as_str).collect::<Vec<_>>();
+        let query = json!({
+            "query": {
+                "bool": {
+                    "must": [
+                        { "match": { "account_id": account_id } },
+                        { "range": { "received_at": { "lt": before } } },
+                    ]
+                }
+            }
+        });
+
+        self.delete_by_query(
+            &[account_id],
+            self.routing(0..INDEX_NAMES.len() as u8, &[account_id]),
+            &index_names,
+            &query,
+            false,
+            &format!("Failed to remove documents of account {account_id}"),
+        )
+        .await
+        .map(|_| ())
     }
 
     pub async fn fts_remove_all(&self, account_id: u32) -> crate::Result<()> {
-        self.index
-            .delete_by_query(DeleteByQueryParts::Index(INDEX_NAMES))
-            .body(json!({
+        self.fts_remove_all_accounts(&[account_id]).await
+    }
+
+    pub async fn fts_remove_all_accounts(&self, account_ids: &[u32]) -> crate::Result<()> {
+        // An empty list must not turn into a query matching every account
+        if account_ids.is_empty() {
+            return Ok(());
+        }
+        if let Some(fallback) = &self.fallback {
+            let mut fallback = fallback.lock();
+            for account_id in account_ids {
+                fallback.remove_account(*account_id);
+            }
+        }
+
+        let index_names = self.index_names();
+        let index_names = index_names.iter().map(String::as_str).collect::<Vec<_>>();
+        for account_ids in self.group_by_cluster(account_ids) {
+            let query = json!({
                 "query": {
                     "bool": {
                         "must": [
-                            { "match": { "account_id": account_id } },
+                            { "terms": { "account_id": account_ids } },
                         ]
                     }
                 }
-            }))
-            .send()
-            .await
-            .map_err(Into::into)
-            .and_then(|response| {
-                if response.status_code().is_success() {
-                    Ok(())
-                } else {
-                    Err(crate::Error::InternalError(format!(
-                        "Failed to remove document: {:?}",
-                        response
-                    )))
+            });
+
+            self.delete_by_query(
+                &account_ids,
+                self.routing(0..INDEX_NAMES.len() as u8, &account_ids),
+                &index_names,
+                &query,
+                false,
+                &format!("Failed to remove documents of accounts {account_ids:?}"),
+            )
+            .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Submits the removal of all the documents of an account as a background
+    /// task, returning the task id to be polled with `task_status`.
+    pub async fn fts_remove_all_async(&self, account_id: u32) -> crate::Result<String> {
+        let index_names = self.index_names();
+        let index_names = index_names.iter().map(String::as_str).collect::<Vec<_>>();
+        let query = json!({
+            "query": {
+                "bool": {
+                    "must": [
+                        { "match": { "account_id": account_id } },
+                    ]
                 }
+            }
+        });
+
+        let routing = self.routing(0..INDEX_NAMES.len() as u8, &[account_id]);
+        let routing = routing.as_deref();
+        let _cache = self.cache_writes([account_id]);
+        let client = self.client_for(account_id);
+        let response = self
+            .send_with_retry(Operation::Remove, || {
+                let request = client.delete_by_query(DeleteByQueryParts::Index(&index_names));
+                let request = match &routing {
+                    Some(routing) => request.routing(std::slice::from_ref(routing)),
+                    None => request,
+                };
+                request
+                    .ignore_unavailable(true)
+                    .allow_no_indices(true)
+                    .conflicts(Conflicts::Proceed)
+                    .wait_for_completion(false)
+                    .request_timeout(self.request_timeout)
+                    .body(&query)
+                    .compatible_with(self.compatible_with)
+                    .send()
+            })
+            .await?;
+        let json: Value = assert_success(response, "Failed to remove document")
+            .await?
+            .json()
+            .await?;
+
+        json["task"]
+            .as_str()
+            .map(|task_id| task_id.to_string())
+            .ok_or_else(|| {
+                crate::Error::InternalError("Invalid response from ElasticSearch".to_string())
             })
     }
 }
 
-impl<'x, T: Into<u8> + Display + Clone + std::fmt::Debug> From<FtsDocument<'x, T>>
-    for Document<'x>
-{
-    fn from(value: FtsDocument<'x, T>) -> Self {
+impl BulkProgress {
+    fn record(&mut self, sent: u64, failed: u64) {
+        self.processed += sent;
+        self.succeeded += sent - failed;
+        self.failed += failed;
+    }
+}
+
+impl ElasticSearchStore {
+    pub(super) fn build_document<'x, T: Into<u8> + Display + Clone + std::fmt::Debug>(
+        &self,
+        mut value: FtsDocument<'x, T>,
+    ) -> Document<'x> {
+        let is_data_stream = self.is_data_stream(value.collection);
+        let attachment_data = std::mem::take(&mut value.attachment_data);
+        let has_attachment_data = !attachment_data.is_empty();
+        if let Some(preprocessor) = &self.body_preprocessor {
+            for part in &mut value.parts {
+                if matches!(part.field, Field::Body) {
+                    part.text = Cow::Owned(preprocessor(&part.text));
+                }
+            }
+        }
+        let mut document = Document::new(
+            value,
+            &self.skip_headers,
+            self.max_header_values,
+            &self.address_headers,
+            self.max_field_length,
+        );
+        if is_data_stream {
+            document.timestamp = Some(document.received_at);
+        }
+        document.has_attachment |= has_attachment_data;
+        // Without a pipeline only the text extracted by the caller is indexed
+        if let Some(ingest) = &self.attachment_ingest {
+            document.attachment_data = attachment_data
+                .into_iter()
+                .filter(|data| data.len() <= ingest.max_size)
+                .map(|data| AttachmentData {
+                    data: STANDARD.encode(data),
+                })
+                .collect();
+        }
+        document
+    }
+
+    pub(super) fn ingest_pipeline(&self) -> Option<&str> {
+        self.attachment_ingest
+            .as_ref()
+            .map(|ingest| ingest.pipeline.as_str())
+    }
+}
+
+impl<'x> Document<'x> {
+    fn new<T: Into<u8> + Display + Clone + std::fmt::Debug>(
+        value: FtsDocument<'x, T>,
+        skip_headers: &AHashSet<String>,
+        max_header_values: Option<usize>,
+        address_headers: &AHashSet<String>,
+        max_field_length: usize,
+    ) -> Self {
         let mut document = Document {
             account_id: value.account_id,
             document_id: value.document_id,
+            // Documents without a known timestamp are dated at index time
+            received_at: value.received_at.unwrap_or_else(|| now() as i64),
+            size: value.size,
+            thread_id: value.thread_id,
+            thread: value
+                .thread_id
+                .map(|thread_id| thread_id.to_string())
+                .unwrap_or_else(|| format!("m{}", value.document_id)),
+            mailbox_ids: value.mailbox_ids,
+            // The null sender of bounces is indexed as no sender
+            envelope_from: value
+                .envelope_from
+                .as_deref()
+                .and_then(envelope_address)
+                .map(Cow::Owned),
+            envelope_to: value
+                .envelope_to
+                .iter()
+                .filter_map(|address| envelope_address(address))
+                .map(Cow::Owned)
+                .collect(),
             ..Default::default()
         };
 
+        let mut detect = LanguageDetector::new();
+        let mut body_parts = Vec::new();
+        let mut header_count: AHashMap<String, usize> = AHashMap::new();
+        let mut references = AHashSet::new();
+        // Oversized body and attachment text would exceed the field limits of ElasticSearch
+        let mut body_left = max_field_length;
+        let mut attachments_left = max_field_length;
+        let mut truncated = false;
+
         for part in value.parts {
+            // Parts without any text would only add empty tokens
+            if part.text.trim().is_empty() {
+                continue;
+            }
+
             match part.field {
-                Field::Header(name) => document.header.push(Header {
-                    name: name.to_string().into(),
-                    value: part.text,
-                }),
-                Field::Body => document.body.push(part.text),
-                Field::Attachment => document.attachments.push(part.text),
-                Field::Keyword => document.keywords.push(part.text),
+                Field::Header(name) => {
+                    let mut name = name.to_string();
+                    let mut key = name.to_ascii_lowercase();
+                    if skip_headers.contains(&key) {
+                        continue;
+                    }
+                    if !part.embedded && (key == "in-reply-to" || key == "references") {
+                        for id in part.text.split_whitespace().map(message_id) {
+                            if !id.is_empty() && references.insert(id.to_string()) {
+                                document.references.push(id.to_string());
+                            }
+                        }
+                    }
+                    // Headers of embedded messages are kept apart from the message headers
+                    if part.embedded {
+                        key = format!("embedded.{key}");
+                        name = key.clone();
+                    }
+
+                    // The first occurrence of a header is always indexed
+                    let is_address = address_headers.contains(&key);
+                    let is_subject = key == "subject";
+                    let count = header_count.entry(key).or_default();
+                    *count += 1;
+                    if max_header_values.is_none_or(|max_values| *count <= max_values) {
+                        let value = trim(part.text);
+                        let address = is_address
+                            .then(|| bare_address(&value))
+                            .filter(|address| !address.is_empty())
+                            .map(|address| Cow::Owned(address.to_string()));
+                        if is_subject && document.subject.is_none() {
+                            document.subject =
+                                sort_subject(&value).map(|subject| Cow::Owned(subject.to_string()));
+                        }
+                        document.header.push(Header {
+                            name: name.into(),
+                            value,
+                            address,
+                        });
+                    }
+                }
+                Field::Body => {
+                    let Some(text) = truncate(part.text, &mut body_left, &mut truncated) else {
+                        continue;
+                    };
+                    // Each part is indexed under its own language
+                    let language = match part.typ {
+                        Type::Text(Language::Unknown) => detect.detect(&text, MIN_LANGUAGE_SCORE),
+                        Type::Text(language) => language,
+                        Type::Tokenize | Type::Keyword => Language::None,
+                    };
+                    body_parts.push((language, text));
+                }
+                Field::Attachment => {
+                    if let Some(text) = truncate(part.text, &mut attachments_left, &mut truncated) {
+                        document.attachments.push(text);
+                    }
+                }
+                Field::Keyword => {
+                    if let Some(flag) = SystemFlag::parse(&part.text) {
+                        *document.flag_mut(flag) = true;
+                    }
+                    document.keywords.push(part.text);
+                }
+            }
+        }
+
+        if document.references.len() > MAX_REFERENCES {
+            document
+                .references
+                .drain(1..document.references.len() - (MAX_REFERENCES - 1));
+        }
+
+        if truncated {
+            tracing::debug!(
+                context = "elasticsearch",
+                event = "truncate",
+                account_id = document.account_id,
+                document_id = document.document_id,
+                max_length = max_field_length,
+                "Truncated oversized document text"
+            );
+        }
+
+        // Attachment metadata is stored apart from the extracted text in "attachments"
+        document.attachment = value
+            .attachments
+            .into_iter()
+            .map(|attachment| Attachment {
+                filename: attachment.filename,
+                content_type: attachment.content_type,
+            })
+            .collect();
+        document.has_attachment =
+            !document.attachments.is_empty() || !document.attachment.is_empty();
+
+        let default_language = detect
+            .most_frequent_language()
+            .unwrap_or(value.default_language);
+
+        for (language, text) in body_parts {
+            let language = if language == Language::Unknown {
+                default_language
+            } else {
+                language
+            };
+
+            if let Some(code) = language_code(language) {
+                document
+                    .body_lang
+                    .entry(format!("body_{code}"))
+                    .or_default()
+                    .push(text);
+            } else {
+                document.body.push(text);
             }
         }
 
         document
     }
+
+    fn flag_mut(&mut self, flag: SystemFlag) -> &mut bool {
+        match flag {
+            SystemFlag::Seen => &mut self.seen,
+            SystemFlag::Answered => &mut self.answered,
+            SystemFlag::Flagged => &mut self.flagged,
+            SystemFlag::Draft => &mut self.draft,
+            SystemFlag::Deleted => &mut self.deleted,
+        }
+    }
+}
+
+// Target of a bulk action, routed documents have to be written and deleted with
+// their routing. Versioned documents are skipped when a newer version is indexed.
+fn bulk_metadata(index: &str, id: &str, routing: Option<String>, version: Option<u64>) -> Value {
+    let mut metadata = json!({ "_index": index, "_id": id });
+    if let Some(routing) = routing {
+        metadata["routing"] = routing.into();
+    }
+    if let Some(version) = version {
+        metadata["version"] = version.into();
+        metadata["version_type"] = "external".into();
+    }
+    metadata
+}
+
+// Cuts text down to the length left for its field, on the last whitespace so no
+// token is split. Returns None once the field has no length left.
+fn truncate<'x>(
+    text: Cow<'x, str>,
+    remaining: &mut usize,
+    truncated: &mut bool,
+) -> Option<Cow<'x, str>> {
+    if text.len() <= *remaining {
+        *remaining -= text.len();
+        return Some(text);
+    }
+    *truncated = true;
+
+    let mut end = *remaining;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    let end = text[..end].rfind(char::is_whitespace).unwrap_or(end);
+    *remaining = 0;
+    if end == 0 {
+        return None;
+    }
+
+    Some(match text {
+        Cow::Borrowed(text) => Cow::Borrowed(&text[..end]),
+        Cow::Owned(mut text) => {
+            text.truncate(end);
+            Cow::Owned(text)
+        }
+    })
+}
+
+// Strips reply and forward prefixes so replies sort next to the original message,
+// the keyword normalizer lowercases the result
+fn sort_subject(subject: &str) -> Option<&str> {
+    let mut subject = subject.trim();
+    while let Some((prefix, rest)) = subject.split_once(':') {
+        if ["re", "fw", "fwd"]
+            .iter()
+            .any(|reply| prefix.trim().eq_ignore_ascii_case(reply))
+        {
+            subject = rest.trim_start();
+        } else {
+            break;
+        }
+    }
+
+    let mut end = subject.len().min(MAX_SUBJECT_LENGTH);
+    while !subject.is_char_boundary(end) {
+        end -= 1;
+    }
+    let subject = subject[..end].trim_end();
+    (!subject.is_empty()).then_some(subject)
+}
+
+fn trim(text: Cow<'_, str>) -> Cow<'_, str> {
+    match text {
+        Cow::Borrowed(text) => Cow::Borrowed(text.trim()),
+        Cow::Owned(text) if text.trim().len() != text.len() => Cow::Owned(text.trim().to_string()),
+        text => text,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ahash::AHashSet;
+    use elasticsearch::indices::IndicesDeleteParts;
+    use nlp::language::Language;
+    use utils::config::Config;
+
+    use crate::fts::{index::FtsDocument, Field};
+
+    use super::{sort_subject, Document, ValidationReport, MAX_REFERENCES};
+    use crate::backend::elastic::{
+        tests::open_store, ElasticError, ElasticSearchStore, RefreshPolicy,
+    };
+
+    #[test]
+    fn empty_parts_are_skipped() {
+        let mut document = FtsDocument::<u8>::with_default_language(Language::English)
+            .with_account_id(1)
+            .with_document_id(2)
+            .with_received_at(0);
+        document.index(Field::Body, "Hello world", Language::English);
+        document.index(Field::Body, " \n\t ", Language::English);
+        document.index_tokenized(Field::Body, "");
+        document.index(Field::Attachment, "  ", Language::English);
+        document.index(Field::Attachment, "report", Language::English);
+        document.index_keyword(Field::Keyword, "");
+        document.index_keyword(Field::Keyword, "$seen");
+        document.index_tokenized(Field::Header(0), "   ");
+        document.index_tokenized(Field::Header(1), "  Invoice  ".to_string());
+
+        let document = Document::new(
+            document,
+            &AHashSet::new(),
+            None,
+            &AHashSet::new(),
+            usize::MAX,
+        );
+
+        assert!(document.body.is_empty());
+        assert_eq!(document.body_lang.len(), 1);
+        assert_eq!(document.body_lang["body_en"], vec!["Hello world"]);
+        assert_eq!(document.attachments, vec!["report"]);
+        assert_eq!(document.keywords, vec!["$seen"]);
+        assert_eq!(document.header.len(), 1);
+        assert_eq!(document.header[0].name, "1");
+        assert_eq!(document.header[0].value, "Invoice");
+    }
+
+    #[test]
+    fn address_headers_are_normalized() {
+        let mut document = FtsDocument::<u8>::with_default_language(Language::English)
+            .with_account_id(1)
+            .with_document_id(2)
+            .with_received_at(0);
+        for value in [
+            "Alice Smith <Alice.Smith@example.com>",
+            "friends: bob@example.com;",
+            " carol@example.com ",
+        ] {
+            document.index_tokenized(Field::Header(1), value);
+        }
+        document.index_tokenized(Field::Header(2), "<Not-An-Address>");
+
+        let address_headers = AHashSet::from_iter(["1".to_string()]);
+        let document = Document::new(
+            document,
+            &AHashSet::new(),
+            None,
+            &address_headers,
+            usize::MAX,
+        );
+
+        assert_eq!(
+            document
+                .header
+                .iter()
+                .map(|header| header.address.as_deref())
+                .collect::<Vec<_>>(),
+            vec![
+                Some("Alice.Smith@example.com"),
+                Some("bob@example.com"),
+                Some("carol@example.com"),
+                None
+            ]
+        );
+        assert_eq!(
+            document.header[0].value,
+            "Alice Smith <Alice.Smith@example.com>"
+        );
+    }
+
+    #[test]
+    fn oversized_text_is_truncated() {
+        let mut document = FtsDocument::<u8>::with_default_language(Language::English)
+            .with_account_id(1)
+            .with_document_id(2)
+            .with_received_at(0);
+        document.index_tokenized(Field::Body, "lorem ipsum dolor");
+        document.index_tokenized(Field::Body, "sit amet");
+        document.index_tokenized(Field::Attachment, "ünïcödé text");
+
+        let document = Document::new(document, &AHashSet::new(), None, &AHashSet::new(), 14);
+
+        assert_eq!(document.body, vec!["lorem ipsum"]);
+        assert_eq!(document.attachments, vec!["ünïcödé"]);
+    }
+
+    #[test]
+    fn index_errors_are_redacted() {
+        let error = serde_json::json!({
+            "type": "mapper_parsing_exception",
+            "reason": concat!(
+                "failed to parse field [size] of type [long] in document with id '1:2'. ",
+                "Preview of field's value: 'Please wire the funds to account 12345'"
+            )
+        });
+
+        let error = ElasticError::from_value(400, &error).to_string();
+
+        assert!(error.starts_with("status 400, mapper_parsing_exception: failed to parse"));
+        assert!(error.contains("'1:2'"));
+        assert!(!error.contains("wire the funds"));
+    }
+
+    // Requires a local cluster, see the "elastic" store in the JMAP tests
+    #[ignore]
+    #[tokio::test]
+    async fn remove_from_missing_index() {
+        let mut config = Config::new(concat!(
+            "[store.\"elastic\"]\n",
+            "url = \"https://localhost:9200\"\n",
+            "user = \"elastic\"\n",
+            "password = \"changeme\"\n",
+            "tls.allow-invalid-certs = true\n",
+            "index.prefix = \"test_missing_\"\n",
+        ))
+        .unwrap();
+        let store = ElasticSearchStore::open(&mut config, ("store", "elastic"))
+            .await
+            .unwrap();
+
+        // Drop the indices created on startup to simulate a fresh cluster
+        for index in store.index_names() {
+            store
+                .client()
+                .indices()
+                .delete(IndicesDeleteParts::Index(&[&format!("{index}_v1")]))
+                .send()
+                .await
+                .unwrap();
+        }
+
+        store
+            .fts_remove(1, 0, &vec![1, 2, 3], RefreshPolicy::Immediate)
+            .await
+            .unwrap();
+        store.fts_remove_by_id(1, 0, &vec![1, 2, 3]).await.unwrap();
+        assert_eq!(store.fts_remove_multi(1, &[(0, vec![1])]).await.unwrap(), 0);
+        store.fts_remove_before(1, i64::MAX).await.unwrap();
+        store.fts_remove_all(1).await.unwrap();
+    }
+
+    #[test]
+    fn subjects_are_normalized_for_sorting() {
+        assert_eq!(
+            sort_subject("  Quarterly report "),
+            Some("Quarterly report")
+        );
+        assert_eq!(
+            sort_subject("RE: Fwd:re:  Quarterly report"),
+            Some("Quarterly report")
+        );
+        assert_eq!(sort_subject("Meeting: agenda"), Some("Meeting: agenda"));
+        assert_eq!(sort_subject("Re: "), None);
+        assert_eq!(sort_subject(""), None);
+        assert_eq!(sort_subject(&"ü".repeat(200)).unwrap().len(), 256);
+    }
+
+    #[test]
+    fn envelope_addresses_are_indexed_bare() {
+        let new_document = |document: FtsDocument<'static, u8>| {
+            Document::new(
+                document.with_received_at(0),
+                &AHashSet::new(),
+                None,
+                &AHashSet::new(),
+                usize::MAX,
+            )
+        };
+
+        let document = new_document(
+            FtsDocument::with_default_language(Language::English)
+                .with_envelope_from("<Bounce@Example.com>")
+                .with_envelope_to(["bcc@example.com", "<>"]),
+        );
+        assert_eq!(
+            document.envelope_from.as_deref(),
+            Some("bounce@example.com")
+        );
+        assert_eq!(document.envelope_to, vec!["bcc@example.com"]);
+
+        // Bounces and messages without envelope metadata omit the fields
+        for document in [
+            FtsDocument::with_default_language(Language::English).with_envelope_from("<>"),
+            FtsDocument::with_default_language(Language::English),
+        ] {
+            let json = serde_json::to_value(new_document(document)).unwrap();
+            assert!(json.get("envelope_from").is_none());
+            assert!(json.get("envelope_to").is_none());
+        }
+    }
+
+    #[test]
+    fn attachment_flag_is_derived() {
+        let has_attachment = |document: FtsDocument<'static, u8>| {
+            Document::new(
+                document.with_received_at(0),
+                &AHashSet::new(),
+                None,
+                &AHashSet::new(),
+                usize::MAX,
+            )
+            .has_attachment
+        };
+
+        let mut with_text = FtsDocument::with_default_language(Language::English);
+        with_text.index(Field::Attachment, "Invoice total", Language::English);
+        let mut with_metadata = FtsDocument::with_default_language(Language::English);
+        with_metadata.index_attachment(Some("photo.jpg".into()), Some("image/jpeg".into()));
+        assert!(has_attachment(with_text));
+        assert!(has_attachment(with_metadata));
+
+        // Body text and blank attachment text are not attachments
+        let mut without = FtsDocument::with_default_language(Language::English);
+        without.index(Field::Body, "Hello", Language::English);
+        without.index(Field::Attachment, " ", Language::English);
+        assert!(!has_attachment(without));
+    }
+
+    #[test]
+    fn system_flags_are_indexed_as_booleans() {
+        let mut document =
+            FtsDocument::<u8>::with_default_language(Language::English).with_received_at(0);
+        document.index_keyword(Field::Keyword, "$Seen");
+        document.index_keyword(Field::Keyword, "\\Answered");
+        document.index_keyword(Field::Keyword, "$label");
+        document.index_keyword(Field::Keyword, "seen");
+
+        let document = Document::new(
+            document,
+            &AHashSet::new(),
+            None,
+            &AHashSet::new(),
+            usize::MAX,
+        );
+        assert!(document.seen && document.answered);
+        assert!(!document.flagged && !document.draft && !document.deleted);
+        assert_eq!(
+            document.keywords,
+            vec!["$Seen", "\\Answered", "$label", "seen"]
+        );
+    }
+
+    #[derive(Debug, Clone)]
+    struct HeaderName(&'static str);
+
+    impl std::fmt::Display for HeaderName {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.write_str(self.0)
+        }
+    }
+
+    impl From<HeaderName> for u8 {
+        fn from(_: HeaderName) -> Self {
+            0
+        }
+    }
+
+    #[test]
+    fn references_are_deduplicated_and_capped() {
+        let references = |document: FtsDocument<'static, HeaderName>| {
+            Document::new(
+                document.with_received_at(0),
+                &AHashSet::new(),
+                None,
+                &AHashSet::new(),
+                usize::MAX,
+            )
+            .references
+        };
+
+        let mut document = FtsDocument::with_default_language(Language::English);
+        document.index_keyword(Field::Header(HeaderName("Message-ID")), "self@host");
+        document.index_keyword(Field::Header(HeaderName("In-Reply-To")), "<parent@host>");
+        document.index_keyword(Field::Header(HeaderName("References")), "root@host");
+        document.index_keyword(Field::Header(HeaderName("References")), "<parent@host>");
+        assert_eq!(references(document), vec!["parent@host", "root@host"]);
+
+        // Long chains keep the root and the most recent ancestors
+        let mut document = FtsDocument::with_default_language(Language::English);
+        for id in 0..MAX_REFERENCES + 10 {
+            document.index_keyword(
+                Field::Header(HeaderName("References")),
+                format!("<{id}@host>"),
+            );
+        }
+        let references = references(document);
+        assert_eq!(references.len(), MAX_REFERENCES);
+        assert_eq!(references[0], "0@host");
+        assert_eq!(references[1], "11@host");
+        assert_eq!(
+            references.last().unwrap(),
+            &format!("{}@host", MAX_REFERENCES + 9)
+        );
+    }
+
+    #[tokio::test]
+    async fn documents_are_validated() {
+        let (store, _) = open_store("{}", None, "").await;
+        let document = |collection: u8| {
+            let mut document = FtsDocument::<u8>::with_default_language(Language::English)
+                .with_account_id(1)
+                .with_document_id(2)
+                .with_collection(collection)
+                .with_received_at(0);
+            document.index(Field::Body, "Quarterly report", Language::English);
+            document
+        };
+
+        assert_eq!(
+            store.fts_index_validate(document(0)).unwrap(),
+            ValidationReport::default()
+        );
+
+        let mut with_empty_part = document(0);
+        with_empty_part.index(Field::Attachment, " \n ", Language::English);
+        let report = store.fts_index_validate(with_empty_part).unwrap();
+        assert_eq!(report.empty_parts, vec!["attachments"]);
+        assert_eq!(report.errors, Vec::<String>::new());
+
+        let report = store.fts_index_validate(document(200)).unwrap();
+        assert_eq!(
+            report.errors,
+            vec!["Collection 200 does not map to an index"]
+        );
+    }
+
+    #[tokio::test]
+    async fn subjects_pass_validation() {
+        let (store, _) = open_store("{}", None, "").await;
+        let mut document = FtsDocument::with_default_language(Language::English)
+            .with_account_id(1)
+            .with_document_id(2)
+            .with_received_at(0);
+        document.index_tokenized(Field::Header(HeaderName("Subject")), "Re: Quarterly report");
+
+        let report = store.fts_index_validate(document).unwrap();
+        assert_eq!(report.errors, Vec::<String>::new());
+    }
+
+    #[tokio::test]
+    async fn pipeline_fields_pass_validation() {
+        let (store, _) = open_store("{}", None, "index.attachments.ingest.enable = true\n").await;
+        let mut document = FtsDocument::<u8>::with_default_language(Language::English)
+            .with_account_id(1)
+            .with_document_id(2)
+            .with_received_at(0);
+        document.index(Field::Body, "See the attached report", Language::English);
+        document.index_attachment_data(&b"%PDF-1.4"[..]);
+
+        let report = store.fts_index_validate(document).unwrap();
+        assert_eq!(report.errors, Vec::<String>::new());
+    }
 }
@@ -0,0 +1,1426 @@
+/*
+ * Copyright (c) 2023 Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Mail Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::time::{Duration, Instant};
+
+use elasticsearch::{
+    cat::CatIndicesParts,
+    http::{headers::HeaderMap, Method, StatusCode},
+    ilm::IlmPutLifecycleParts,
+    indices::{
+        IndicesCloseParts, IndicesCreateDataStreamParts, IndicesCreateParts,
+        IndicesDeleteDataStreamParts, IndicesDeleteParts, IndicesExistsParts,
+        IndicesForcemergeParts, IndicesGetAliasParts, IndicesGetMappingParts, IndicesOpenParts,
+        IndicesPutIndexTemplateParts, IndicesPutMappingParts, IndicesRefreshParts,
+        IndicesStatsParts,
+    },
+    ingest::IngestPutPipelineParts,
+    params::{ExpandWildcards, Refresh},
+    IndexParts, SearchParts,
+};
+use nlp::language::Language;
+use serde_json::{json, Value};
+
+use crate::fts::{index::FtsDocument, Field, FtsFilter};
+
+use super::{
+    assert_removed, assert_success, compat::CompatibleRequest, metrics::Operation,
+    DataStreamPolicy, ElasticError, ElasticSearchStore, Flavor, INDEX_NAMES, LANGUAGE_ANALYZERS,
+};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TaskStatus {
+    pub completed: bool,
+    pub total: u64,
+    pub deleted: u64,
+    pub version_conflicts: u64,
+    pub failures: Vec<String>,
+}
+
+// Made up word so the test document is the only possible match
+const SELF_TEST_TEXT: &str = "stalwartselftest";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SelfTestStep {
+    Connect,
+    Index,
+    Search,
+    Delete,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SelfTestReport {
+    pub steps: Vec<(SelfTestStep, Duration)>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IndexStat {
+    pub name: String,
+    pub documents: u64,
+    pub size_bytes: u64,
+}
+
+impl ElasticSearchStore {
+    /// Returns the number of documents and the size of the primary shards of
+    /// each index, replicas are not included.
+    pub async fn fts_index_stats(&self) -> crate::Result<Vec<IndexStat>> {
+        let mut stats = Vec::new();
+        let client = self.client();
+        let indices = client.indices();
+
+        for name in self.index_names() {
+            let index = [name.as_str()];
+            let response = self
+                .send_with_retry(Operation::Manage, || {
+                    indices
+                        .stats(IndicesStatsParts::IndexMetric(&index, &["docs", "store"]))
+                        .request_timeout(self.request_timeout)
+                        .compatible_with(self.compatible_with)
+                        .send()
+                })
+                .await?;
+            let json: Value = assert_success(response, "Failed to obtain index stats")
+                .await?
+                .json()
+                .await?;
+
+            // Totals include every backing index of an alias or data stream
+            let primaries = &json["_all"]["primaries"];
+            stats.push(IndexStat {
+                documents: primaries["docs"]["count"].as_u64().unwrap_or(0),
+                size_bytes: primaries["store"]["size_in_bytes"].as_u64().unwrap_or(0),
+                name,
+            });
+        }
+
+        Ok(stats)
+    }
+
+    /// Estimates the storage used by an account in each index from its document
+    /// count and the average document size, ElasticSearch does not track it.
+    pub async fn fts_account_stats(&self, account_id: u32) -> crate::Result<Vec<IndexStat>> {
+        let mut stats = self.fts_index_stats().await?;
+
+        // Stats are returned in collection order
+        for (collection, stat) in stats.iter_mut().enumerate() {
+            let documents = self
+                .count_query(
+                    account_id,
+                    self.routing([collection as u8], &[account_id]),
+                    &[stat.name.as_str()],
+                    json!({ "term": { "account_id": account_id } }),
+                )
+                .await?;
+            stat.size_bytes = if stat.documents > 0 {
+                (stat.size_bytes as u128 * documents as u128 / stat.documents as u128) as u64
+            } else {
+                0
+            };
+            stat.documents = documents;
+        }
+
+        Ok(stats)
+    }
+
+    pub async fn task_status(&self, task_id: &str) -> crate::Result<TaskStatus> {
+        // The tasks API is experimental in the client, so the request is sent directly
+        let client = self.client();
+        let path = format!("/_tasks/{task_id}");
+        let response = self
+            .send_with_retry(Operation::Manage, || {
+                client.send(
+                    Method::Get,
+                    &path,
+                    HeaderMap::new().compatible_with(self.compatible_with),
+                    None::<&()>,
+                    None::<()>,
+                    Some(self.request_timeout),
+                )
+            })
+            .await?;
+        let json: Value = assert_success(response, "Failed to obtain task status")
+            .await?
+            .json()
+            .await?;
+
+        // Failures, including version conflicts when the task aborts, are only
+        // available once the task has completed.
+        let status = &json["task"]["status"];
+        let mut failures = json["response"]["failures"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(|failure| {
+                failure["cause"]["reason"]
+                    .as_str()
+                    .map(|reason| reason.to_string())
+                    .unwrap_or_else(|| failure.to_string())
+            })
+            .collect::<Vec<_>>();
+        if let Some(error) = json.get("error").filter(|error| !error.is_null()) {
+            failures.push(
+                error["reason"]
+                    .as_str()
+                    .map(|reason| reason.to_string())
+                    .unwrap_or_else(|| error.to_string()),
+            );
+        }
+
+        Ok(TaskStatus {
+            completed: json["completed"].as_bool().unwrap_or(false),
+            total: status["total"].as_u64().unwrap_or(0),
+            deleted: status["deleted"].as_u64().unwrap_or(0),
+            version_conflicts: status["version_conflicts"].as_u64().unwrap_or(0),
+            failures,
+        })
+    }
+
+    pub async fn refresh_indices(&self) -> crate::Result<()> {
+        let index_names = self.index_names();
+        let index_names = index_names.iter().map(String::as_str).collect::<Vec<_>>();
+        let client = self.client();
+        let indices = client.indices();
+        let response = self
+            .send_with_retry(Operation::Manage, || {
+                indices
+                    .refresh(IndicesRefreshParts::Index(&index_names))
+                    .request_timeout(self.request_timeout)
+                    .compatible_with(self.compatible_with)
+                    .send()
+            })
+            .await?;
+
+        assert_success(response, "Error while refreshing ElasticSearch indices")
+            .await
+            .map(|_| ())
+    }
+
+    /// Submits a force merge of the indices of a collection, or of every indexed
+    /// collection, as a background task, returning the task id to be polled with
+    /// `task_status`. A `max_segments` of zero only expunges deleted documents,
+    /// which reclaims the space left by large removals. Otherwise the segments are
+    /// merged down to `max_segments`, which is only worth it for indices that are
+    /// no longer written to. Merging rewrites the affected segments and is I/O
+    /// heavy, so it should be scheduled off-peak.
+    pub async fn fts_force_merge(
+        &self,
+        collection: Option<u8>,
+        max_segments: u32,
+    ) -> crate::Result<String> {
+        let index_names = match collection {
+            Some(collection) => vec![self.search_index(collection)?],
+            None => self
+                .index_names()
+                .into_iter()
+                .enumerate()
+                .filter(|(collection, _)| self.is_enabled(*collection as u8))
+                .map(|(_, index)| index)
+                .collect(),
+        };
+        let index_names = index_names.iter().map(String::as_str).collect::<Vec<_>>();
+        let client = self.client();
+        let indices = client.indices();
+        let response = self
+            .send_with_retry(Operation::Manage, || {
+                let request = indices.forcemerge(IndicesForcemergeParts::Index(&index_names));
+                // Both options can't be combined in the same request
+                let request = if max_segments == 0 {
+                    request.only_expunge_deletes(true)
+                } else {
+                    request.max_num_segments(max_segments as i64)
+                };
+                // Merges can take hours, so the request returns as soon as the task starts
+                request
+                    .ignore_unavailable(true)
+                    .allow_no_indices(true)
+                    .wait_for_completion(false)
+                    .request_timeout(self.request_timeout)
+                    .compatible_with(self.compatible_with)
+                    .send()
+            })
+            .await?;
+        let json: Value = assert_success(response, "Failed to force merge indices")
+            .await?
+            .json()
+            .await?;
+
+        let task_id = json["task"].as_str().ok_or_else(|| {
+            crate::Error::InternalError("Invalid response from ElasticSearch".to_string())
+        })?;
+        tracing::info!(
+            context = "elasticsearch",
+            event = "force-merge",
+            indices = ?index_names,
+            max_segments = max_segments,
+            task_id = task_id,
+            "Started force merge"
+        );
+        Ok(task_id.to_string())
+    }
+
+    /// Opens the closed indices of a collection so it can be searched, returning
+    /// whether any index was opened. Missing indices are left alone. Indices
+    /// mounted from searchable snapshots are always searchable and need no opening.
+    pub async fn ensure_open(&self, collection: u8) -> crate::Result<bool> {
+        let index = self.search_index(collection)?;
+        let index = [index.as_str()];
+        let client = self.client();
+        let cat = client.cat();
+        let response = self
+            .send_with_retry(Operation::Manage, || {
+                cat.indices(CatIndicesParts::Index(&index))
+                    .expand_wildcards(&[ExpandWildcards::All])
+                    .format("json")
+                    .h(&["index", "status"])
+                    .request_timeout(self.request_timeout)
+                    .compatible_with(self.compatible_with)
+                    .send()
+            })
+            .await?;
+        if response.status_code() == StatusCode::NOT_FOUND {
+            return Ok(false);
+        }
+        let json: Value = assert_success(response, "Failed to obtain index status")
+            .await?
+            .json()
+            .await?;
+
+        // Aliases and data streams resolve to all their backing indices
+        let mut closed = Vec::new();
+        let now = Instant::now();
+        for entry in json.as_array().into_iter().flatten() {
+            let Some(name) = entry["index"].as_str() else {
+                continue;
+            };
+            if entry["status"] == "close" {
+                closed.push(name.to_string());
+            } else if let Some(last_used) = self.opened.lock().get_mut(name) {
+                *last_used = now;
+            }
+        }
+        if closed.is_empty() {
+            return Ok(false);
+        }
+
+        let names = closed.iter().map(String::as_str).collect::<Vec<_>>();
+        let indices = client.indices();
+        let response = self
+            .send_with_retry(Operation::Manage, || {
+                indices
+                    .open(IndicesOpenParts::Index(&names))
+                    .wait_for_active_shards("1")
+                    .request_timeout(self.bulk_timeout)
+                    .compatible_with(self.compatible_with)
+                    .send()
+            })
+            .await?;
+        assert_success(response, "Error while opening ElasticSearch indices").await?;
+
+        tracing::info!(
+            context = "elasticsearch",
+            event = "open",
+            indices = ?closed,
+            "Opened closed indices for search"
+        );
+        let mut opened = self.opened.lock();
+        for name in closed {
+            opened.insert(name, now);
+        }
+        Ok(true)
+    }
+
+    /// Closes the indices opened by `ensure_open` that were not searched for
+    /// `idle`, returning their names. Indices that were open already are never closed.
+    pub async fn close_idle(&self, idle: Duration) -> crate::Result<Vec<String>> {
+        let idle_indices = self
+            .opened
+            .lock()
+            .iter()
+            .filter(|(_, last_used)| last_used.elapsed() >= idle)
+            .map(|(name, _)| name.clone())
+            .collect::<Vec<_>>();
+        if idle_indices.is_empty() {
+            return Ok(idle_indices);
+        }
+
+        let names = idle_indices.iter().map(String::as_str).collect::<Vec<_>>();
+        let client = self.client();
+        let indices = client.indices();
+        let response = self
+            .send_with_retry(Operation::Manage, || {
+                indices
+                    .close(IndicesCloseParts::Index(&names))
+                    .request_timeout(self.request_timeout)
+                    .compatible_with(self.compatible_with)
+                    .send()
+            })
+            .await?;
+        assert_success(response, "Error while closing ElasticSearch indices").await?;
+
+        let mut opened = self.opened.lock();
+        for name in &idle_indices {
+            opened.remove(name);
+        }
+        Ok(idle_indices)
+    }
+
+    /// Verifies the whole full-text path by indexing a synthetic document into a
+    /// throwaway index, searching for it and deleting the index. No real data is
+    /// read or modified. Errors name the step that failed and how long it took.
+    pub async fn fts_self_test(&self) -> crate::Result<SelfTestReport> {
+        let index = format!(
+            "{}stalwart_self_test_{:08x}",
+            self.index_prefix,
+            rand::random::<u32>()
+        );
+        let mut report = SelfTestReport::default();
+
+        let result = self.self_test_steps(&index, &mut report).await;
+
+        // The throwaway index is removed even when an earlier step failed
+        let started = Instant::now();
+        let deleted = async {
+            let response = self
+                .client()
+                .indices()
+                .delete(IndicesDeleteParts::Index(&[&index]))
+                .request_timeout(self.request_timeout)
+                .compatible_with(self.compatible_with)
+                .send()
+                .await?;
+            assert_removed(response, "Failed to delete test index")
+                .await
+                .map(|_| ())
+        }
+        .await;
+        result?;
+        deleted.map_err(|err| SelfTestStep::Delete.error(started, err))?;
+        report.steps.push((SelfTestStep::Delete, started.elapsed()));
+
+        Ok(report)
+    }
+
+    async fn self_test_steps(&self, index: &str, report: &mut SelfTestReport) -> crate::Result<()> {
+        let client = self.client();
+
+        let started = Instant::now();
+        async {
+            let response = client
+                .ping()
+                .request_timeout(self.request_timeout)
+                .compatible_with(self.compatible_with)
+                .send()
+                .await?;
+            assert_success(response, "Failed to reach cluster").await
+        }
+        .await
+        .map_err(|err| SelfTestStep::Connect.error(started, err))?;
+        report
+            .steps
+            .push((SelfTestStep::Connect, started.elapsed()));
+
+        // Uses the same mapping and analysis as the real indices
+        let started = Instant::now();
+        async {
+            let mut template = self.index_template();
+            template["settings"]["index.number_of_shards"] = 1.into();
+            template["settings"]["index.number_of_replicas"] = 0.into();
+            if let Some(settings) = template["settings"].as_object_mut() {
+                settings.remove("index.routing_partition_size");
+            }
+            let response = client
+                .indices()
+                .create(IndicesCreateParts::Index(index))
+                .request_timeout(self.request_timeout)
+                .body(template)
+                .compatible_with(self.compatible_with)
+                .send()
+                .await?;
+            assert_success(response, "Failed to create test index").await?;
+
+            let mut document = FtsDocument::<u8>::with_default_language(Language::English);
+            document.index(Field::Body, SELF_TEST_TEXT, Language::English);
+            let request = client.index(IndexParts::IndexId(index, "0:0"));
+            let request = if self.account_routing {
+                request.routing("0")
+            } else {
+                request
+            };
+            let response = request
+                .refresh(Refresh::True)
+                .request_timeout(self.request_timeout)
+                .body(self.build_document(document))
+                .compatible_with(self.compatible_with)
+                .send()
+                .await?;
+            assert_success(response, "Failed to index test document").await
+        }
+        .await
+        .map_err(|err| SelfTestStep::Index.error(started, err))?;
+        report.steps.push((SelfTestStep::Index, started.elapsed()));
+
+        let started = Instant::now();
+        async {
+            let query = json!({
+                "query": self.build_query(
+                    &[0],
+                    vec![FtsFilter::<u8>::has_english_text(Field::Body, SELF_TEST_TEXT)],
+                    true,
+                ),
+                "_source": false
+            });
+            let response = client
+                .search(SearchParts::Index(&[index]))
+                .request_timeout(self.request_timeout)
+                .body(query)
+                .compatible_with(self.compatible_with)
+                .send()
+                .await?;
+            let json: Value = assert_success(response, "Failed to search test index")
+                .await?
+                .json()
+                .await?;
+            match json["hits"]["total"]["value"].as_u64() {
+                Some(1) => Ok(()),
+                hits => Err(crate::Error::InternalError(format!(
+                    "Expected one match for the test document, found {}",
+                    hits.unwrap_or_default()
+                ))),
+            }
+        }
+        .await
+        .map_err(|err| SelfTestStep::Search.error(started, err))?;
+        report.steps.push((SelfTestStep::Search, started.elapsed()));
+
+        Ok(())
+    }
+
+    pub async fn init_indices(&self) -> crate::Result<()> {
+        let template = self.index_template();
+        self.register_remote_clusters().await?;
+        if let Some(ingest) = &self.attachment_ingest {
+            self.put_attachment_pipeline(&ingest.pipeline).await?;
+        }
+
+        for (collection, index) in self.index_names().into_iter().enumerate() {
+            // No index is created for collections that are not indexed
+            if !self.is_enabled(collection as u8) {
+                continue;
+            }
+            if let Some(policy) = self
+                .data_stream
+                .as_ref()
+                .filter(|_| self.is_data_stream(collection as u8))
+            {
+                self.init_data_stream(&index, &template, policy).await?;
+                continue;
+            }
+
+            // Templates are overwritten on every start so mapping changes are picked up
+            // by newly created indices.
+            let response = self
+                .client()
+                .indices()
+                .put_index_template(IndicesPutIndexTemplateParts::Name(&index))
+                .body(json!({
+                    "index_patterns": [&index, format!("{index}_v*")],
+                    "template": &template,
+                }))
+                .compatible_with(self.compatible_with)
+                .send()
+                .await?;
+
+            assert_success(
+                response,
+                "Error while creating ElasticSearch index template",
+            )
+            .await?;
+
+            let exists = self
+                .client()
+                .indices()
+                .exists(IndicesExistsParts::Index(&[&index]))
+                .compatible_with(self.compatible_with)
+                .send()
+                .await?;
+
+            // Indices are accessed through an alias pointing to a versioned index,
+            // existing unversioned indices are used as is until they are reindexed.
+            if exists.status_code() == StatusCode::NOT_FOUND {
+                self.create_versioned_index(&index, 1).await?;
+            } else {
+                // Analyzers and field types are fixed at creation, so the documents have
+                // to be copied into a new index created from the updated template. Copying
+                // a large index takes long, so it is left to an explicit reindex.
+                if self
+                    .mapping_changed(&index, &template["settings"]["analysis"])
+                    .await?
+                {
+                    tracing::warn!(
+                        context = "elasticsearch",
+                        event = "reindex-required",
+                        index = index,
+                        "Analysis settings or mapping changed, reindex the collection to apply them"
+                    );
+                }
+                self.put_added_mappings(&index).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // New fields can be added to existing indices without reindexing, documents
+    // indexed before have no envelope, attachment, system flag or reference fields
+    async fn put_added_mappings(&self, index: &str) -> crate::Result<()> {
+        let response = self
+            .client()
+            .indices()
+            .put_mapping(IndicesPutMappingParts::Index(&[index]))
+            .body(json!({
+                "properties": {
+                    "envelope_from": envelope_mapping(),
+                    "envelope_to": envelope_mapping(),
+                    "has_attachment": { "type": "boolean" },
+                    "seen": { "type": "boolean" },
+                    "answered": { "type": "boolean" },
+                    "flagged": { "type": "boolean" },
+                    "draft": { "type": "boolean" },
+                    "deleted": { "type": "boolean" },
+                    "references": { "type": "keyword" },
+                }
+            }))
+            .compatible_with(self.compatible_with)
+            .send()
+            .await?;
+
+        assert_success(response, "Error while updating ElasticSearch mapping")
+            .await
+            .map(|_| ())
+    }
+
+    // Extracts the text of each attachment into "attachments", attachments that
+    // can't be parsed are skipped instead of rejecting the whole message
+    async fn put_attachment_pipeline(&self, pipeline: &str) -> crate::Result<()> {
+        let response = self
+            .client()
+            .ingest()
+            .put_pipeline(IngestPutPipelineParts::Id(pipeline))
+            .body(json!({
+                "description": "Extracts the text of Stalwart message attachments",
+                "processors": [
+                    {
+                        "foreach": {
+                            "field": "attachment_data",
+                            "ignore_missing": true,
+                            "processor": {
+                                "attachment": {
+                                    "field": "_ingest._value.data",
+                                    "target_field": "_ingest._value.attachment",
+                                    "properties": ["content"],
+                                    "indexed_chars": self.max_field_length,
+                                    "ignore_failure": true
+                                }
+                            }
+                        }
+                    },
+                    {
+                        "script": {
+                            "if": "ctx.attachment_data != null",
+                            "source": concat!(
+                                "if (ctx.attachments == null) { ctx.attachments = []; } ",
+                                "for (def item : ctx.attachment_data) { ",
+                                "if (item.attachment != null && item.attachment.content != null) { ",
+                                "ctx.attachments.add(item.attachment.content); } } ",
+                                "ctx.remove('attachment_data');"
+                            )
+                        }
+                    }
+                ]
+            }))
+            .compatible_with(self.compatible_with)
+            .send()
+            .await?;
+
+        assert_success(
+            response,
+            "Error while creating ElasticSearch ingest pipeline",
+        )
+        .await
+        .map(|_| ())
+    }
+
+    pub(super) async fn mapping_changed(
+        &self,
+        index: &str,
+        current: &Value,
+    ) -> crate::Result<bool> {
+        let response = self
+            .client()
+            .indices()
+            .get_mapping(IndicesGetMappingParts::Index(&[index]))
+            .compatible_with(self.compatible_with)
+            .send()
+            .await?;
+        let json: Value = assert_success(response, "Error while obtaining ElasticSearch mapping")
+            .await?
+            .json()
+            .await?;
+
+        // Indices created before analysis settings were configurable use the defaults
+        let mappings = json
+            .as_object()
+            .and_then(|indices| indices.values().next())
+            .map(|index| &index["mappings"]);
+        let previous = mappings
+            .and_then(|mappings| mappings["_meta"].get("analysis"))
+            .cloned()
+            .unwrap_or_else(|| analysis(&[], &[], false));
+        // Headers were stored as plain objects before they were nested
+        let nested_headers =
+            mappings.is_some_and(|mappings| mappings["properties"]["header"]["type"] == "nested");
+        // Routing moves documents to other shards
+        let routing = mappings
+            .map(|mappings| &mappings["_meta"]["routing_partition_size"])
+            .and_then(Value::as_u64);
+        let routing_changed = routing
+            != self
+                .account_routing
+                .then_some(self.routing_partition_size as u64);
+
+        Ok(&previous != current || !nested_headers || routing_changed)
+    }
+
+    async fn init_data_stream(
+        &self,
+        name: &str,
+        template: &Value,
+        policy: &DataStreamPolicy,
+    ) -> crate::Result<()> {
+        let mut template = template.clone();
+        // Documents in data streams are not routed
+        if let Some(mappings) = template["mappings"].as_object_mut() {
+            mappings.remove("_routing");
+        }
+        if let Some(settings) = template["settings"].as_object_mut() {
+            settings.remove("index.routing_partition_size");
+        }
+        if self.flavor() == Flavor::OpenSearch {
+            // OpenSearch manages the lifecycle of indices with ISM, which has to be configured separately
+            tracing::warn!(
+                context = "elasticsearch",
+                event = "unsupported",
+                data_stream = name,
+                "OpenSearch does not support lifecycle policies, rollover and retention are not applied"
+            );
+        } else {
+            self.put_lifecycle_policy(name, policy).await?;
+            template["settings"]["index.lifecycle.name"] = name.into();
+        }
+
+        let response = self
+            .client()
+            .indices()
+            .put_index_template(IndicesPutIndexTemplateParts::Name(name))
+            .body(json!({
+                "index_patterns": [name],
+                "data_stream": {},
+                "template": template,
+            }))
+            .compatible_with(self.compatible_with)
+            .send()
+            .await?;
+        assert_success(
+            response,
+            "Error while creating ElasticSearch index template",
+        )
+        .await?;
+
+        let exists = self
+            .client()
+            .indices()
+            .exists(IndicesExistsParts::Index(&[name]))
+            .compatible_with(self.compatible_with)
+            .send()
+            .await?;
+        if exists.status_code() == StatusCode::NOT_FOUND {
+            let response = self
+                .client()
+                .indices()
+                .create_data_stream(IndicesCreateDataStreamParts::Name(name))
+                .compatible_with(self.compatible_with)
+                .send()
+                .await?;
+            assert_success(response, "Error while creating ElasticSearch data stream").await?;
+        }
+
+        Ok(())
+    }
+
+    // Backing indices are rolled over when they grow old or large, and deleted
+    // once the retention period has passed.
+    async fn put_lifecycle_policy(
+        &self,
+        name: &str,
+        policy: &DataStreamPolicy,
+    ) -> crate::Result<()> {
+        let response = self
+            .client()
+            .ilm()
+            .put_lifecycle(IlmPutLifecycleParts::Policy(name))
+            .body(json!({
+                "policy": {
+                    "phases": {
+                        "hot": {
+                            "actions": {
+                                "rollover": {
+                                    "max_age": &policy.rollover,
+                                    "max_primary_shard_size": "50gb"
+                                }
+                            }
+                        },
+                        "delete": {
+                            "min_age": &policy.retention,
+                            "actions": { "delete": {} }
+                        }
+                    }
+                }
+            }))
+            .compatible_with(self.compatible_with)
+            .send()
+            .await?;
+        assert_success(
+            response,
+            "Error while creating ElasticSearch lifecycle policy",
+        )
+        .await
+        .map(|_| ())
+    }
+
+    /// Deletes every document of a collection by recreating its index, which is
+    /// much faster than deleting them by query. Requires `confirm` to be set.
+    pub async fn fts_clear_collection(&self, collection: u8, confirm: bool) -> crate::Result<()> {
+        if !confirm {
+            return Err(crate::Error::InternalError(
+                "Clearing a collection deletes all its documents and has to be confirmed"
+                    .to_string(),
+            ));
+        }
+        if INDEX_NAMES.get(collection as usize).is_none() {
+            return Err(crate::Error::InternalError(format!(
+                "Collection {collection} does not map to an index"
+            )));
+        }
+        let alias = self.index_name(collection);
+        self.pending.remove_index(&alias);
+
+        if let Some(policy) = self
+            .data_stream
+            .as_ref()
+            .filter(|_| self.is_data_stream(collection))
+        {
+            let response = self
+                .client()
+                .indices()
+                .delete_data_stream(IndicesDeleteDataStreamParts::Name(&[&alias]))
+                .compatible_with(self.compatible_with)
+                .send()
+                .await?;
+            assert_removed(response, "Error while deleting ElasticSearch data stream").await?;
+            return self
+                .init_data_stream(&alias, &self.index_template(), policy)
+                .await;
+        }
+
+        // The alias can point to a versioned index or be an unversioned index itself
+        let response = self
+            .client()
+            .indices()
+            .get_alias(IndicesGetAliasParts::Name(&[&alias]))
+            .compatible_with(self.compatible_with)
+            .send()
+            .await?;
+        let (indices, version) = if response.status_code() != StatusCode::NOT_FOUND {
+            let json: Value = assert_success(response, "Error while resolving ElasticSearch alias")
+                .await?
+                .json()
+                .await?;
+            let indices = json
+                .as_object()
+                .map(|indices| indices.keys().cloned().collect::<Vec<_>>())
+                .unwrap_or_default();
+            let version = indices
+                .iter()
+                .filter_map(|index| index.strip_prefix(&alias)?.strip_prefix("_v")?.parse().ok())
+                .max()
+                .unwrap_or(0);
+            (indices, version)
+        } else {
+            (vec![alias.clone()], 0)
+        };
+
+        let indices = indices.iter().map(String::as_str).collect::<Vec<_>>();
+        let response = self
+            .client()
+            .indices()
+            .delete(IndicesDeleteParts::Index(&indices))
+            .compatible_with(self.compatible_with)
+            .send()
+            .await?;
+        assert_removed(response, "Error while deleting ElasticSearch index").await?;
+
+        tracing::info!(
+            context = "elasticsearch",
+            event = "clear",
+            index = alias,
+            "Cleared ElasticSearch index"
+        );
+
+        self.create_versioned_index(&alias, version + 1).await
+    }
+
+    pub async fn reindex_collection(
+        &self,
+        collection: u8,
+        delete_previous: bool,
+    ) -> crate::Result<String> {
+        if self.is_data_stream(collection) {
+            return Err(crate::Error::InternalError(
+                "Data streams cannot be reindexed into a new index".to_string(),
+            ));
+        }
+        if self.exclude_text_source {
+            return Err(crate::Error::InternalError(
+                "Documents without their text in the source cannot be copied by the reindex API, \
+                 index the accounts again instead"
+                    .to_string(),
+            ));
+        }
+        let alias = self.index_name(collection);
+
+        // Obtain the index the alias currently points to
+        let response = self
+            .client()
+            .indices()
+            .get_alias(IndicesGetAliasParts::Name(&[&alias]))
+            .compatible_with(self.compatible_with)
+            .send()
+            .await?;
+        let (previous, is_alias) = if response.status_code() != StatusCode::NOT_FOUND {
+            let response =
+                assert_success(response, "Error while resolving ElasticSearch alias").await?;
+            let json: Value = response.json().await?;
+            (
+                json.as_object()
+                    .and_then(|indices| indices.keys().next())
+                    .ok_or_else(|| {
+                        crate::Error::InternalError(
+                            "Invalid response from ElasticSearch".to_string(),
+                        )
+                    })?
+                    .to_string(),
+                true,
+            )
+        } else {
+            (alias.clone(), false)
+        };
+        let version = previous
+            .strip_prefix(&alias)
+            .and_then(|v| v.strip_prefix("_v"))
+            .and_then(|v| v.parse::<u32>().ok())
+            .unwrap_or(0)
+            + 1;
+        let current = format!("{alias}_v{version}");
+
+        // Copy all documents into the new index
+        let response = self
+            .client()
+            .indices()
+            .create(IndicesCreateParts::Index(&current))
+            .compatible_with(self.compatible_with)
+            .send()
+            .await?;
+        assert_success(response, "Error while creating ElasticSearch index").await?;
+        if let Err(err) = self
+            .copy_and_swap(&alias, &previous, &current, is_alias)
+            .await
+        {
+            // The partial copy is removed so the reindex can be retried
+            let removed = async {
+                let response = self
+                    .client()
+                    .indices()
+                    .delete(IndicesDeleteParts::Index(&[&current]))
+                    .compatible_with(self.compatible_with)
+                    .send()
+                    .await?;
+                assert_success(response, "Error while deleting ElasticSearch index")
+                    .await
+                    .map(|_| ())
+            }
+            .await;
+            if let Err(err) = removed {
+                tracing::warn!(
+                    context = "elasticsearch",
+                    event = "error",
+                    index = current,
+                    reason = %err,
+                    "Failed to remove partially reindexed index"
+                );
+            }
+            return Err(err);
+        }
+
+        if delete_previous && is_alias {
+            let response = self
+                .client()
+                .indices()
+                .delete(IndicesDeleteParts::Index(&[&previous]))
+                .compatible_with(self.compatible_with)
+                .send()
+                .await?;
+            assert_success(response, "Error while deleting ElasticSearch index").await?;
+        }
+
+        Ok(current)
+    }
+
+    // The alias is only moved once every document was copied, the previous index
+    // is dropped by the swap when it is unversioned
+    async fn copy_and_swap(
+        &self,
+        alias: &str,
+        previous: &str,
+        current: &str,
+        is_alias: bool,
+    ) -> crate::Result<()> {
+        let response = self
+            .client()
+            .reindex()
+            .wait_for_completion(true)
+            .body(if self.account_routing {
+                json!({
+                    "source": { "index": &previous },
+                    "dest": { "index": &current },
+                    "script": {
+                        "lang": "painless",
+                        "source": "ctx._routing = String.valueOf(ctx._source.account_id)"
+                    }
+                })
+            } else {
+                json!({
+                    "source": { "index": &previous },
+                    "dest": { "index": &current, "routing": "discard" }
+                })
+            })
+            .compatible_with(self.compatible_with)
+            .send()
+            .await?;
+        let json: Value = assert_success(response, "Error while reindexing ElasticSearch index")
+            .await?
+            .json()
+            .await?;
+        if json["timed_out"].as_bool().unwrap_or(false) {
+            return Err(crate::Error::InternalError(format!(
+                "Timed out while reindexing {previous} into {current}"
+            )));
+        }
+        if let Some(failures) = json["failures"]
+            .as_array()
+            .filter(|failures| !failures.is_empty())
+        {
+            let failures = failures
+                .iter()
+                .map(|failure| {
+                    let status = failure["status"].as_u64().unwrap_or_default() as u16;
+                    format!(
+                        "{} ({})",
+                        failure["id"].as_str().unwrap_or("unknown"),
+                        ElasticError::from_value(status, &failure["cause"])
+                    )
+                })
+                .collect::<Vec<_>>();
+            return Err(crate::Error::InternalError(format!(
+                "{} documents could not be reindexed into {current}: {}",
+                failures.len(),
+                failures.join(", ")
+            )));
+        }
+
+        // Atomically point the alias to the new index. An unversioned index has
+        // the same name as the alias, so it has to be removed in the same operation.
+        let actions = if is_alias {
+            json!([
+                { "remove": { "index": &previous, "alias": &alias } },
+                { "add": { "index": &current, "alias": &alias } }
+            ])
+        } else {
+            json!([
+                { "remove_index": { "index": &previous } },
+                { "add": { "index": &current, "alias": &alias } }
+            ])
+        };
+        let response = self
+            .client()
+            .indices()
+            .update_aliases()
+            .body(json!({ "actions": actions }))
+            .compatible_with(self.compatible_with)
+            .send()
+            .await?;
+        assert_success(response, "Error while updating ElasticSearch alias")
+            .await
+            .map(|_| ())
+    }
+
+    async fn create_versioned_index(&self, alias: &str, version: u32) -> crate::Result<()> {
+        let response = self
+            .client()
+            .indices()
+            .create(IndicesCreateParts::Index(&format!("{alias}_v{version}")))
+            .body(json!({
+                "aliases": { alias: {} }
+            }))
+            .compatible_with(self.compatible_with)
+            .send()
+            .await?;
+
+        assert_success(response, "Error while creating ElasticSearch index")
+            .await
+            .map(|_| ())
+    }
+
+    pub(super) fn index_template(&self) -> Value {
+        let analysis = analysis(&self.stopwords, &self.synonyms, self.fold_diacritics);
+        let mut template = json!({
+          "mappings": {
+            // Used to detect analysis and routing changes that require a reindex
+            "_meta": {
+              "analysis": &analysis,
+              "routing_partition_size": self.account_routing.then_some(self.routing_partition_size)
+            },
+            "properties": {
+              "document_id": {
+                "type": "integer"
+              },
+              "account_id": {
+                "type": "integer"
+              },
+              "received_at": {
+                "type": "date",
+                "format": "epoch_second"
+              },
+              // Only populated in data streams, which require a timestamp
+              "@timestamp": {
+                "type": "date",
+                "format": "epoch_second"
+              },
+              "size": {
+                "type": "long"
+              },
+              "thread_id": {
+                "type": "integer"
+              },
+              "thread": {
+                "type": "keyword"
+              },
+              "mailbox_ids": {
+                "type": "integer"
+              },
+              "envelope_from": envelope_mapping(),
+              "envelope_to": envelope_mapping(),
+              "has_attachment": {
+                "type": "boolean"
+              },
+              // System flags, also part of "keywords"
+              "seen": {
+                "type": "boolean"
+              },
+              "answered": {
+                "type": "boolean"
+              },
+              "flagged": {
+                "type": "boolean"
+              },
+              "draft": {
+                "type": "boolean"
+              },
+              "deleted": {
+                "type": "boolean"
+              },
+              // Message ids of In-Reply-To and References
+              "references": {
+                "type": "keyword"
+              },
+              // Nested so conditions on different headers never match the same entry
+              "header": {
+                "type": "nested",
+                "properties": {
+                  "name": {
+                    "type": "keyword"
+                  },
+                  "value": {
+                    "type": "text",
+                    "analyzer": "default_analyzer",
+                  },
+                  "address": {
+                    "type": "text",
+                    "analyzer": "address_analyzer",
+                  }
+                }
+              },
+              "body": {
+                "analyzer": "default_analyzer",
+                "type": "text"
+              },
+              "attachments": {
+                "analyzer": "attachment_analyzer",
+                "type": "text"
+              },
+              "attachment": {
+                "type": "object",
+                "properties": {
+                  "filename": {
+                    "type": "keyword"
+                  },
+                  "content_type": {
+                    "type": "keyword"
+                  }
+                }
+              },
+              // Matched regardless of case, the source keeps the original casing
+              "keywords": {
+                "type": "keyword",
+                "normalizer": "keyword_normalizer"
+              },
+              // Subject without reply prefixes, only used for sorting
+              "subject": {
+                "type": "object",
+                "properties": {
+                  "keyword": {
+                    "type": "keyword",
+                    "normalizer": "keyword_normalizer"
+                  }
+                }
+              }
+            }
+          },
+          "settings": {
+            "index.number_of_shards": self.shards,
+            "index.number_of_replicas": self.replicas,
+            "analysis": &analysis
+          }
+        });
+
+        if self.best_compression {
+            template["settings"]["index.codec"] = "best_compression".into();
+        }
+        // Requests missing the routing are rejected instead of reaching the wrong shard
+        if self.account_routing {
+            template["mappings"]["_routing"] = json!({ "required": true });
+            if self.routing_partition_size > 1 {
+                template["settings"]["index.routing_partition_size"] =
+                    self.routing_partition_size.into();
+            }
+        }
+        // Text fields are only searched, never read back from the source
+        if self.exclude_text_source {
+            template["mappings"]["_source"] = json!({
+                "excludes": ["body", "body_*", "attachments"]
+            });
+        }
+
+        // Language specific body fields
+        let properties = template["mappings"]["properties"].as_object_mut().unwrap();
+        for (_, code, analyzer) in LANGUAGE_ANALYZERS {
+            // Rebuilt language analyzers replace the built-in ones
+            let custom = format!("custom_{analyzer}");
+            let analyzer = if analysis["analyzer"].get(&custom).is_some() {
+                custom.as_str()
+            } else {
+                analyzer
+            };
+            properties.insert(
+                format!("body_{code}"),
+                json!({
+                    "analyzer": analyzer,
+                    "type": "text"
+                }),
+            );
+        }
+
+        template
+    }
+}
+
+impl SelfTestStep {
+    fn error(self, started: Instant, err: crate::Error) -> crate::Error {
+        crate::Error::InternalError(format!(
+            "Self-test failed at the {} step after {}ms: {err}",
+            self.as_str(),
+            started.elapsed().as_millis()
+        ))
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SelfTestStep::Connect => "connect",
+            SelfTestStep::Index => "index",
+            SelfTestStep::Search => "search",
+            SelfTestStep::Delete => "delete",
+        }
+    }
+}
+
+// Envelope addresses are matched whole regardless of case
+fn envelope_mapping() -> Value {
+    json!({
+        "type": "keyword",
+        "normalizer": "keyword_normalizer"
+    })
+}
+
+fn analysis(stopwords: &[String], synonyms: &[String], fold_diacritics: bool) -> Value {
+    let mut filters = vec!["lowercase".to_string()];
+    // Text extracted from attachments, OCR in particular, is full of stray characters
+    // and run-together words that only add noise to the index.
+    let mut filter = serde_json::Map::from_iter([(
+        "attachment_length".to_string(),
+        json!({ "type": "length", "min": 2, "max": 40 }),
+    )]);
+    if !stopwords.is_empty() {
+        filter.insert(
+            "custom_stopwords".to_string(),
+            json!({ "type": "stop", "stopwords": stopwords }),
+        );
+        filters.push("custom_stopwords".to_string());
+    }
+    if !synonyms.is_empty() {
+        filter.insert(
+            "custom_synonyms".to_string(),
+            json!({ "type": "synonym", "synonyms": synonyms }),
+        );
+        filters.push("custom_synonyms".to_string());
+    }
+    let custom_filters = filters[1..].to_vec();
+    // Folding runs last so accented stopwords and synonyms still match
+    let mut keyword_filters = vec!["lowercase"];
+    if fold_diacritics {
+        filters.push("asciifolding".to_string());
+        keyword_filters.push("asciifolding");
+    }
+    // Attachment text is always folded
+    let mut attachment_filters = filters.clone();
+    if !fold_diacritics {
+        attachment_filters.push("asciifolding".to_string());
+    }
+    attachment_filters.push("attachment_length".to_string());
+
+    // Built-in language analyzers cannot be extended, so when custom filters are
+    // configured they are rebuilt from the same stop lists and stemmers. Custom
+    // stopwords and synonyms run before stemming, as they are written unstemmed.
+    let mut language_analyzers = serde_json::Map::new();
+    if !custom_filters.is_empty() || fold_diacritics {
+        for (_, _, language) in LANGUAGE_ANALYZERS {
+            let name = format!("custom_{language}");
+            if language_analyzers.contains_key(&name) {
+                continue;
+            }
+            let stop = format!("{language}_stop");
+            filter.insert(
+                stop.clone(),
+                json!({ "type": "stop", "stopwords": format!("_{language}_") }),
+            );
+            let (tokenizer, mut chain) = match *language {
+                "cjk" => (
+                    "standard",
+                    vec![
+                        "cjk_width".to_string(),
+                        "lowercase".to_string(),
+                        "cjk_bigram".to_string(),
+                        stop,
+                    ],
+                ),
+                "thai" => (
+                    "thai",
+                    vec!["lowercase".to_string(), "decimal_digit".to_string(), stop],
+                ),
+                _ => ("standard", vec!["lowercase".to_string(), stop]),
+            };
+            chain.extend(custom_filters.iter().cloned());
+            // CJK and Thai text is not stemmed
+            if !matches!(*language, "cjk" | "thai") {
+                let stemmer = format!("{language}_stemmer");
+                filter.insert(
+                    stemmer.clone(),
+                    json!({ "type": "stemmer", "language": language }),
+                );
+                chain.push(stemmer);
+            }
+            if fold_diacritics {
+                chain.push("asciifolding".to_string());
+            }
+            language_analyzers.insert(
+                name,
+                json!({
+                    "type": "custom",
+                    "tokenizer": tokenizer,
+                    "filter": chain
+                }),
+            );
+        }
+    }
+
+    let mut analysis = json!({
+      "analyzer": {
+        "default_analyzer": {
+          "type": "custom",
+          "tokenizer": "standard",
+          "filter": filters
+        },
+        "attachment_analyzer": {
+          "type": "custom",
+          "tokenizer": "standard",
+          "filter": attachment_filters
+        },
+        // Keeps email addresses and message ids as single tokens
+        "address_analyzer": {
+          "type": "custom",
+          "tokenizer": "uax_url_email",
+          "filter": ["lowercase"]
+        }
+      },
+      "normalizer": {
+        "keyword_normalizer": {
+          "type": "custom",
+          "filter": keyword_filters
+        }
+      },
+      "filter": filter
+    });
+    analysis["analyzer"]
+        .as_object_mut()
+        .unwrap()
+        .extend(language_analyzers);
+    analysis
+}
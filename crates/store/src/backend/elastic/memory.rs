@@ -0,0 +1,426 @@
+/*
+ * Copyright (c) 2023 Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Mail Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::{collections::VecDeque, fmt::Display};
+
+use ahash::AHashMap;
+use roaring::RoaringBitmap;
+
+use crate::fts::{index::FtsDocument, Field, FtsFilter, SystemFlag};
+
+use super::{envelope_address, message_id};
+
+// Documents held in memory, text filters are matched as case insensitive
+// substrings of each part instead of analyzed terms. Beyond `max_documents` the
+// oldest documents are discarded.
+pub(crate) struct MemoryIndex {
+    max_documents: usize,
+    documents: AHashMap<(u32, u8), AHashMap<u32, MemoryDocument>>,
+    // Documents in the order they were indexed, with the sequence number of the
+    // indexing. Entries of documents indexed again or removed since are stale.
+    order: VecDeque<((u32, u8, u32), u64)>,
+    sequence: u64,
+    len: usize,
+}
+
+struct MemoryDocument {
+    sequence: u64,
+    size: Option<u64>,
+    mailbox_ids: Vec<u32>,
+    envelope_from: Option<String>,
+    envelope_to: Vec<String>,
+    has_attachment: bool,
+    flags: Vec<SystemFlag>,
+    references: Vec<String>,
+    // Field name and lowercase text of each part
+    parts: Vec<(String, String)>,
+}
+
+impl MemoryIndex {
+    pub fn new(max_documents: usize) -> Self {
+        MemoryIndex {
+            max_documents,
+            documents: AHashMap::new(),
+            order: VecDeque::new(),
+            sequence: 0,
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    #[cfg(feature = "test-util")]
+    pub fn account_len(&self, account_id: u32, collection: u8) -> usize {
+        self.documents
+            .get(&(account_id, collection))
+            .map_or(0, |documents| documents.len())
+    }
+
+    pub fn insert<T: Into<u8> + Display + Clone + std::fmt::Debug>(
+        &mut self,
+        document: FtsDocument<'_, T>,
+    ) {
+        let has_attachment = !document.attachments.is_empty()
+            || !document.attachment_data.is_empty()
+            || document.parts.iter().any(|part| {
+                matches!(part.field, Field::Attachment) && !part.text.trim().is_empty()
+            });
+        let flags = document
+            .parts
+            .iter()
+            .filter(|part| matches!(part.field, Field::Keyword))
+            .filter_map(|part| SystemFlag::parse(&part.text))
+            .collect();
+        let references = document
+            .parts
+            .iter()
+            .filter(|part| {
+                !part.embedded
+                    && matches!(&part.field, Field::Header(name)
+                        if ["in-reply-to", "references"]
+                            .iter()
+                            .any(|header| name.to_string().eq_ignore_ascii_case(header)))
+            })
+            .flat_map(|part| part.text.split_whitespace().map(message_id))
+            .map(str::to_string)
+            .collect();
+        let parts = document
+            .parts
+            .into_iter()
+            .map(|part| (part.field.name().into_owned(), part.text.to_lowercase()))
+            .collect();
+
+        self.sequence += 1;
+        let key = (
+            document.account_id,
+            document.collection,
+            document.document_id,
+        );
+        let replaced = self
+            .documents
+            .entry((document.account_id, document.collection))
+            .or_default()
+            .insert(
+                document.document_id,
+                MemoryDocument {
+                    sequence: self.sequence,
+                    size: document.size,
+                    mailbox_ids: document.mailbox_ids,
+                    envelope_from: document.envelope_from.as_deref().and_then(envelope_address),
+                    envelope_to: document
+                        .envelope_to
+                        .iter()
+                        .filter_map(|address| envelope_address(address))
+                        .collect(),
+                    has_attachment,
+                    flags,
+                    references,
+                    parts,
+                },
+            );
+        if replaced.is_none() {
+            self.len += 1;
+        }
+        self.order.push_back((key, self.sequence));
+
+        while self.len > self.max_documents {
+            let Some(((account_id, collection, document_id), sequence)) = self.order.pop_front()
+            else {
+                break;
+            };
+            if let Some(documents) = self.documents.get_mut(&(account_id, collection)) {
+                if documents
+                    .get(&document_id)
+                    .is_some_and(|document| document.sequence == sequence)
+                {
+                    documents.remove(&document_id);
+                    self.len -= 1;
+                }
+            }
+        }
+        // Stale entries are dropped once they outnumber the documents
+        if self.order.len() > self.len.saturating_mul(2).max(64) {
+            let documents = &self.documents;
+            self.order
+                .retain(|((account_id, collection, document_id), sequence)| {
+                    documents
+                        .get(&(*account_id, *collection))
+                        .and_then(|documents| documents.get(document_id))
+                        .is_some_and(|document| document.sequence == *sequence)
+                });
+        }
+    }
+
+    pub fn query<T: Into<u8> + Display + Clone + std::fmt::Debug>(
+        &self,
+        account_id: u32,
+        collection: u8,
+        filters: Vec<FtsFilter<T>>,
+        include_attachments: bool,
+    ) -> RoaringBitmap {
+        let documents = match self.documents.get(&(account_id, collection)) {
+            Some(documents) => documents,
+            None => return RoaringBitmap::new(),
+        };
+        let all = documents.keys().copied().collect::<RoaringBitmap>();
+
+        let mut stack: Vec<(FtsFilter<T>, Vec<RoaringBitmap>)> = vec![];
+        let mut logical_op = FtsFilter::And;
+        let mut results = Vec::new();
+
+        for filter in filters {
+            match filter {
+                FtsFilter::And | FtsFilter::Or | FtsFilter::Not => {
+                    stack.push((logical_op, std::mem::take(&mut results)));
+                    logical_op = filter;
+                }
+                FtsFilter::End => {
+                    if let Some((prev_logical_op, mut prev_results)) = stack.pop() {
+                        prev_results.push(combine(&logical_op, results, &all));
+                        logical_op = prev_logical_op;
+                        results = prev_results;
+                    }
+                }
+                filter => results.push(
+                    documents
+                        .iter()
+                        .filter(|(_, document)| document.matches(&filter, include_attachments))
+                        .map(|(document_id, _)| *document_id)
+                        .collect(),
+                ),
+            }
+        }
+
+        // Groups left open are closed at the end
+        while let Some((prev_logical_op, mut prev_results)) = stack.pop() {
+            prev_results.push(combine(&logical_op, results, &all));
+            logical_op = prev_logical_op;
+            results = prev_results;
+        }
+
+        combine(&logical_op, results, &all)
+    }
+
+    pub fn remove(
+        &mut self,
+        account_id: u32,
+        collection: u8,
+        document_ids: impl IntoIterator<Item = u32>,
+    ) {
+        if let Some(documents) = self.documents.get_mut(&(account_id, collection)) {
+            for document_id in document_ids {
+                if documents.remove(&document_id).is_some() {
+                    self.len -= 1;
+                }
+            }
+        }
+    }
+
+    pub fn remove_account(&mut self, account_id: u32) {
+        let len = &mut self.len;
+        self.documents
+            .retain(|(document_account_id, _), documents| {
+                if *document_account_id == account_id {
+                    *len -= documents.len();
+                    false
+                } else {
+                    true
+                }
+            });
+    }
+
+    pub fn clear(&mut self) {
+        self.documents.clear();
+        self.order.clear();
+        self.len = 0;
+    }
+}
+
+impl MemoryDocument {
+    fn matches<T: Into<u8> + Display + Clone + std::fmt::Debug>(
+        &self,
+        filter: &FtsFilter<T>,
+        include_attachments: bool,
+    ) -> bool {
+        match filter {
+            FtsFilter::Exact {
+                field: Field::Attachment,
+                ..
+            }
+            | FtsFilter::Contains {
+                field: Field::Attachment,
+                ..
+            }
+            | FtsFilter::Phrase {
+                field: Field::Attachment,
+                ..
+            }
+            | FtsFilter::Keyword {
+                field: Field::Attachment,
+                ..
+            }
+            | FtsFilter::Prefix {
+                field: Field::Attachment,
+                ..
+            }
+            | FtsFilter::Wildcard {
+                field: Field::Attachment,
+                ..
+            } if !include_attachments => false,
+            FtsFilter::Exact { field, text, .. }
+            | FtsFilter::Contains { field, text, .. }
+            | FtsFilter::Phrase { field, text, .. } => self.contains(field, text, false),
+            FtsFilter::Keyword { field, text } => {
+                self.contains(field, text, matches!(field, Field::Keyword))
+            }
+            FtsFilter::Header { name, value } => {
+                let name = format!("header.{name}");
+                let value = value.to_lowercase();
+                self.parts
+                    .iter()
+                    .any(|(field, text)| field.eq_ignore_ascii_case(&name) && text.contains(&value))
+            }
+            FtsFilter::Prefix { field, value } => {
+                let value = value.to_lowercase();
+                self.any_term(field, |term| term.starts_with(&value))
+            }
+            FtsFilter::Wildcard { field, value } => {
+                let value = value.to_lowercase().chars().collect::<Vec<_>>();
+                self.any_term(field, |term| {
+                    wildcard_matches(&value, &term.chars().collect::<Vec<_>>())
+                })
+            }
+            FtsFilter::InMailbox(mailbox_id) => self.mailbox_ids.iter().any(|id| id == mailbox_id),
+            FtsFilter::EnvelopeFrom(address) => {
+                self.envelope_from.is_some() && self.envelope_from == envelope_address(address)
+            }
+            FtsFilter::EnvelopeTo(address) => {
+                envelope_address(address).is_some_and(|address| self.envelope_to.contains(&address))
+            }
+            FtsFilter::HasAttachment(has_attachment) => self.has_attachment == *has_attachment,
+            FtsFilter::Flag(flag, is_set) => self.flags.contains(flag) == *is_set,
+            FtsFilter::References(id) => self
+                .references
+                .iter()
+                .any(|reference| reference == message_id(id)),
+            FtsFilter::SizeRange { min, max } => self.size.is_some_and(|size| {
+                min.is_none_or(|min| size >= min) && max.is_none_or(|max| size <= max)
+            }),
+            FtsFilter::And | FtsFilter::Or | FtsFilter::Not | FtsFilter::End => false,
+        }
+    }
+
+    fn contains<T: Into<u8> + Display + Clone + std::fmt::Debug>(
+        &self,
+        field: &Field<T>,
+        text: &str,
+        exact: bool,
+    ) -> bool {
+        let field = field.name();
+        let text = text.to_lowercase();
+        self.parts.iter().any(|(name, value)| {
+            *name == field
+                && if exact {
+                    *value == text
+                } else {
+                    value.contains(&text)
+                }
+        })
+    }
+
+    // Terms are the words of each part, so "Alice <alice@example.com>" has the
+    // terms "alice" and "alice@example.com"
+    fn any_term<T: Into<u8> + Display + Clone + std::fmt::Debug>(
+        &self,
+        field: &Field<T>,
+        matches: impl Fn(&str) -> bool,
+    ) -> bool {
+        let field = field.name();
+        self.parts.iter().any(|(name, value)| {
+            *name == field
+                && value
+                    .split_whitespace()
+                    .map(|term| term.trim_matches(|ch: char| !ch.is_alphanumeric()))
+                    .any(&matches)
+        })
+    }
+}
+
+fn wildcard_matches(pattern: &[char], text: &[char]) -> bool {
+    match pattern.split_first() {
+        None => text.is_empty(),
+        Some(('*', rest)) => (0..=text.len()).any(|pos| wildcard_matches(rest, &text[pos..])),
+        Some((ch, rest)) => text.split_first().is_some_and(|(first, text)| {
+            (*ch == '?' || ch == first) && wildcard_matches(rest, text)
+        }),
+    }
+}
+
+fn combine<T: Into<u8> + Display + Clone + std::fmt::Debug>(
+    logical_op: &FtsFilter<T>,
+    results: Vec<RoaringBitmap>,
+    all: &RoaringBitmap,
+) -> RoaringBitmap {
+    match logical_op {
+        FtsFilter::Or => results.into_iter().fold(RoaringBitmap::new(), |a, b| a | b),
+        FtsFilter::Not => all - results.into_iter().fold(RoaringBitmap::new(), |a, b| a | b),
+        _ => results.into_iter().fold(all.clone(), |a, b| a & b),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nlp::language::Language;
+
+    use crate::fts::{index::FtsDocument, Field, FtsFilter};
+
+    use super::MemoryIndex;
+
+    #[test]
+    fn oldest_documents_are_discarded() {
+        let mut index = MemoryIndex::new(2);
+        for document_id in [0, 1, 0, 2] {
+            let mut document = FtsDocument::<u8>::with_default_language(Language::English)
+                .with_account_id(1)
+                .with_document_id(document_id);
+            document.index(Field::Body, "Quarterly report", Language::English);
+            index.insert(document);
+        }
+
+        // Indexing a document again makes it the newest
+        assert_eq!(index.len(), 2);
+        let filters: Vec<FtsFilter<u8>> = vec![FtsFilter::has_english_text(Field::Body, "report")];
+        assert_eq!(
+            index.query(1, 0, filters, true).iter().collect::<Vec<_>>(),
+            vec![0, 2]
+        );
+
+        index.remove(1, 0, [0]);
+        index.remove_account(2);
+        assert_eq!(index.len(), 1);
+        index.remove_account(1);
+        assert_eq!(index.len(), 0);
+    }
+}
@@ -0,0 +1,150 @@
+/*
+ * Copyright (c) 2023 Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Mail Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::time::Duration;
+
+#[cfg(feature = "metrics")]
+use std::sync::atomic::{AtomicU64, Ordering};
+
+#[cfg(feature = "metrics")]
+use ahash::AHashMap;
+#[cfg(feature = "metrics")]
+use parking_lot::Mutex;
+
+// Upper bounds in milliseconds of the latency histogram buckets
+pub const LATENCY_BUCKETS: &[u64] = &[5, 10, 25, 50, 100, 250, 500, 1000, 2500, 5000, 10000];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Operation {
+    Index,
+    Remove,
+    Search,
+    Manage,
+}
+
+#[derive(Default)]
+pub(crate) struct Metrics {
+    #[cfg(feature = "metrics")]
+    operations: [OperationCounters; 4],
+    #[cfg(feature = "metrics")]
+    errors: Mutex<AHashMap<String, u64>>,
+}
+
+#[cfg(feature = "metrics")]
+#[derive(Default)]
+struct OperationCounters {
+    requests: AtomicU64,
+    failures: AtomicU64,
+    // One extra bucket for requests slower than the last bound
+    latency: [AtomicU64; 12],
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OperationMetrics {
+    pub requests: u64,
+    pub failures: u64,
+    pub latency: Vec<u64>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    pub index: OperationMetrics,
+    pub remove: OperationMetrics,
+    pub search: OperationMetrics,
+    pub manage: OperationMetrics,
+    pub errors: Vec<(String, u64)>,
+    // Tracked regardless of the feature, failed operations waiting to be replayed,
+    // documents waiting in the index buffer, requests awaiting a response,
+    // searches served from or missing the query cache and documents held by the
+    // fallback index
+    pub pending_operations: u64,
+    pub buffered_documents: u64,
+    pub in_flight_requests: u64,
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+    pub fallback_documents: u64,
+}
+
+impl Metrics {
+    #[allow(unused_variables)]
+    pub fn record(&self, operation: Operation, elapsed: Duration, error: Option<&str>) {
+        #[cfg(feature = "metrics")]
+        {
+            let counters = &self.operations[operation as usize];
+            let elapsed = elapsed.as_millis() as u64;
+            let bucket = LATENCY_BUCKETS
+                .iter()
+                .position(|bound| elapsed <= *bound)
+                .unwrap_or(LATENCY_BUCKETS.len());
+
+            counters.requests.fetch_add(1, Ordering::Relaxed);
+            counters.latency[bucket].fetch_add(1, Ordering::Relaxed);
+            if let Some(error) = error {
+                counters.failures.fetch_add(1, Ordering::Relaxed);
+                *self.errors.lock().entry(error.to_string()).or_default() += 1;
+            }
+        }
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        #[cfg(feature = "metrics")]
+        {
+            let operation = |operation: Operation| {
+                let counters = &self.operations[operation as usize];
+                OperationMetrics {
+                    requests: counters.requests.load(Ordering::Relaxed),
+                    failures: counters.failures.load(Ordering::Relaxed),
+                    latency: counters
+                        .latency
+                        .iter()
+                        .map(|count| count.load(Ordering::Relaxed))
+                        .collect(),
+                }
+            };
+            let mut errors = self
+                .errors
+                .lock()
+                .iter()
+                .map(|(error, count)| (error.clone(), *count))
+                .collect::<Vec<_>>();
+            errors.sort_unstable();
+
+            MetricsSnapshot {
+                index: operation(Operation::Index),
+                remove: operation(Operation::Remove),
+                search: operation(Operation::Search),
+                manage: operation(Operation::Manage),
+                errors,
+                pending_operations: 0,
+                buffered_documents: 0,
+                in_flight_requests: 0,
+                cache_hits: 0,
+                cache_misses: 0,
+                fallback_documents: 0,
+            }
+        }
+
+        #[cfg(not(feature = "metrics"))]
+        MetricsSnapshot::default()
+    }
+}
@@ -0,0 +1,340 @@
+/*
+ * Copyright (c) 2023 Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Mail Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::fmt::Display;
+
+use parking_lot::Mutex;
+use roaring::RoaringBitmap;
+
+use crate::{
+    dispatch::DocumentSet,
+    fts::{index::FtsDocument, FtsFilter},
+};
+
+use super::{backend::FtsBackend, memory::MemoryIndex};
+
+/// In-memory full-text store for tests. Documents are only visible to their own
+/// account and text filters are matched as case insensitive substrings.
+pub struct MockFtsStore {
+    index: Mutex<MemoryIndex>,
+}
+
+impl Default for MockFtsStore {
+    fn default() -> Self {
+        MockFtsStore {
+            index: Mutex::new(MemoryIndex::new(usize::MAX)),
+        }
+    }
+}
+
+impl MockFtsStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of documents indexed for an account in a collection.
+    pub fn len(&self, account_id: u32, collection: u8) -> usize {
+        self.index.lock().account_len(account_id, collection)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index.lock().len() == 0
+    }
+}
+
+impl FtsBackend for MockFtsStore {
+    async fn fts_index<T: Into<u8> + Display + Clone + std::fmt::Debug + Send + Sync>(
+        &self,
+        document: FtsDocument<'_, T>,
+    ) -> crate::Result<()> {
+        self.index.lock().insert(document);
+        Ok(())
+    }
+
+    async fn fts_query<T: Into<u8> + Display + Clone + std::fmt::Debug + Send + Sync>(
+        &self,
+        account_id: u32,
+        collection: u8,
+        filters: Vec<FtsFilter<T>>,
+        include_attachments: bool,
+    ) -> crate::Result<RoaringBitmap> {
+        Ok(self
+            .index
+            .lock()
+            .query(account_id, collection, filters, include_attachments))
+    }
+
+    async fn fts_remove(
+        &self,
+        account_id: u32,
+        collection: u8,
+        document_ids: &impl DocumentSet,
+    ) -> crate::Result<()> {
+        self.index
+            .lock()
+            .remove(account_id, collection, document_ids.iterate());
+        Ok(())
+    }
+
+    async fn fts_remove_all(&self, account_id: u32) -> crate::Result<()> {
+        self.index.lock().remove_account(account_id);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nlp::language::Language;
+
+    use crate::{
+        backend::elastic::backend::FtsBackend,
+        fts::{index::FtsDocument, Field, FtsFilter, SystemFlag},
+    };
+
+    use super::MockFtsStore;
+
+    #[tokio::test]
+    async fn query_is_scoped_to_account() {
+        let store = MockFtsStore::new();
+        for (account_id, document_id, body) in [
+            (1, 0, "Quarterly report"),
+            (1, 1, "Holiday plans"),
+            (2, 0, "Quarterly report"),
+        ] {
+            let mut document = FtsDocument::<u8>::with_default_language(Language::English)
+                .with_account_id(account_id)
+                .with_document_id(document_id);
+            document.index(Field::Body, body, Language::English);
+            store.fts_index(document).await.unwrap();
+        }
+
+        let filters: Vec<FtsFilter<u8>> = vec![FtsFilter::has_english_text(Field::Body, "REPORT")];
+        let results = store.fts_query(1, 0, filters, true).await.unwrap();
+        assert_eq!(results.iter().collect::<Vec<_>>(), vec![0]);
+
+        let filters: Vec<FtsFilter<u8>> = vec![
+            FtsFilter::Not,
+            FtsFilter::has_english_text(Field::Body, "report"),
+            FtsFilter::End,
+        ];
+        let results = store.fts_query(1, 0, filters, true).await.unwrap();
+        assert_eq!(results.iter().collect::<Vec<_>>(), vec![1]);
+
+        store.fts_remove_all(1).await.unwrap();
+        assert_eq!(store.len(1, 0), 0);
+        assert_eq!(store.len(2, 0), 1);
+    }
+
+    #[tokio::test]
+    async fn attachment_flag_filters_messages() {
+        let store = MockFtsStore::new();
+        for (document_id, attachment) in [(0, None), (1, Some("report.pdf")), (2, None)] {
+            let mut document = FtsDocument::<u8>::with_default_language(Language::English)
+                .with_account_id(1)
+                .with_document_id(document_id);
+            document.index(Field::Body, "Quarterly report", Language::English);
+            if let Some(filename) = attachment {
+                document.index_attachment(Some(filename.into()), None);
+            }
+            store.fts_index(document).await.unwrap();
+        }
+
+        for (has_attachment, expected) in [(true, vec![1]), (false, vec![0, 2])] {
+            let filters: Vec<FtsFilter<u8>> = vec![
+                FtsFilter::has_english_text(Field::Body, "report"),
+                FtsFilter::has_attachment(has_attachment),
+            ];
+            let results = store.fts_query(1, 0, filters, true).await.unwrap();
+            assert_eq!(results.iter().collect::<Vec<_>>(), expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn unread_search_excludes_seen_messages() {
+        let store = MockFtsStore::new();
+        for (document_id, keywords) in [
+            (0, vec!["$seen", "$label"]),
+            (1, vec!["$label"]),
+            (2, vec!["\\Seen", "$flagged"]),
+            (3, vec![]),
+        ] {
+            let mut document = FtsDocument::<u8>::with_default_language(Language::English)
+                .with_account_id(1)
+                .with_document_id(document_id);
+            document.index(Field::Body, "Quarterly report", Language::English);
+            for keyword in keywords {
+                document.index_keyword(Field::Keyword, keyword);
+            }
+            store.fts_index(document).await.unwrap();
+        }
+
+        for (filter, expected) in [
+            (FtsFilter::has_flag(SystemFlag::Seen, false), vec![1, 3]),
+            (FtsFilter::has_flag(SystemFlag::Seen, true), vec![0, 2]),
+            (FtsFilter::has_flag(SystemFlag::Flagged, true), vec![2]),
+        ] {
+            let filters: Vec<FtsFilter<u8>> =
+                vec![FtsFilter::has_english_text(Field::Body, "report"), filter];
+            let results = store.fts_query(1, 0, filters, true).await.unwrap();
+            assert_eq!(results.iter().collect::<Vec<_>>(), expected);
+        }
+    }
+
+    #[derive(Debug, Clone)]
+    struct Subject;
+
+    impl From<Subject> for u8 {
+        fn from(_: Subject) -> Self {
+            0
+        }
+    }
+
+    impl std::fmt::Display for Subject {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.write_str("Subject")
+        }
+    }
+
+    #[tokio::test]
+    async fn header_filter_ignores_body() {
+        let store = MockFtsStore::new();
+        for (document_id, subject, body) in [
+            (0, "Meeting tomorrow", "See attached"),
+            (1, "Lunch", "The meeting was moved"),
+        ] {
+            let mut document = FtsDocument::with_default_language(Language::English)
+                .with_account_id(1)
+                .with_document_id(document_id);
+            document.index(Field::Header(Subject), subject, Language::English);
+            document.index(Field::Body, body, Language::English);
+            store.fts_index(document).await.unwrap();
+        }
+
+        let filters: Vec<FtsFilter<Subject>> = vec![FtsFilter::has_header("subject", "meeting")];
+        let results = store.fts_query(1, 0, filters, true).await.unwrap();
+        assert_eq!(results.iter().collect::<Vec<_>>(), vec![0]);
+    }
+
+    #[tokio::test]
+    async fn mailbox_filter_matches_any_mailbox() {
+        let store = MockFtsStore::new();
+        for (document_id, mailbox_ids) in [(0, vec![1, 2]), (1, vec![2]), (2, vec![])] {
+            let mut document = FtsDocument::<u8>::with_default_language(Language::English)
+                .with_account_id(1)
+                .with_document_id(document_id)
+                .with_mailbox_ids(mailbox_ids);
+            document.index(Field::Body, "Invoice", Language::English);
+            store.fts_index(document).await.unwrap();
+        }
+
+        let filters: Vec<FtsFilter<u8>> = vec![
+            FtsFilter::And,
+            FtsFilter::InMailbox(1),
+            FtsFilter::has_english_text(Field::Body, "invoice"),
+            FtsFilter::End,
+        ];
+        let results = store.fts_query(1, 0, filters, true).await.unwrap();
+        assert_eq!(results.iter().collect::<Vec<_>>(), vec![0]);
+
+        // Messages in no mailbox are still found by unscoped searches
+        let filters: Vec<FtsFilter<u8>> = vec![FtsFilter::has_english_text(Field::Body, "invoice")];
+        let results = store.fts_query(1, 0, filters, true).await.unwrap();
+        assert_eq!(results.iter().collect::<Vec<_>>(), vec![0, 1, 2]);
+    }
+
+    #[tokio::test]
+    async fn attachments_can_be_excluded() {
+        let store = MockFtsStore::new();
+        for (document_id, field, text) in [
+            (0, Field::Body, "Invoice"),
+            (1, Field::Attachment, "Invoice"),
+        ] {
+            let mut document = FtsDocument::<u8>::with_default_language(Language::English)
+                .with_account_id(1)
+                .with_document_id(document_id);
+            document.index(field, text, Language::English);
+            store.fts_index(document).await.unwrap();
+        }
+        let filters = || -> Vec<FtsFilter<u8>> {
+            vec![
+                FtsFilter::Or,
+                FtsFilter::has_english_text(Field::Body, "invoice"),
+                FtsFilter::has_english_text(Field::Attachment, "invoice"),
+                FtsFilter::End,
+            ]
+        };
+
+        let results = store.fts_query(1, 0, filters(), true).await.unwrap();
+        assert_eq!(results.iter().collect::<Vec<_>>(), vec![0, 1]);
+        let results = store.fts_query(1, 0, filters(), false).await.unwrap();
+        assert_eq!(results.iter().collect::<Vec<_>>(), vec![0]);
+    }
+
+    #[tokio::test]
+    async fn prefix_matches_addresses() {
+        let store = MockFtsStore::new();
+        for (document_id, from) in [
+            (0, "Alice Smith <alice@example.com>"),
+            (1, "alice.jones@example.org"),
+            (2, "Bob <bob@alice.example>"),
+            (3, "malice@example.com"),
+        ] {
+            let mut document = FtsDocument::<u8>::with_default_language(Language::English)
+                .with_account_id(1)
+                .with_document_id(document_id);
+            document.index_tokenized(Field::Header(1), from);
+            store.fts_index(document).await.unwrap();
+        }
+
+        let query = |filter: FtsFilter<u8>| {
+            let store = &store;
+            async move {
+                store
+                    .fts_query(1, 0, vec![filter], true)
+                    .await
+                    .unwrap()
+                    .iter()
+                    .collect::<Vec<_>>()
+            }
+        };
+        assert_eq!(
+            query(FtsFilter::has_prefix(Field::Header(1), "Alice")).await,
+            vec![0, 1]
+        );
+        assert_eq!(
+            query(FtsFilter::has_prefix(Field::Header(1), "alice.")).await,
+            vec![1]
+        );
+        assert_eq!(
+            query(FtsFilter::has_wildcard(Field::Header(1), "*@example.com")).await,
+            vec![0, 3]
+        );
+        assert_eq!(
+            query(FtsFilter::has_wildcard(Field::Header(1), "b?b@*")).await,
+            vec![2]
+        );
+        assert!(query(FtsFilter::has_prefix(Field::Body, "alice"))
+            .await
+            .is_empty());
+    }
+}
@@ -21,64 +21,416 @@
  * for more details.
 */
 
+use std::{
+    borrow::Cow,
+    fmt::Display,
+    future::Future,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use ahash::{AHashMap, AHashSet};
+use arc_swap::ArcSwap;
 use elasticsearch::{
-    auth::Credentials,
-    cert::CertificateValidation,
+    auth::{ClientCertificate, Credentials},
+    cert::{Certificate, CertificateValidation},
+    cluster::ClusterHealthParts,
     http::{
+        response::Response,
         transport::{BuildError, SingleNodeConnectionPool, Transport, TransportBuilder},
         StatusCode, Url,
     },
-    indices::{IndicesCreateParts, IndicesExistsParts},
+    params::Refresh,
     Elasticsearch, Error,
 };
-use serde_json::json;
+use nlp::language::Language;
+use parking_lot::Mutex;
+use rand::Rng;
+use serde_json::Value;
+use tokio::sync::Semaphore;
+use unicode_normalization::{char::is_combining_mark, UnicodeNormalization};
 use utils::config::{utils::AsKey, Config};
 
+use self::{
+    archive::SnapshotArchive,
+    breaker::{BreakerState, CircuitBreaker},
+    buffer::IndexBuffer,
+    cache::{QueryCache, WriteGuard},
+    cluster::Clusters,
+    compat::CompatibleRequest,
+    memory::MemoryIndex,
+    metrics::{Metrics, MetricsSnapshot, Operation},
+    pending::PendingQueue,
+    query::QueryOperator,
+};
+
+pub mod archive;
+pub mod backend;
+pub mod breaker;
+pub mod buffer;
+pub mod builder;
+pub mod cache;
+pub mod cluster;
+pub mod compat;
 pub mod index;
+pub mod manage;
+pub mod memory;
+pub mod metrics;
+#[cfg(feature = "test-util")]
+pub mod mock;
+pub mod multi;
+pub mod pending;
 pub mod query;
 
 pub struct ElasticSearchStore {
-    index: Elasticsearch,
+    index: ArcSwap<Elasticsearch>,
+    connection: Mutex<Connection>,
+    flavor: Flavor,
+    // Major version of the REST API requested from the cluster, the current one when unset
+    compatible_with: Option<u8>,
+    health: Mutex<HealthStatus>,
+    failures: AtomicU32,
+    reconnect_after: u32,
+    breaker: CircuitBreaker,
+    pending: PendingQueue,
+    buffer: Option<IndexBuffer>,
+    // Collections, in the same order as INDEX_NAMES, that are indexed
+    enabled: Vec<bool>,
+    // Shards and replicas are only applied when an index is created
+    shards: u32,
+    replicas: u32,
+    index_prefix: String,
+    max_retries: u32,
+    retry_wait: Duration,
+    // Requests sent to the cluster at once, callers above the limit wait for a slot
+    concurrency: Semaphore,
+    max_concurrency: usize,
+    request_timeout: Duration,
+    bulk_timeout: Duration,
+    skip_headers: AHashSet<String>,
+    max_header_values: Option<usize>,
+    address_headers: AHashSet<String>,
+    max_field_length: usize,
+    body_preprocessor: Option<BodyPreprocessor>,
+    data_stream: Option<DataStreamPolicy>,
+    recency: Option<RecencyBoost>,
+    // Storage settings are only applied when an index is created. The
+    // best_compression codec typically shrinks the stored source by 15-25% at a
+    // small indexing cost. Excluding the text from the source roughly halves the
+    // disk used by text heavy mail, but documents can then no longer be updated in
+    // place or copied by the reindex API.
+    best_compression: bool,
+    exclude_text_source: bool,
+    attachment_ingest: Option<AttachmentIngest>,
+    // Analysis settings are only applied when an index is created
+    stopwords: Vec<String>,
+    synonyms: Vec<String>,
+    // Diacritics are folded so "resume" matches "résumé"
+    fold_diacritics: bool,
+    // Terms of multi-term text conditions that have to match, any of them when unset
+    minimum_should_match: Option<String>,
+    // Whether all the terms of text conditions have to match or any of them
+    default_operator: QueryOperator,
+    // Documents are routed by account so searching an account only queries the
+    // shard holding it. Every document of an account lands on the same shard, so
+    // very large accounts make their shard grow much larger than the others, and
+    // shards cannot be rebalanced without reindexing. A partition size above one
+    // spreads each account over that many shards, trading some fan-out back for
+    // evenness. Changing either setting requires reindexing the collections.
+    account_routing: bool,
+    routing_partition_size: u32,
+    metrics: Metrics,
+    // Closed indices opened by `ensure_open` and when they were last searched
+    opened: Mutex<AHashMap<String, Instant>>,
+    // Remote clusters holding some of the accounts
+    clusters: Clusters,
+    // Repository collections are archived to as searchable snapshots
+    archive: Option<SnapshotArchive>,
+    // Results of recent searches, expired by writes to their account
+    cache: Option<QueryCache>,
+    // Documents that failed to be indexed, searched by substring while the cluster
+    // is unreachable and discarded once the pending operations are replayed. Only
+    // the most recent documents are kept.
+    fallback: Option<Mutex<MemoryIndex>>,
+}
+
+// Rotation and retention of the collections stored in data streams
+pub(crate) struct DataStreamPolicy {
+    pub rollover: String,
+    pub retention: String,
+}
+
+// Extracts the text of binary attachments on the cluster with the ingest attachment
+// processor, which runs Apache Tika on the ingest nodes. This moves the parsing of
+// untrusted files and its CPU and memory cost to the cluster, and the base64
+// encoded attachments make index requests about a third larger than the file.
+pub(crate) struct AttachmentIngest {
+    pub pipeline: String,
+    // Larger attachments are not sent to the pipeline
+    pub max_size: usize,
+}
+
+/// Rewrites the text of each body part before it is indexed, for example to strip
+/// quoted replies or signatures.
+pub type BodyPreprocessor = Arc<dyn Fn(&str) -> String + Send + Sync>;
+
+// Ranks recent messages higher, a message received now gets a boost of `weight`
+// that drops to half for messages received `scale` ago
+pub(crate) struct RecencyBoost {
+    pub scale: Duration,
+    pub weight: f64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RefreshPolicy {
+    #[default]
+    NoRefresh,
+    WaitFor,
+    Immediate,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Flavor {
+    #[default]
+    Elasticsearch,
+    OpenSearch,
+}
+
+// REST API compatibility requested through the Accept and Content-Type headers. The
+// client speaks the API of CLIENT_MAJOR_VERSION, newer clusters are asked to
+// follow it while older ones only understand plain JSON.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Compatibility {
+    Auto,
+    Disabled,
+    Version(u8),
+}
+
+// Major version of the ElasticSearch client
+const CLIENT_MAJOR_VERSION: u8 = 8;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HealthStatus {
+    #[default]
+    Unknown,
+    Green,
+    Yellow,
+    Red,
+    Unreachable,
+}
+
+#[derive(Clone)]
+enum Connection {
+    Url {
+        url: Url,
+        credentials: Option<Credentials>,
+        ca_cert: Option<Vec<u8>>,
+        allow_invalid_certs: bool,
+    },
+    Cloud {
+        cloud_id: String,
+        credentials: Credentials,
+    },
 }
 
 pub(crate) static INDEX_NAMES: &[&str] = &["stalwart_email"];
 
+// Collections, in the same order as INDEX_NAMES, that are only ever appended to and
+// can be stored in data streams when enabled. Emails are not eligible, their keywords
+// are updated in place and their documents are replaced when reindexed.
+pub(crate) static APPEND_ONLY: &[bool] = &[false];
+
+// Languages with a built-in ElasticSearch analyzer, indexed under "body_<code>"
+pub(crate) static LANGUAGE_ANALYZERS: &[(Language, &str, &str)] = &[
+    (Language::Arabic, "ar", "arabic"),
+    (Language::Armenian, "hy", "armenian"),
+    (Language::Bengali, "bn", "bengali"),
+    (Language::Bokmal, "nb", "norwegian"),
+    (Language::Bulgarian, "bg", "bulgarian"),
+    (Language::Catalan, "ca", "catalan"),
+    (Language::Czech, "cs", "czech"),
+    (Language::Danish, "da", "danish"),
+    (Language::Dutch, "nl", "dutch"),
+    (Language::English, "en", "english"),
+    (Language::Estonian, "et", "estonian"),
+    (Language::Finnish, "fi", "finnish"),
+    (Language::French, "fr", "french"),
+    (Language::German, "de", "german"),
+    (Language::Greek, "el", "greek"),
+    (Language::Hindi, "hi", "hindi"),
+    (Language::Hungarian, "hu", "hungarian"),
+    (Language::Indonesian, "id", "indonesian"),
+    (Language::Italian, "it", "italian"),
+    (Language::Japanese, "ja", "cjk"),
+    (Language::Korean, "ko", "cjk"),
+    (Language::Latvian, "lv", "latvian"),
+    (Language::Lithuanian, "lt", "lithuanian"),
+    (Language::Mandarin, "zh", "cjk"),
+    (Language::Persian, "fa", "persian"),
+    (Language::Portuguese, "pt", "portuguese"),
+    (Language::Romanian, "ro", "romanian"),
+    (Language::Russian, "ru", "russian"),
+    (Language::Spanish, "es", "spanish"),
+    (Language::Swedish, "sv", "swedish"),
+    (Language::Thai, "th", "thai"),
+    (Language::Turkish, "tr", "turkish"),
+];
+
+pub(crate) fn language_code(language: Language) -> Option<&'static str> {
+    LANGUAGE_ANALYZERS
+        .iter()
+        .find_map(|(lang, code, _)| (*lang == language).then_some(*code))
+}
+
+// Headers indexed under "header.address" with the email address analyzer
+pub(crate) static DEFAULT_ADDRESS_HEADERS: &[&str] = &["from", "to", "cc", "message-id"];
+
+// Removes diacritics like the asciifolding filter does for the letters used by
+// European languages, so query terms match the folded keywords
+pub(crate) fn fold_diacritics(text: &str) -> Cow<'_, str> {
+    if text.is_ascii() {
+        return Cow::Borrowed(text);
+    }
+
+    let mut folded = String::with_capacity(text.len());
+    for ch in text.nfd().filter(|ch| !is_combining_mark(*ch)) {
+        match ch {
+            'ß' => folded.push_str("ss"),
+            'æ' => folded.push_str("ae"),
+            'Æ' => folded.push_str("AE"),
+            'œ' => folded.push_str("oe"),
+            'Œ' => folded.push_str("OE"),
+            'ø' => folded.push('o'),
+            'Ø' => folded.push('O'),
+            'ł' => folded.push('l'),
+            'Ł' => folded.push('L'),
+            'đ' => folded.push('d'),
+            'Đ' => folded.push('D'),
+            'þ' => folded.push_str("th"),
+            'Þ' => folded.push_str("TH"),
+            ch => folded.push(ch),
+        }
+    }
+    Cow::Owned(folded)
+}
+
+// Lowercase bare envelope address, None for the null sender
+pub(crate) fn envelope_address(value: &str) -> Option<String> {
+    Some(bare_address(value).to_lowercase()).filter(|address| !address.is_empty())
+}
+
+// Accepts a number or percentage of terms, negative to count the terms allowed
+// to be missing, or space separated "count<value" conditions
+pub(crate) fn is_minimum_should_match(value: &str) -> bool {
+    let is_value = |value: &str| {
+        let value = value.strip_suffix('%').unwrap_or(value);
+        let value = value.strip_prefix('-').unwrap_or(value);
+        !value.is_empty() && value.bytes().all(|ch| ch.is_ascii_digit())
+    };
+    let parts = value.split_whitespace().collect::<Vec<_>>();
+    match parts.as_slice() {
+        [] => false,
+        [part] if !part.contains('<') => is_value(part),
+        parts => parts.iter().all(|part| {
+            part.split_once('<').is_some_and(|(count, value)| {
+                !count.is_empty() && count.bytes().all(|ch| ch.is_ascii_digit()) && is_value(value)
+            })
+        }),
+    }
+}
+
+// Reduces "Name <address>" and "group: address;" values to the bare address
+pub(crate) fn bare_address(value: &str) -> &str {
+    let value = value.trim();
+    if let (Some(start), Some(end)) = (value.find('<'), value.rfind('>')) {
+        if start < end {
+            return value[start + 1..end].trim();
+        }
+    }
+    value
+        .split_once(':')
+        .filter(|(group, _)| !group.contains('@'))
+        .map_or(value, |(_, address)| address)
+        .trim()
+        .trim_end_matches(';')
+        .trim()
+}
+
+// Reads a list of entries that can also be loaded from files with one entry per
+// line, empty lines and lines starting with '#' are ignored.
+fn config_lines(config: &mut Config, key: impl AsKey) -> Vec<String> {
+    config
+        .values(key)
+        .flat_map(|(_, value)| value.lines())
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| line.to_string())
+        .collect()
+}
+
 impl ElasticSearchStore {
     pub async fn open(config: &mut Config, prefix: impl AsKey) -> Option<Self> {
         let prefix = prefix.as_key();
-        let credentials = if let Some(user) = config.value((&prefix, "user")) {
+
+        // The transport accepts a single authentication method
+        let mut credentials = Vec::new();
+        if let Some(user) = config.value((&prefix, "user")) {
             let user = user.to_string();
             let password = config
                 .value_require((&prefix, "password"))
                 .unwrap_or_default();
-            Some(Credentials::Basic(user, password.to_string()))
-        } else {
-            None
-        };
+            credentials.push(Credentials::Basic(user, password.to_string()));
+        }
+        if let Some(id) = config.value((&prefix, "api-key.id")) {
+            let id = id.to_string();
+            let secret = config
+                .value_require((&prefix, "api-key.secret"))
+                .unwrap_or_default();
+            credentials.push(Credentials::ApiKey(id, secret.to_string()));
+        }
+        if let Some(cert) = config.value((&prefix, "tls.client-cert")) {
+            let mut pem = cert.as_bytes().to_vec();
+            let key = config
+                .value_require((&prefix, "tls.client-key"))
+                .unwrap_or_default();
+            pem.push(b'\n');
+            pem.extend_from_slice(key.as_bytes());
+            credentials.push(Credentials::Certificate(ClientCertificate::Pem(pem)));
+        }
+        if credentials.len() > 1 {
+            config.new_build_error(
+                prefix.as_str(),
+                "Only one of user, api-key or tls.client-cert can be configured",
+            );
+            return None;
+        }
+        let credentials = credentials.pop();
 
-        let es = if let Some(url) = config.value((&prefix, "url")) {
+        let connection = if let Some(url) = config.value((&prefix, "url")) {
             let url = Url::parse(url)
                 .map_err(|e| config.new_parse_error((&prefix, "url"), format!("Invalid URL: {e}",)))
                 .ok()?;
-            let conn_pool = SingleNodeConnectionPool::new(url);
-            let mut builder = TransportBuilder::new(conn_pool);
-            if let Some(credentials) = credentials {
-                builder = builder.auth(credentials);
-            }
-            if config
+            let allow_invalid_certs = config
                 .property_or_default::<bool>((&prefix, "tls.allow-invalid-certs"), "false")
-                .unwrap_or(false)
-            {
-                builder = builder.cert_validation(CertificateValidation::None);
+                .unwrap_or(false);
+            if allow_invalid_certs {
+                tracing::warn!(
+                    context = "elasticsearch",
+                    event = "insecure",
+                    "TLS certificate verification is disabled for ElasticSearch"
+                );
             }
 
-            Self {
-                index: Elasticsearch::new(
-                    builder
-                        .build()
-                        .map_err(|err| config.new_build_error(prefix.as_str(), err.to_string()))
-                        .ok()?,
-                ),
+            Connection::Url {
+                url,
+                credentials,
+                ca_cert: config
+                    .value((&prefix, "tls.ca-cert"))
+                    .map(|cert| cert.as_bytes().to_vec()),
+                allow_invalid_certs,
             }
         } else {
             let credentials = credentials.unwrap_or_else(|| {
@@ -87,12 +439,9 @@ impl ElasticSearchStore {
             });
 
             if let Some(cloud_id) = config.value((&prefix, "cloud-id")) {
-                Self {
-                    index: Elasticsearch::new(
-                        Transport::cloud(cloud_id, credentials)
-                            .map_err(|err| config.new_build_error(prefix.as_str(), err.to_string()))
-                            .ok()?,
-                    ),
+                Connection::Cloud {
+                    cloud_id: cloud_id.to_string(),
+                    credentials,
                 }
             } else {
                 config.new_parse_error(
@@ -102,108 +451,2610 @@ impl ElasticSearchStore {
                 return None;
             }
         };
+        let disabled = config
+            .values((&prefix, "index.disable"))
+            .map(|(_, name)| name.to_string())
+            .collect::<Vec<_>>();
+        let mut enabled = vec![true; INDEX_NAMES.len()];
+        for name in disabled {
+            // Collections can be named with or without the "stalwart_" prefix
+            match INDEX_NAMES
+                .iter()
+                .position(|index| *index == name || index.strip_prefix("stalwart_") == Some(&name))
+            {
+                Some(collection) => enabled[collection] = false,
+                None => {
+                    config.new_parse_error(
+                        (&prefix, "index.disable"),
+                        format!("Unknown collection {name:?}"),
+                    );
+                    return None;
+                }
+            }
+        }
+
+        let flavor = match config.value((&prefix, "flavor")) {
+            Some("elasticsearch") => Some(Flavor::Elasticsearch),
+            Some("opensearch") => Some(Flavor::OpenSearch),
+            Some(flavor) => {
+                config.new_parse_error(
+                    (&prefix, "flavor"),
+                    format!("Unknown flavor {flavor:?}, expected elasticsearch or opensearch"),
+                );
+                return None;
+            }
+            None => None,
+        };
+        let compatibility = match config.value((&prefix, "compatibility")) {
+            Some("auto") | None => Compatibility::Auto,
+            Some("none") => Compatibility::Disabled,
+            Some(version) => match version.parse::<u8>() {
+                Ok(version) if version > 0 => Compatibility::Version(version),
+                _ => {
+                    config.new_parse_error(
+                        (&prefix, "compatibility"),
+                        format!(
+                            "Unknown compatibility {version:?}, expected auto, none or a major version"
+                        ),
+                    );
+                    return None;
+                }
+            },
+        };
+        let default_operator = match config.value((&prefix, "query.default-operator")) {
+            Some("or") | None => QueryOperator::Or,
+            Some("and") => QueryOperator::And,
+            Some(operator) => {
+                config.new_parse_error(
+                    (&prefix, "query.default-operator"),
+                    format!("Unknown operator {operator:?}, expected and or or"),
+                );
+                return None;
+            }
+        };
+        let transport = connection
+            .build(flavor.unwrap_or_default())
+            .map_err(|err| config.new_build_error(prefix.as_str(), err.to_string()))
+            .ok()?;
+        let clusters = Clusters::parse(
+            config,
+            prefix.as_str(),
+            &connection,
+            flavor.unwrap_or_default(),
+        )?;
+        let max_concurrency = config
+            .property_or_default::<usize>((&prefix, "concurrency"), "64")
+            .unwrap_or(64)
+            .max(1);
 
-        if let Err(err) = es
-            .create_index(
+        let mut es = Self {
+            index: ArcSwap::from_pointee(Elasticsearch::new(transport)),
+            connection: Mutex::new(connection),
+            flavor: flavor.unwrap_or_default(),
+            compatible_with: match compatibility {
+                Compatibility::Version(version) => Some(version),
+                Compatibility::Auto | Compatibility::Disabled => None,
+            },
+            health: Mutex::new(HealthStatus::Unknown),
+            failures: AtomicU32::new(0),
+            pending: PendingQueue::new(
                 config
-                    .property_or_default((&prefix, "index.shards"), "3")
-                    .unwrap_or(3),
+                    .property_or_default((&prefix, "pending.max-operations"), "10000")
+                    .unwrap_or(10000),
+            ),
+            enabled,
+            buffer: config
+                .property_or_default::<bool>((&prefix, "buffer.enable"), "false")
+                .unwrap_or(false)
+                .then(|| {
+                    IndexBuffer::new(
+                        config
+                            .property_or_default((&prefix, "buffer.batch-size"), "500")
+                            .unwrap_or(500),
+                        config
+                            .property_or_default::<Duration>((&prefix, "buffer.max-wait"), "1s")
+                            .unwrap_or(Duration::from_secs(1)),
+                        config
+                            .property_or_default::<Duration>((&prefix, "buffer.jitter"), "100ms")
+                            .unwrap_or(Duration::from_millis(100)),
+                        config
+                            .property_or_default((&prefix, "buffer.capacity"), "5000")
+                            .unwrap_or(5000),
+                    )
+                }),
+            breaker: CircuitBreaker::new(
                 config
-                    .property_or_default((&prefix, "index.replicas"), "0")
-                    .unwrap_or(0),
-            )
-            .await
-        {
+                    .property_or_default((&prefix, "circuit-breaker.failures"), "10")
+                    .unwrap_or(10),
+                config
+                    .property_or_default::<Duration>((&prefix, "circuit-breaker.cooldown"), "30s")
+                    .unwrap_or(Duration::from_secs(30)),
+            ),
+            reconnect_after: config
+                .property_or_default((&prefix, "reconnect.after"), "3")
+                .unwrap_or(3),
+            index_prefix: config
+                .value((&prefix, "index.prefix"))
+                .unwrap_or_default()
+                .to_string(),
+            max_retries: config
+                .property_or_default((&prefix, "retry.total"), "3")
+                .unwrap_or(3),
+            retry_wait: config
+                .property_or_default::<Duration>((&prefix, "retry.min-wait"), "100ms")
+                .unwrap_or(Duration::from_millis(100)),
+            concurrency: Semaphore::new(max_concurrency),
+            max_concurrency,
+            request_timeout: config
+                .property_or_default::<Duration>((&prefix, "timeout.request"), "30s")
+                .unwrap_or(Duration::from_secs(30)),
+            bulk_timeout: config
+                .property_or_default::<Duration>((&prefix, "timeout.bulk"), "5m")
+                .unwrap_or(Duration::from_secs(300)),
+            shards: config
+                .property_or_default((&prefix, "index.shards"), "3")
+                .unwrap_or(3),
+            replicas: config
+                .property_or_default((&prefix, "index.replicas"), "0")
+                .unwrap_or(0),
+            skip_headers: config
+                .values((&prefix, "index.headers.skip"))
+                .map(|(_, name)| name.to_ascii_lowercase())
+                .collect(),
+            max_header_values: config
+                .property::<usize>((&prefix, "index.headers.max-values"))
+                .map(|max_values| max_values.max(1)),
+            address_headers: {
+                let headers = config
+                    .values((&prefix, "index.headers.address"))
+                    .map(|(_, name)| name.to_ascii_lowercase())
+                    .collect::<AHashSet<_>>();
+                if headers.is_empty() {
+                    DEFAULT_ADDRESS_HEADERS
+                        .iter()
+                        .map(|name| name.to_string())
+                        .collect()
+                } else {
+                    headers
+                }
+            },
+            max_field_length: config
+                .property_or_default((&prefix, "index.max-field-length"), "1048576")
+                .unwrap_or(1048576),
+            best_compression: config
+                .property_or_default((&prefix, "index.best-compression"), "false")
+                .unwrap_or(false),
+            exclude_text_source: config
+                .property_or_default((&prefix, "index.source.exclude-text"), "false")
+                .unwrap_or(false),
+            attachment_ingest: config
+                .property_or_default::<bool>((&prefix, "index.attachments.ingest.enable"), "false")
+                .unwrap_or(false)
+                .then(|| AttachmentIngest {
+                    pipeline: config
+                        .value((&prefix, "index.attachments.ingest.pipeline"))
+                        .map(|pipeline| pipeline.to_string())
+                        .unwrap_or_else(|| {
+                            format!(
+                                "{}stalwart_attachments",
+                                config.value((&prefix, "index.prefix")).unwrap_or_default()
+                            )
+                        }),
+                    max_size: config
+                        .property_or_default(
+                            (&prefix, "index.attachments.ingest.max-size"),
+                            "10485760",
+                        )
+                        .unwrap_or(10485760),
+                }),
+            data_stream: config
+                .property_or_default::<bool>((&prefix, "index.data-stream.enable"), "false")
+                .unwrap_or(false)
+                .then(|| DataStreamPolicy {
+                    rollover: config
+                        .value((&prefix, "index.data-stream.rollover"))
+                        .unwrap_or("30d")
+                        .to_string(),
+                    retention: config
+                        .value((&prefix, "index.data-stream.retention"))
+                        .unwrap_or("365d")
+                        .to_string(),
+                }),
+            recency: config
+                .property_or_default::<bool>((&prefix, "query.recency.enable"), "false")
+                .unwrap_or(false)
+                .then(|| RecencyBoost {
+                    scale: config
+                        .property_or_default((&prefix, "query.recency.scale"), "30d")
+                        .unwrap_or(Duration::from_secs(30 * 86400)),
+                    weight: config
+                        .property_or_default((&prefix, "query.recency.weight"), "1.0")
+                        .unwrap_or(1.0),
+                }),
+            stopwords: config_lines(config, (&prefix, "index.analysis.stopwords")),
+            synonyms: config_lines(config, (&prefix, "index.analysis.synonyms")),
+            fold_diacritics: config
+                .property_or_default((&prefix, "index.analysis.fold-diacritics"), "true")
+                .unwrap_or(true),
+            minimum_should_match: config
+                .value((&prefix, "query.minimum-should-match"))
+                .map(|value| value.trim().to_string())
+                .filter(|value| {
+                    is_minimum_should_match(value) || {
+                        config.new_parse_error(
+                            (&prefix, "query.minimum-should-match"),
+                            format!("Invalid minimum_should_match value {value:?}"),
+                        );
+                        false
+                    }
+                }),
+            default_operator,
+            account_routing: config
+                .property_or_default((&prefix, "index.routing.enable"), "false")
+                .unwrap_or(false),
+            routing_partition_size: config
+                .property_or_default::<u32>((&prefix, "index.routing.partition-size"), "1")
+                .unwrap_or(1)
+                .max(1),
+            body_preprocessor: None,
+            metrics: Metrics::default(),
+            opened: Mutex::new(AHashMap::new()),
+            clusters,
+            archive: SnapshotArchive::parse(config, prefix.as_str()),
+            cache: config
+                .property::<usize>((&prefix, "query.cache.size"))
+                .filter(|size| *size > 0)
+                .map(|size| {
+                    QueryCache::new(
+                        size,
+                        config
+                            .property_or_default::<Duration>((&prefix, "query.cache.ttl"), "10s")
+                            .unwrap_or(Duration::from_secs(10)),
+                    )
+                }),
+            fallback: config
+                .property_or_default::<bool>((&prefix, "fallback.enable"), "false")
+                .unwrap_or(false)
+                .then(|| {
+                    Mutex::new(MemoryIndex::new(
+                        config
+                            .property_or_default((&prefix, "fallback.max-documents"), "1000")
+                            .unwrap_or(1000),
+                    ))
+                }),
+        };
+
+        if es.exclude_text_source {
+            tracing::warn!(
+                context = "elasticsearch",
+                event = "config",
+                "Text is excluded from the document source, keyword and mailbox updates \
+                 and reindexing require indexing the documents again"
+            );
+        }
+        let detect_version = compatibility == Compatibility::Auto;
+        if flavor.is_none() || detect_version {
+            match es.detect_cluster(flavor.is_none(), detect_version).await {
+                Ok(()) => {}
+                Err(err) if flavor.is_none() => {
+                    config.new_build_error(prefix.as_str(), err.to_string());
+                }
+                // Without the version the current API is requested
+                Err(err) => {
+                    tracing::warn!(
+                        context = "elasticsearch",
+                        event = "compatibility",
+                        reason = %err,
+                        "Failed to detect the cluster version"
+                    );
+                }
+            }
+        }
+        if let Err(err) = es.init_indices().await {
             config.new_build_error(prefix.as_str(), err.to_string());
         }
 
         Some(es)
     }
 
-    async fn create_index(&self, shards: usize, replicas: usize) -> crate::Result<()> {
-        let exists = self
-            .index
-            .indices()
-            .exists(IndicesExistsParts::Index(&[INDEX_NAMES[0]]))
+    pub async fn ping(&self) -> HealthStatus {
+        let client = self.client();
+        let cluster = client.cluster();
+        let status = match self
+            .send_with_retry(Operation::Manage, || {
+                cluster
+                    .health(ClusterHealthParts::None)
+                    .compatible_with(self.compatible_with)
+                    .send()
+            })
+            .await
+        {
+            Ok(response) if response.status_code().is_success() => {
+                match response.json::<Value>().await {
+                    Ok(json) => match json["status"].as_str() {
+                        Some("green") => HealthStatus::Green,
+                        Some("yellow") => HealthStatus::Yellow,
+                        Some("red") => HealthStatus::Red,
+                        _ => HealthStatus::Unknown,
+                    },
+                    Err(_) => HealthStatus::Unknown,
+                }
+            }
+            Ok(_) => HealthStatus::Unknown,
+            Err(_) => HealthStatus::Unreachable,
+        };
+
+        *self.health.lock() = status;
+
+        // Operations that failed while the cluster was unreachable are replayed
+        // once it answers again
+        if status != HealthStatus::Unreachable && !self.pending.is_empty() {
+            if let Err(err) = self.drain_pending().await {
+                tracing::debug!(
+                    context = "elasticsearch",
+                    event = "error",
+                    reason = %err,
+                    "Failed to replay pending operations"
+                );
+            }
+        }
+
+        status
+    }
+
+    pub fn health(&self) -> HealthStatus {
+        *self.health.lock()
+    }
+
+    /// Returns false while requests are suspended because the cluster is unreachable.
+    pub fn is_available(&self) -> bool {
+        self.breaker.state() != BreakerState::Open
+    }
+
+    // A single health request decides whether the breaker closes again
+    async fn probe(&self) -> bool {
+        let client = self.client();
+        let result = client
+            .cluster()
+            .health(ClusterHealthParts::None)
+            .request_timeout(self.request_timeout)
+            .compatible_with(self.compatible_with)
             .send()
+            .await;
+        if result.is_ok_and(|response| response.status_code().is_success()) {
+            self.breaker.success();
+            true
+        } else {
+            self.breaker.failure();
+            false
+        }
+    }
+
+    /// Returns the underlying client for requests not covered by the store. The
+    /// client is replaced when the connection is rebuilt, so it should not be kept.
+    /// Raw access bypasses retries, metrics and the circuit breaker, and its
+    /// stability is not guaranteed across releases.
+    pub fn client(&self) -> Arc<Elasticsearch> {
+        self.index.load_full()
+    }
+
+    pub fn flavor(&self) -> Flavor {
+        self.flavor
+    }
+
+    // Reads the distribution from the cluster info, OpenSearch clusters
+    // report "opensearch" while ElasticSearch omits it. ElasticSearch clusters
+    // newer than the client are asked for the API of the client.
+    async fn detect_cluster(
+        &mut self,
+        detect_flavor: bool,
+        detect_version: bool,
+    ) -> crate::Result<()> {
+        let response = self.client().info().send().await?;
+        let json: Value = assert_success(response, "Failed to obtain cluster info")
+            .await?
+            .json()
             .await?;
+        if detect_flavor && json["version"]["distribution"].as_str() == Some("opensearch") {
+            let transport = self.connection.lock().build(Flavor::OpenSearch)?;
+            self.index.store(Arc::new(Elasticsearch::new(transport)));
+            self.clusters.rebuild(Flavor::OpenSearch)?;
+            self.flavor = Flavor::OpenSearch;
+        }
+        tracing::debug!(
+            context = "elasticsearch",
+            event = "flavor",
+            flavor = ?self.flavor,
+            "Detected search cluster flavor"
+        );
+        if detect_version && self.flavor == Flavor::Elasticsearch {
+            self.compatible_with = json["version"]["number"]
+                .as_str()
+                .and_then(|version| version.split('.').next()?.parse::<u8>().ok())
+                .filter(|major| *major > CLIENT_MAJOR_VERSION)
+                .map(|_| CLIENT_MAJOR_VERSION);
+            tracing::debug!(
+                context = "elasticsearch",
+                event = "compatibility",
+                compatible_with = ?self.compatible_with,
+                "Detected REST API compatibility"
+            );
+        }
 
-        if exists.status_code() == StatusCode::NOT_FOUND {
-            let response = self
-                .index
-                .indices()
-                .create(IndicesCreateParts::Index(INDEX_NAMES[0]))
-                .body(json!({
-                  "mappings": {
-                    "properties": {
-                      "document_id": {
-                        "type": "integer"
-                      },
-                      "account_id": {
-                        "type": "integer"
-                      },
-                      "header": {
-                        "type": "object",
-                        "properties": {
-                          "name": {
-                            "type": "keyword"
-                          },
-                          "value": {
-                            "type": "text",
-                            "analyzer": "default_analyzer",
-                          }
-                        }
-                      },
-                      "body": {
-                        "analyzer": "default_analyzer",
-                        "type": "text"
-                      },
-                      "attachment": {
-                        "analyzer": "default_analyzer",
-                        "type": "text"
-                      },
-                      "keyword": {
-                        "type": "keyword"
-                      }
-                    }
-                  },
-                  "settings": {
-                    "index.number_of_shards": shards,
-                    "index.number_of_replicas": replicas,
-                    "analysis": {
-                      "analyzer": {
-                        "default_analyzer": {
-                          "type": "custom",
-                          "tokenizer": "standard",
-                          "filter": ["lowercase"]
+        Ok(())
+    }
+
+    /// Replaces the credentials used to authenticate, for example after an API key
+    /// was rotated, on the local and the remote clusters. Requests already in flight
+    /// complete on the previous client.
+    pub fn update_credentials(&self, credentials: Credentials) -> crate::Result<()> {
+        let mut connection = self.connection.lock();
+        let mut updated = connection.clone();
+        match &mut updated {
+            Connection::Url {
+                credentials: current,
+                ..
+            } => *current = Some(credentials.clone()),
+            Connection::Cloud {
+                credentials: current,
+                ..
+            } => *current = credentials.clone(),
+        }
+
+        // The previous connections are kept if the new ones cannot be built
+        let transport = updated.build(self.flavor)?;
+        self.clusters
+            .update_credentials(&credentials, self.flavor)?;
+        self.index.store(Arc::new(Elasticsearch::new(transport)));
+        *connection = updated;
+
+        tracing::info!(
+            context = "elasticsearch",
+            event = "credentials",
+            "Updated ElasticSearch credentials"
+        );
+
+        Ok(())
+    }
+
+    fn connection_failed(&self) {
+        *self.health.lock() = HealthStatus::Unreachable;
+
+        // Rebuild the client after repeated failures, the node may have been restarted
+        if self.failures.fetch_add(1, Ordering::Relaxed) + 1 >= self.reconnect_after {
+            self.failures.store(0, Ordering::Relaxed);
+            match self.connection.lock().build(self.flavor) {
+                Ok(transport) => {
+                    tracing::info!(
+                        context = "elasticsearch",
+                        event = "reconnect",
+                        "Rebuilding ElasticSearch client after repeated connection failures"
+                    );
+                    self.index.store(Arc::new(Elasticsearch::new(transport)));
+                }
+                Err(err) => {
+                    tracing::warn!(
+                        context = "elasticsearch",
+                        event = "error",
+                        reason = %err,
+                        "Failed to rebuild ElasticSearch client"
+                    );
+                }
+            }
+        }
+    }
+
+    /// Returns the index, alias or data stream name of a collection, including
+    /// the configured prefix. Panics if the collection does not map to an index.
+    pub fn index_name(&self, collection: u8) -> String {
+        format!("{}{}", self.index_prefix, INDEX_NAMES[collection as usize])
+    }
+
+    /// Sets the function applied to the text of every body part before it is
+    /// indexed, body text is indexed as is by default. It runs before parts left
+    /// empty are skipped and before the text is truncated to the field length
+    /// limit, so the limit applies to the preprocessed text. Headers, attachments
+    /// and keywords are not preprocessed.
+    pub fn with_body_preprocessor(
+        mut self,
+        preprocessor: impl Fn(&str) -> String + Send + Sync + 'static,
+    ) -> Self {
+        self.body_preprocessor = Some(Arc::new(preprocessor));
+        self
+    }
+
+    pub fn is_enabled(&self, collection: u8) -> bool {
+        self.enabled
+            .get(collection as usize)
+            .copied()
+            .unwrap_or(false)
+    }
+
+    // Index to search for a collection, searching a collection that is not
+    // indexed is an error rather than an empty result.
+    pub(crate) fn search_index(&self, collection: u8) -> crate::Result<String> {
+        if self.is_enabled(collection) {
+            Ok(self.index_name(collection))
+        } else {
+            Err(crate::Error::InternalError(format!(
+                "Full-text indexing is disabled for collection {collection}, remove it from index.disable to search it"
+            )))
+        }
+    }
+
+    pub(crate) fn is_data_stream(&self, collection: u8) -> bool {
+        self.data_stream.is_some()
+            && APPEND_ONLY
+                .get(collection as usize)
+                .copied()
+                .unwrap_or(false)
+    }
+
+    // Routing of the documents of the accounts in the collections, documents in data
+    // streams are never routed
+    pub(crate) fn routing(
+        &self,
+        collections: impl IntoIterator<Item = u8>,
+        account_ids: &[u32],
+    ) -> Option<String> {
+        let mut collections = collections.into_iter();
+        (self.account_routing && !collections.any(|collection| self.is_data_stream(collection)))
+            .then(|| {
+                account_ids
+                    .iter()
+                    .map(|account_id| account_id.to_string())
+                    .collect::<Vec<_>>()
+                    .join(",")
+            })
+    }
+
+    pub(crate) fn index_names(&self) -> Vec<String> {
+        INDEX_NAMES
+            .iter()
+            .map(|name| format!("{}{name}", self.index_prefix))
+            .collect()
+    }
+
+    pub fn metrics(&self) -> MetricsSnapshot {
+        let mut snapshot = self.metrics.snapshot();
+        snapshot.pending_operations = self.pending.len() as u64;
+        snapshot.buffered_documents = self.buffer.as_ref().map_or(0, |buffer| buffer.len()) as u64;
+        snapshot.in_flight_requests =
+            (self.max_concurrency - self.concurrency.available_permits()) as u64;
+        if let Some(cache) = &self.cache {
+            snapshot.cache_hits = cache.hits();
+            snapshot.cache_misses = cache.misses();
+        }
+        snapshot.fallback_documents =
+            self.fallback
+                .as_ref()
+                .map_or(0, |fallback| fallback.lock().len()) as u64;
+        snapshot
+    }
+
+    // Expires the cached results of the accounts now and once the write is done
+    pub(crate) fn cache_writes(
+        &self,
+        account_ids: impl IntoIterator<Item = u32>,
+    ) -> Option<WriteGuard<'_>> {
+        self.cache
+            .as_ref()
+            .map(|cache| cache.writing(account_ids.into_iter().collect()))
+    }
+
+    pub(crate) async fn send_with_retry<F, R>(
+        &self,
+        operation: Operation,
+        request: F,
+    ) -> crate::Result<Response>
+    where
+        F: Fn() -> R,
+        R: Future<Output = Result<Response, Error>>,
+    {
+        // Health checks always reach the cluster so the breaker can recover. Indexing
+        // failures are not lost, queued documents are retried by the indexer.
+        if operation != Operation::Manage {
+            match self.breaker.state() {
+                BreakerState::Closed => {}
+                BreakerState::HalfOpen if self.breaker.try_probe() && self.probe().await => {}
+                _ => {
+                    return Err(crate::Error::InternalError(
+                        match operation {
+                            Operation::Search => "Search unavailable: ElasticSearch is unreachable",
+                            _ => "Full-text index unavailable: ElasticSearch is unreachable",
                         }
-                      }
-                    }
-                  }
-                }))
-                .send()
-                .await?;
+                        .to_string(),
+                    ))
+                }
+            }
+        }
+
+        let mut retry_count = 0;
+
+        loop {
+            // The slot is held until the response headers arrive and released during
+            // backoffs, so a burst of callers queues here instead of on the cluster
+            let permit = self.concurrency.acquire().await;
+            let time = Instant::now();
+            let result = request().await;
+            drop(permit);
+            let error = match &result {
+                Ok(response) if response.status_code().is_success() => None,
+                Ok(response) => Some(Cow::Owned(format!(
+                    "http_{}",
+                    response.status_code().as_u16()
+                ))),
+                Err(err) if err.is_timeout() => Some(Cow::Borrowed("timeout")),
+                Err(err) if err.status_code().is_none() && !err.is_json() => {
+                    Some(Cow::Borrowed("transport"))
+                }
+                Err(_) => Some(Cow::Borrowed("client")),
+            };
+            self.metrics
+                .record(operation, time.elapsed(), error.as_deref());
+
+            // Errors without a status code are transport failures (connection reset, timeout)
+            let is_transport_error =
+                matches!(&result, Err(err) if err.status_code().is_none() && !err.is_json());
+            let is_timeout = matches!(&result, Err(err) if err.is_timeout());
+            // Proxies in front of a cluster that is down, and clusters without an
+            // elected master, still answer but cannot serve requests
+            let is_unavailable = matches!(
+                &result,
+                Ok(response) if matches!(
+                    response.status_code(),
+                    StatusCode::BAD_GATEWAY
+                        | StatusCode::SERVICE_UNAVAILABLE
+                        | StatusCode::GATEWAY_TIMEOUT
+                )
+            );
+            if is_transport_error {
+                self.connection_failed();
+                self.breaker.failure();
+            } else if is_unavailable {
+                self.breaker.failure();
+            } else {
+                self.failures.store(0, Ordering::Relaxed);
+                self.breaker.success();
+            }
+
+            match result {
+                Ok(response)
+                    if retry_count < self.max_retries
+                        && matches!(
+                            response.status_code(),
+                            StatusCode::TOO_MANY_REQUESTS
+                                | StatusCode::BAD_GATEWAY
+                                | StatusCode::SERVICE_UNAVAILABLE
+                                | StatusCode::GATEWAY_TIMEOUT
+                        ) => {}
+                // Timed out requests are not retried, the node is likely overloaded
+                Err(_) if is_timeout => return Err(crate::Error::Timeout),
+                Err(_) if retry_count < self.max_retries && is_transport_error => {}
+                result => return result.map_err(Into::into),
+            }
 
-            if !response.status_code().is_success() {
-                return Err(crate::Error::InternalError(format!(
-                    "Error while creating ElasticSearch index: {:?}",
-                    response
-                )));
+            let backoff = self.retry_wait.as_millis() as u64 * (1 << retry_count.min(10));
+            let jitter = rand::thread_rng().gen_range(0..=backoff / 2);
+            tokio::time::sleep(Duration::from_millis(backoff + jitter)).await;
+            retry_count += 1;
+        }
+    }
+}
+
+impl Connection {
+    fn build(&self, flavor: Flavor) -> crate::Result<Transport> {
+        match self {
+            Connection::Url {
+                url,
+                credentials,
+                ca_cert,
+                allow_invalid_certs,
+            } => {
+                // OpenSearch rejects requests carrying ElasticSearch client metadata
+                let mut builder = TransportBuilder::new(SingleNodeConnectionPool::new(url.clone()))
+                    .enable_meta_header(flavor == Flavor::Elasticsearch);
+                if let Some(credentials) = credentials {
+                    builder = builder.auth(credentials.clone());
+                }
+                if *allow_invalid_certs {
+                    builder = builder.cert_validation(CertificateValidation::None);
+                } else if let Some(ca_cert) = ca_cert {
+                    builder = builder.cert_validation(CertificateValidation::Full(
+                        Certificate::from_pem(ca_cert)?,
+                    ));
+                }
+                builder.build().map_err(Into::into)
             }
+            Connection::Cloud {
+                cloud_id,
+                credentials,
+            } => Transport::cloud(cloud_id, credentials.clone()).map_err(Into::into),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ElasticError {
+    pub status: u16,
+    pub error_type: Option<String>,
+    pub reason: Option<String>,
+}
+
+impl ElasticError {
+    pub(crate) async fn from_response(response: Response) -> Self {
+        let status = response.status_code().as_u16();
+        let json = response.json::<Value>().await.unwrap_or_default();
+
+        ElasticError::from_value(status, &json["error"])
+    }
+
+    /// Parses the error object of a response or of a bulk item, the reason is
+    /// redacted so no document content ends up in logs.
+    pub(crate) fn from_value(status: u16, error: &Value) -> Self {
+        ElasticError {
+            status,
+            error_type: error["type"].as_str().map(|t| t.to_string()),
+            reason: error["reason"].as_str().map(redact_reason),
         }
+    }
 
+    pub fn is_index_not_found(&self) -> bool {
+        self.error_type.as_deref() == Some("index_not_found_exception")
+    }
+
+    pub fn is_version_conflict(&self) -> bool {
+        self.error_type.as_deref() == Some("version_conflict_engine_exception")
+    }
+}
+
+impl Display for ElasticError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "status {}", self.status)?;
+        if let Some(error_type) = &self.error_type {
+            write!(f, ", {error_type}")?;
+        }
+        if let Some(reason) = &self.reason {
+            write!(f, ": {reason}")?;
+        }
         Ok(())
     }
 }
 
+// Mapping errors quote the start of the rejected value, which can be message text
+fn redact_reason(reason: &str) -> String {
+    match reason.find("Preview of field's value") {
+        Some(pos) => format!("{}[value redacted]", &reason[..pos]),
+        None => reason.to_string(),
+    }
+}
+
+// Message ids are stored and matched without their angle brackets
+pub(crate) fn message_id(value: &str) -> &str {
+    value
+        .trim()
+        .trim_start_matches('<')
+        .trim_end_matches('>')
+        .trim()
+}
+
+// Time left before the caller's deadline, capped by the configured timeout.
+// Requests are not sent once the deadline has passed as they could not complete.
+pub(crate) fn time_left(deadline: Option<Instant>, timeout: Duration) -> crate::Result<Duration> {
+    match deadline.map(|deadline| deadline.saturating_duration_since(Instant::now())) {
+        Some(remaining) if remaining.is_zero() => Err(crate::Error::Timeout),
+        Some(remaining) => Ok(remaining.min(timeout)),
+        None => Ok(timeout),
+    }
+}
+
+// Documents are indexed under a fixed id so reindexing replaces them
+pub(crate) fn document_key(account_id: u32, document_id: u32) -> String {
+    format!("{account_id}:{document_id}")
+}
+
+pub(crate) async fn assert_success(response: Response, context: &str) -> crate::Result<Response> {
+    if response.status_code().is_success() {
+        Ok(response)
+    } else {
+        Err(crate::Error::InternalError(format!(
+            "{context}: {}",
+            ElasticError::from_response(response).await
+        )))
+    }
+}
+
+// Removing documents from an index that was never created is a no-op
+pub(crate) async fn assert_removed(
+    response: Response,
+    context: &str,
+) -> crate::Result<Option<Response>> {
+    if response.status_code().is_success() {
+        Ok(Some(response))
+    } else {
+        let error = ElasticError::from_response(response).await;
+        if error.status == 404 || error.is_index_not_found() {
+            Ok(None)
+        } else {
+            Err(crate::Error::InternalError(format!("{context}: {error}")))
+        }
+    }
+}
+
+impl From<RefreshPolicy> for Refresh {
+    fn from(value: RefreshPolicy) -> Self {
+        match value {
+            RefreshPolicy::NoRefresh => Refresh::False,
+            RefreshPolicy::WaitFor => Refresh::WaitFor,
+            RefreshPolicy::Immediate => Refresh::True,
+        }
+    }
+}
+
 impl From<Error> for crate::Error {
     fn from(value: Error) -> Self {
         crate::Error::InternalError(format!("ElasticSearch error: {}", value))
     }
 }
 
+impl From<serde_json::Error> for crate::Error {
+    fn from(value: serde_json::Error) -> Self {
+        crate::Error::InternalError(format!("ElasticSearch serialization error: {}", value))
+    }
+}
+
 impl From<BuildError> for crate::Error {
     fn from(value: BuildError) -> Self {
         crate::Error::InternalError(format!("ElasticSearch build error: {}", value))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+
+    use elasticsearch::auth::Credentials;
+    use nlp::language::Language;
+    use parking_lot::Mutex;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+    use utils::config::Config;
+
+    use crate::fts::{index::FtsDocument, Field, FtsFilter};
+
+    use super::{
+        query::{QueryOperator, QueryOptions, SortField},
+        ElasticSearchStore, Flavor, RefreshPolicy,
+    };
+
+    // Replies to every request with the same body and records the request headers,
+    // requests starting with `stall` are never answered.
+    async fn fake_cluster(
+        body: &'static str,
+        stall: Option<&'static str>,
+    ) -> (u16, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let recorded = requests.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let recorded = recorded.clone();
+                tokio::spawn(async move {
+                    let mut request = Vec::new();
+                    let mut buf = [0u8; 1024];
+                    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                        match stream.read(&mut buf).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(n) => request.extend_from_slice(&buf[..n]),
+                        }
+                    }
+                    let body_read = request.len()
+                        - request.windows(4).position(|w| w == b"\r\n\r\n").unwrap()
+                        - 4;
+                    let request = String::from_utf8_lossy(&request).to_string();
+                    // Large bodies are read in full, closing the connection with unread
+                    // data would reset it before the response arrives
+                    let mut body_left = header(&request, "content-length")
+                        .and_then(|len| len.parse::<usize>().ok())
+                        .unwrap_or(0)
+                        .saturating_sub(body_read);
+                    while body_left > 0 {
+                        match stream.read(&mut buf).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(n) => body_left = body_left.saturating_sub(n),
+                        }
+                    }
+                    if stall.is_some_and(|stall| request.starts_with(stall)) {
+                        std::future::pending::<()>().await;
+                    }
+                    recorded.lock().push(request);
+                    let response = format!(
+                        concat!(
+                            "HTTP/1.1 200 OK\r\n",
+                            "content-type: application/json\r\n",
+                            "content-length: {}\r\n",
+                            "connection: close\r\n\r\n{}"
+                        ),
+                        body.len(),
+                        body
+                    );
+                    let _ = stream.write_all(response.as_bytes()).await;
+                });
+            }
+        });
+
+        (port, requests)
+    }
+
+    // Store settings pointing at a fake cluster, without retries so failures are
+    // reported at once
+    fn store_config(port: u16, extra_config: &str) -> Config {
+        Config::new(format!(
+            concat!(
+                "[store.\"elastic\"]\n",
+                "url = \"http://127.0.0.1:{}\"\n",
+                "retry.total = 0\n",
+                "{}",
+            ),
+            port, extra_config
+        ))
+        .unwrap()
+    }
+
+    pub(super) async fn open_store(
+        body: &'static str,
+        stall: Option<&'static str>,
+        extra_config: &str,
+    ) -> (ElasticSearchStore, Arc<Mutex<Vec<String>>>) {
+        let (port, requests) = fake_cluster(body, stall).await;
+        let store =
+            ElasticSearchStore::open(&mut store_config(port, extra_config), ("store", "elastic"))
+                .await
+                .unwrap();
+        (store, requests)
+    }
+
+    fn header(request: &str, name: &str) -> Option<String> {
+        request.lines().find_map(|line| {
+            line.split_once(": ")
+                .filter(|(key, _)| key.eq_ignore_ascii_case(name))
+                .map(|(_, value)| value.to_string())
+        })
+    }
+
+    #[tokio::test]
+    async fn updated_credentials_are_used() {
+        let (port, requests) = fake_cluster("{}", None).await;
+        let (remote_port, remote_requests) = fake_cluster("{}", None).await;
+        let mut config = store_config(
+            port,
+            &format!(
+                concat!(
+                    "api-key.id = \"old\"\n",
+                    "api-key.secret = \"key\"\n",
+                    "cluster.eu.url = \"http://127.0.0.1:{}\"\n",
+                    "cluster.eu.accounts = [\"100-199\"]\n",
+                ),
+                remote_port
+            ),
+        );
+        let store = ElasticSearchStore::open(&mut config, ("store", "elastic"))
+            .await
+            .unwrap();
+        let last_key = |requests: &Arc<Mutex<Vec<String>>>| {
+            requests
+                .lock()
+                .last()
+                .and_then(|request| header(request, "authorization"))
+        };
+        let ping_all = || async {
+            store.client().ping().send().await.unwrap();
+            store.client_for(150).ping().send().await.unwrap();
+        };
+        ping_all().await;
+        assert_eq!(last_key(&requests).as_deref(), Some("ApiKey b2xkOmtleQ=="));
+        assert_eq!(
+            last_key(&remote_requests).as_deref(),
+            Some("ApiKey b2xkOmtleQ==")
+        );
+
+        store
+            .update_credentials(Credentials::ApiKey("new".to_string(), "key".to_string()))
+            .unwrap();
+        ping_all().await;
+        assert_eq!(last_key(&requests).as_deref(), Some("ApiKey bmV3OmtleQ=="));
+        assert_eq!(
+            last_key(&remote_requests).as_deref(),
+            Some("ApiKey bmV3OmtleQ==")
+        );
+    }
+
+    #[tokio::test]
+    async fn opensearch_is_detected() {
+        let (store, requests) = open_store(
+            r#"{"version":{"distribution":"opensearch","number":"2.11.0"}}"#,
+            None,
+            "",
+        )
+        .await;
+        assert_eq!(store.flavor(), Flavor::OpenSearch);
+
+        requests.lock().clear();
+        store.client().ping().send().await.unwrap();
+        let request = requests.lock().pop().unwrap();
+        assert_eq!(header(&request, "x-elastic-client-meta"), None);
+    }
+
+    #[tokio::test]
+    async fn compatibility_follows_cluster_version() {
+        for (body, compatibility, expected) in [
+            (r#"{"version":{"number":"9.0.1"}}"#, None, Some(8)),
+            (r#"{"version":{"number":"8.13.0"}}"#, None, None),
+            (r#"{"version":{"number":"9.0.1"}}"#, Some("none"), None),
+            (r#"{"version":{"number":"8.13.0"}}"#, Some("7"), Some(7)),
+        ] {
+            let (store, requests) = open_store(
+                body,
+                None,
+                &compatibility
+                    .map(|compatibility| format!("compatibility = \"{compatibility}\"\n"))
+                    .unwrap_or_default(),
+            )
+            .await;
+
+            requests.lock().clear();
+            store.ping().await;
+            let request = requests.lock().pop().unwrap();
+            let media_type = expected.map(|version| {
+                format!("application/vnd.elasticsearch+json; compatible-with={version}")
+            });
+            assert_eq!(
+                header(&request, "accept"),
+                Some(media_type.unwrap_or_else(|| "application/json".to_string())),
+                "{body} {compatibility:?}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn cancelled_index_is_pending() {
+        let (store, _) = open_store("{}", Some("POST /stalwart_email/_doc/"), "").await;
+        let mut document = FtsDocument::<u8>::with_default_language(Language::English)
+            .with_account_id(1)
+            .with_document_id(2);
+        document.index(Field::Body, "Hello world", Language::English);
+
+        // The future is dropped while waiting for a response
+        let result = tokio::time::timeout(
+            Duration::from_millis(200),
+            store.fts_index(document, RefreshPolicy::NoRefresh),
+        )
+        .await;
+        assert!(result.is_err());
+        assert_eq!(store.pending.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn fallback_serves_searches_while_unreachable() {
+        // Nothing listens on the port once the listener is dropped
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let mut config = store_config(
+            port,
+            concat!(
+                "flavor = \"elasticsearch\"\n",
+                "fallback.enable = true\n",
+                "fallback.max-documents = 2\n",
+            ),
+        );
+        let store = ElasticSearchStore::open(&mut config, ("store", "elastic"))
+            .await
+            .unwrap();
+        for document_id in 1..=3 {
+            let mut document = FtsDocument::<u8>::with_default_language(Language::English)
+                .with_account_id(1)
+                .with_document_id(document_id);
+            document.index(Field::Body, "Quarterly report", Language::English);
+            assert!(store
+                .fts_index(document, RefreshPolicy::NoRefresh)
+                .await
+                .is_err());
+        }
+
+        // Only the most recent documents are kept
+        let search = || async {
+            store
+                .fts_query::<u8>(
+                    1,
+                    0,
+                    vec![FtsFilter::has_english_text(Field::Body, "port")],
+                    true,
+                )
+                .await
+                .unwrap()
+                .iter()
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(search().await, vec![2, 3]);
+        store
+            .fts_remove(1, 0, &vec![3u32], RefreshPolicy::NoRefresh)
+            .await
+            .ok();
+        assert_eq!(search().await, vec![2]);
+        assert_eq!(store.metrics().fallback_documents, 1);
+    }
+
+    #[tokio::test]
+    async fn self_test_reports_failed_step() {
+        // The empty search response has no hits
+        let (store, requests) = open_store("{}", None, "").await;
+        requests.lock().clear();
+
+        let err = store.fts_self_test().await.unwrap_err().to_string();
+        assert!(err.contains("failed at the search step"), "{err}");
+        assert!(requests
+            .lock()
+            .last()
+            .is_some_and(|request| request.starts_with("DELETE /stalwart_self_test_")));
+    }
+
+    #[tokio::test]
+    async fn query_reads_filtered_hits() {
+        let (store, requests) = open_store(
+            r#"{"hits":{"hits":[{"_score":1.5,"fields":{"document_id":[7]}}]}}"#,
+            None,
+            "",
+        )
+        .await;
+
+        let filters = vec![FtsFilter::<u8>::has_english_text(Field::Body, "hello")];
+        let results = store
+            .fts_query_scored(1, 0, filters, None, true, false)
+            .await
+            .unwrap();
+        assert_eq!(results, vec![(7, 1.5)]);
+        let request = requests.lock().pop().unwrap();
+        assert!(
+            request.contains("filter_path=hits.hits._score%2Chits.hits.fields "),
+            "{request}"
+        );
+    }
+
+    #[tokio::test]
+    async fn keyword_terms_are_lowercased() {
+        let (store, _) = open_store("{}", None, "").await;
+
+        let query = store.build_query(
+            &[1],
+            vec![FtsFilter::<u8>::has_keyword(Field::Keyword, "Important")],
+            true,
+        );
+        assert_eq!(
+            query["bool"]["must"][1],
+            serde_json::json!({ "term": { "keywords": "important" } })
+        );
+    }
+
+    #[tokio::test]
+    async fn buffer_is_drained_on_shutdown() {
+        let (store, requests) = open_store(
+            r#"{"errors":false}"#,
+            None,
+            concat!(
+                "buffer.enable = true\n",
+                "buffer.batch-size = 10\n",
+                "buffer.max-wait = \"1h\"\n",
+            ),
+        )
+        .await;
+        let store = Arc::new(store);
+        let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+        store.clone().spawn_index_buffer(shutdown_rx);
+        requests.lock().clear();
+
+        for document_id in 0..2 {
+            let mut document = FtsDocument::<u8>::with_default_language(Language::English)
+                .with_account_id(1)
+                .with_document_id(document_id);
+            document.index(Field::Body, "Hello world", Language::English);
+            store.fts_index_buffered(document).await.unwrap();
+        }
+        assert!(requests.lock().is_empty());
+        assert_eq!(store.metrics().buffered_documents, 2);
+
+        shutdown_tx.send(true).unwrap();
+        for _ in 0..50 {
+            if store.metrics().buffered_documents == 0 && !requests.lock().is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let requests = requests.lock();
+        assert_eq!(requests.len(), 1);
+        assert!(requests[0].starts_with("POST /_bulk"), "{}", requests[0]);
+    }
+
+    #[tokio::test]
+    async fn disabled_collections_are_skipped() {
+        let (store, requests) = open_store("{}", None, "index.disable = [\"email\"]\n").await;
+        requests.lock().clear();
+
+        let mut document = FtsDocument::<u8>::with_default_language(Language::English)
+            .with_account_id(1)
+            .with_document_id(2);
+        document.index(Field::Body, "Hello world", Language::English);
+        store
+            .fts_index(document, RefreshPolicy::NoRefresh)
+            .await
+            .unwrap();
+        store
+            .fts_remove(1, 0, &vec![2], RefreshPolicy::NoRefresh)
+            .await
+            .unwrap();
+        assert_eq!(store.fts_remove_multi(1, &[(0, vec![2])]).await.unwrap(), 0);
+        assert!(requests.lock().is_empty());
+
+        let filters = vec![FtsFilter::<u8>::has_english_text(Field::Body, "hello")];
+        let err = store.fts_query(1, 0, filters, true).await.unwrap_err();
+        assert!(err.to_string().contains("disabled"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn failed_deletes_are_reported() {
+        let (store, requests) = open_store(
+            concat!(
+                "{\"deleted\":1,\"version_conflicts\":0,\"failures\":[{\"id\":\"1:2\",",
+                "\"status\":500,\"cause\":{\"type\":\"exception\",\"reason\":\"failed\"}}]}"
+            ),
+            None,
+            "",
+        )
+        .await;
+        requests.lock().clear();
+
+        let err = store
+            .fts_remove(1, 0, &vec![2, 3], RefreshPolicy::NoRefresh)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("1:2"), "{err}");
+
+        let requests = requests.lock();
+        assert!(requests[0].contains("conflicts=proceed"), "{}", requests[0]);
+    }
+
+    #[tokio::test]
+    async fn missing_subjects_are_sorted_last() {
+        let (store, requests) = open_store(
+            r#"{"hits":{"hits":[{"fields":{"document_id":[3]}},{"fields":{"document_id":[1]}}]}}"#,
+            None,
+            "",
+        )
+        .await;
+
+        let filters = vec![FtsFilter::<u8>::has_english_text(Field::Body, "hello")];
+        let results = store
+            .fts_query_sorted(1, 0, filters, SortField::Subject, false)
+            .await
+            .unwrap();
+        assert_eq!(results, vec![3, 1]);
+        let request = requests.lock().pop().unwrap();
+        assert!(
+            request.contains(concat!(
+                r#""sort":[{"subject.keyword":{"missing":"_last","order":"desc","#,
+                r#""unmapped_type":"keyword"}},{"document_id":"asc"}]"#
+            )),
+            "{request}"
+        );
+    }
+
+    #[tokio::test]
+    async fn recent_messages_are_boosted() {
+        let (store, requests) = open_store(
+            r#"{"hits":{"hits":[]}}"#,
+            None,
+            concat!(
+                "query.recency.enable = true\n",
+                "query.recency.scale = \"7d\"\n",
+                "query.recency.weight = 2.5\n"
+            ),
+        )
+        .await;
+
+        for boost_recent in [true, false] {
+            let filters = vec![FtsFilter::<u8>::has_english_text(Field::Body, "hello")];
+            store
+                .fts_query_scored(1, 0, filters, None, true, boost_recent)
+                .await
+                .unwrap();
+            let request = requests.lock().pop().unwrap();
+            assert_eq!(
+                request.contains(r#""scale":"604800s""#) && request.contains(r#""weight":2.5"#),
+                boost_recent,
+                "{request}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn bulk_exists_keeps_request_order() {
+        let (store, requests) =
+            open_store(r#"{"docs":[{"found":true},{"found":false},{}]}"#, None, "").await;
+        requests.lock().clear();
+
+        assert_eq!(
+            store.fts_exists_bulk(1, 0, &[4, 5, 6, 7]).await.unwrap(),
+            vec![true, false, false, false]
+        );
+        assert!(store.fts_exists_bulk(1, 0, &[]).await.unwrap().is_empty());
+
+        let requests = requests.lock();
+        assert_eq!(requests.len(), 1);
+        assert!(
+            requests[0].starts_with("POST /stalwart_email/_mget"),
+            "{}",
+            requests[0]
+        );
+        assert!(
+            requests[0].contains(r#"{"ids":["1:4","1:5","1:6","1:7"]}"#),
+            "{}",
+            requests[0]
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_reindex_keeps_the_alias() {
+        for body in [
+            concat!(
+                "{\"failures\":[{\"id\":\"1:2\",\"status\":400,",
+                "\"cause\":{\"type\":\"mapper_parsing_exception\",\"reason\":\"failed\"}}]}"
+            ),
+            r#"{"timed_out":true,"failures":[]}"#,
+        ] {
+            let (store, requests) = open_store(body, None, "").await;
+            requests.lock().clear();
+
+            assert!(store.reindex_collection(0, true).await.is_err());
+            let requests = requests.lock();
+            assert!(
+                requests
+                    .iter()
+                    .any(|request| request.starts_with("DELETE /stalwart_email_v1 ")),
+                "{requests:?}"
+            );
+            assert!(
+                !requests
+                    .iter()
+                    .any(|request| request.starts_with("POST /_aliases")),
+                "{requests:?}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn text_can_be_excluded_from_source() {
+        let (store, requests) = open_store(
+            "{}",
+            None,
+            concat!(
+                "index.best-compression = true\n",
+                "index.source.exclude-text = true\n"
+            ),
+        )
+        .await;
+        requests.lock().clear();
+
+        let template = store.index_template();
+        assert_eq!(template["settings"]["index.codec"], "best_compression");
+        assert_eq!(
+            template["mappings"]["_source"]["excludes"],
+            serde_json::json!(["body", "body_*", "attachments"])
+        );
+
+        let err = store
+            .fts_update_keywords(1, 0, 2, vec!["$seen".to_string()])
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("cannot be updated"), "{err}");
+        assert!(store.reindex_collection(0, false).await.is_err());
+        assert!(requests.lock().is_empty());
+    }
+
+    #[tokio::test]
+    async fn header_conditions_are_nested() {
+        let (store, _) = open_store("{}", None, "").await;
+
+        let query = store.build_query(
+            &[1],
+            vec![
+                FtsFilter::<u8>::has_header("from", "alice"),
+                FtsFilter::<u8>::has_header("to", "bob"),
+            ],
+            true,
+        );
+        for (pos, name) in [(1, "from"), (2, "to")] {
+            let condition = &query["bool"]["must"][pos]["nested"];
+            assert_eq!(condition["path"], "header");
+            assert_eq!(
+                condition["query"]["bool"]["must"][0]["term"]["header.name"]["value"],
+                name
+            );
+        }
+    }
+
+    #[ignore]
+    #[tokio::test]
+    async fn distinct_headers_are_matched_independently() {
+        let mut config = Config::new(concat!(
+            "[store.\"elastic\"]\n",
+            "url = \"https://localhost:9200\"\n",
+            "user = \"elastic\"\n",
+            "password = \"changeme\"\n",
+            "tls.allow-invalid-certs = true\n",
+            "index.prefix = \"test_nested_\"\n",
+        ))
+        .unwrap();
+        let store = ElasticSearchStore::open(&mut config, ("store", "elastic"))
+            .await
+            .unwrap();
+        store.fts_remove_all(1).await.unwrap();
+
+        // Header 1 is the sender and header 2 the recipient
+        for (document_id, headers) in [
+            (1, vec![(1, "alice"), (2, "bob")]),
+            (2, vec![(1, "alice bob")]),
+            (3, vec![(1, "alice"), (2, "carol")]),
+        ] {
+            let mut document = FtsDocument::<u8>::with_default_language(Language::English)
+                .with_account_id(1)
+                .with_document_id(document_id);
+            for (header, value) in headers {
+                document.index_tokenized(Field::Header(header), value);
+            }
+            store
+                .fts_index(document, RefreshPolicy::Immediate)
+                .await
+                .unwrap();
+        }
+
+        let filters = vec![
+            FtsFilter::<u8>::has_header("1", "alice"),
+            FtsFilter::<u8>::has_header("2", "bob"),
+        ];
+        let results = store.fts_query(1, 0, filters, true).await.unwrap();
+        assert_eq!(results.iter().collect::<Vec<_>>(), vec![1]);
+    }
+
+    #[tokio::test]
+    async fn attachments_are_sent_to_pipeline() {
+        let (store, requests) = open_store(
+            "{}",
+            None,
+            concat!(
+                "index.attachments.ingest.enable = true\n",
+                "index.attachments.ingest.max-size = 8\n"
+            ),
+        )
+        .await;
+        assert!(requests
+            .lock()
+            .iter()
+            .any(|request| request.starts_with("PUT /_ingest/pipeline/stalwart_attachments ")));
+        requests.lock().clear();
+
+        let mut document = FtsDocument::<u8>::with_default_language(Language::English)
+            .with_account_id(1)
+            .with_document_id(2);
+        document.index_attachment_data(&b"%PDF-1.4"[..]);
+        document.index_attachment_data(&b"too large to send"[..]);
+        store
+            .fts_index(document, RefreshPolicy::NoRefresh)
+            .await
+            .unwrap();
+
+        let request = requests.lock().pop().unwrap();
+        assert!(
+            request.contains("pipeline=stalwart_attachments"),
+            "{request}"
+        );
+        assert!(
+            request.contains(r#""attachment_data":[{"data":"JVBERi0xLjQ="}]"#),
+            "{request}"
+        );
+    }
+
+    #[tokio::test]
+    async fn closed_indices_are_opened_on_demand() {
+        let (store, requests) = open_store(
+            r#"[{"index":"stalwart_email_v1","status":"close"}]"#,
+            None,
+            "",
+        )
+        .await;
+        requests.lock().clear();
+
+        assert!(store.ensure_open(0).await.unwrap());
+        assert!(store
+            .close_idle(Duration::from_secs(3600))
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            store.close_idle(Duration::ZERO).await.unwrap(),
+            vec!["stalwart_email_v1".to_string()]
+        );
+
+        let requests = requests.lock();
+        assert!(
+            requests[0].starts_with("GET /_cat/indices/stalwart_email?"),
+            "{}",
+            requests[0]
+        );
+        assert!(
+            requests[1].starts_with("POST /stalwart_email_v1/_open"),
+            "{}",
+            requests[1]
+        );
+        assert!(
+            requests[2].starts_with("POST /stalwart_email_v1/_close"),
+            "{}",
+            requests[2]
+        );
+    }
+
+    #[test]
+    fn diacritics_are_folded() {
+        assert_eq!(super::fold_diacritics("résumé"), "resume");
+        assert_eq!(
+            super::fold_diacritics("Straße Øre Łódź"),
+            "Strasse Ore Lodz"
+        );
+        assert!(matches!(
+            super::fold_diacritics("resume"),
+            std::borrow::Cow::Borrowed("resume")
+        ));
+    }
+
+    #[tokio::test]
+    async fn folding_can_be_disabled() {
+        let (port, _) = fake_cluster("{}", None).await;
+        for (fold, keyword) in [(true, "resume"), (false, "résumé")] {
+            let mut config =
+                store_config(port, &format!("index.analysis.fold-diacritics = {fold}\n"));
+            let store = ElasticSearchStore::open(&mut config, ("store", "elastic"))
+                .await
+                .unwrap();
+
+            let query = store.build_query(
+                &[1],
+                vec![FtsFilter::<u8>::has_keyword(Field::Keyword, "Résumé")],
+                true,
+            );
+            assert_eq!(
+                query["bool"]["must"][1],
+                serde_json::json!({ "term": { "keywords": keyword } })
+            );
+
+            let analysis = &store.index_template()["settings"]["analysis"];
+            for filters in [
+                &analysis["analyzer"]["default_analyzer"]["filter"],
+                &analysis["normalizer"]["keyword_normalizer"]["filter"],
+            ] {
+                assert_eq!(
+                    filters
+                        .as_array()
+                        .unwrap()
+                        .iter()
+                        .any(|filter| filter == "asciifolding"),
+                    fold
+                );
+            }
+            assert!(analysis["analyzer"]["attachment_analyzer"]["filter"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .any(|filter| filter == "asciifolding"));
+        }
+    }
+
+    #[tokio::test]
+    async fn custom_analysis_applies_to_languages() {
+        let custom = concat!(
+            "index.analysis.stopwords = [\"not\"]\n",
+            "index.analysis.synonyms = [\"ceo => chief executive officer\"]\n",
+        );
+        let (store, _) = open_store("{}", None, custom).await;
+        let template = store.index_template();
+        let analysis = &template["settings"]["analysis"];
+        assert_eq!(
+            analysis["analyzer"]["custom_english"]["filter"],
+            serde_json::json!([
+                "lowercase",
+                "english_stop",
+                "custom_stopwords",
+                "custom_synonyms",
+                "english_stemmer",
+                "asciifolding"
+            ])
+        );
+        assert_eq!(
+            analysis["analyzer"]["custom_cjk"]["filter"],
+            serde_json::json!([
+                "cjk_width",
+                "lowercase",
+                "cjk_bigram",
+                "cjk_stop",
+                "custom_stopwords",
+                "custom_synonyms",
+                "asciifolding"
+            ])
+        );
+        assert_eq!(analysis["filter"]["english_stop"]["stopwords"], "_english_");
+        assert_eq!(
+            template["mappings"]["properties"]["body_en"]["analyzer"],
+            "custom_english"
+        );
+        assert_eq!(
+            template["mappings"]["properties"]["body_ja"]["analyzer"],
+            "custom_cjk"
+        );
+
+        // The built-in analyzers are used without custom filters
+        let (store, _) = open_store("{}", None, "index.analysis.fold-diacritics = false\n").await;
+        let template = store.index_template();
+        assert!(template["settings"]["analysis"]["analyzer"]
+            .get("custom_english")
+            .is_none());
+        assert_eq!(
+            template["mappings"]["properties"]["body_en"]["analyzer"],
+            "english"
+        );
+
+        // Indices created with other analysis settings have to be reindexed
+        let mappings = serde_json::json!({
+            "stalwart_email_v1": { "mappings": template["mappings"] }
+        })
+        .to_string();
+        let mappings: &'static str = Box::leak(mappings.into_boxed_str());
+        for (config, changed) in [
+            ("index.analysis.fold-diacritics = false\n", false),
+            ("index.analysis.fold-diacritics = true\n", true),
+            (custom, true),
+        ] {
+            let (store, _) = open_store(mappings, None, config).await;
+            let analysis = &store.index_template()["settings"]["analysis"];
+            assert_eq!(
+                store
+                    .mapping_changed("stalwart_email", analysis)
+                    .await
+                    .unwrap(),
+                changed,
+                "{config}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn filtered_removal_requires_conditions() {
+        let (store, requests) =
+            open_store(r#"{"deleted":3,"version_conflicts":0}"#, None, "").await;
+        requests.lock().clear();
+
+        let filters = vec![FtsFilter::<u8>::Not, FtsFilter::End];
+        assert!(store
+            .fts_remove_by_filter(1, 0, filters, false)
+            .await
+            .is_err());
+        assert!(requests.lock().is_empty());
+
+        let filters = vec![FtsFilter::<u8>::has_header("from", "spammer@example.com")];
+        assert_eq!(
+            store
+                .fts_remove_by_filter(1, 0, filters, false)
+                .await
+                .unwrap(),
+            3
+        );
+        assert_eq!(
+            store
+                .fts_remove_by_filter(1, 0, Vec::<FtsFilter<u8>>::new(), true)
+                .await
+                .unwrap(),
+            3
+        );
+
+        let requests = requests.lock();
+        assert!(
+            requests[0].starts_with("POST /stalwart_email/_delete_by_query"),
+            "{}",
+            requests[0]
+        );
+        assert!(
+            requests[0].contains(r#""header.address""#)
+                && requests[0].contains(r#""account_id":[1]"#),
+            "{}",
+            requests[0]
+        );
+    }
+
+    #[tokio::test]
+    async fn accounts_are_routed_to_their_home_cluster() {
+        let (port, requests) = fake_cluster(r#"{"hits":{"hits":[]}}"#, None).await;
+        let (remote_port, remote_requests) = fake_cluster("{}", None).await;
+        let mut config = store_config(
+            port,
+            &format!(
+                concat!(
+                    "cluster.eu.url = \"http://127.0.0.1:{}\"\n",
+                    "cluster.eu.accounts = [\"100-199\", \"500\"]\n",
+                    "cluster.eu.seeds = [\"eu-node:9300\"]\n",
+                ),
+                remote_port
+            ),
+        );
+        let store = ElasticSearchStore::open(&mut config, ("store", "elastic"))
+            .await
+            .unwrap();
+        assert!(requests
+            .lock()
+            .iter()
+            .any(|request| request.starts_with("PUT /_cluster/settings")));
+        assert_eq!(store.home_cluster(150), Some("eu"));
+        assert_eq!(store.home_cluster(500), Some("eu"));
+        assert_eq!(store.home_cluster(5), None);
+        requests.lock().clear();
+        remote_requests.lock().clear();
+
+        // Documents are written directly to the home cluster
+        let mut document = FtsDocument::<u8>::with_default_language(Language::English)
+            .with_account_id(150)
+            .with_document_id(2);
+        document.index(Field::Body, "Hello world", Language::English);
+        store
+            .fts_index(document, RefreshPolicy::NoRefresh)
+            .await
+            .unwrap();
+        assert!(requests.lock().is_empty());
+        assert_eq!(remote_requests.lock().len(), 1);
+
+        // Searches go through the local cluster
+        let filters = || vec![FtsFilter::<u8>::has_english_text(Field::Body, "hello")];
+        store.fts_query(150, 0, filters(), true).await.unwrap();
+        store
+            .fts_query_accounts(&[5, 150], 0, filters())
+            .await
+            .unwrap();
+        let requests = requests.lock();
+        assert_eq!(requests.len(), 2);
+        assert!(
+            requests[0].starts_with("POST /eu%3Astalwart_email/_search"),
+            "{}",
+            requests[0]
+        );
+        assert!(
+            requests[1].starts_with("POST /stalwart_email,eu%3Astalwart_email/_search"),
+            "{}",
+            requests[1]
+        );
+    }
+
+    #[tokio::test]
+    async fn requests_are_routed_by_account() {
+        let (store, requests) = open_store(
+            "{}",
+            None,
+            concat!(
+                "index.routing.enable = true\n",
+                "index.routing.partition-size = 2\n"
+            ),
+        )
+        .await;
+        let template = store.index_template();
+        assert_eq!(template["mappings"]["_routing"]["required"], true);
+        assert_eq!(template["settings"]["index.routing_partition_size"], 2);
+        requests.lock().clear();
+
+        let mut document = FtsDocument::<u8>::with_default_language(Language::English)
+            .with_account_id(7)
+            .with_document_id(2);
+        document.index(Field::Body, "Hello world", Language::English);
+        store
+            .fts_index(document, RefreshPolicy::NoRefresh)
+            .await
+            .unwrap();
+        store
+            .fts_query(
+                7,
+                0,
+                vec![FtsFilter::<u8>::has_english_text(Field::Body, "hello")],
+                true,
+            )
+            .await
+            .unwrap();
+        store
+            .fts_remove(7, 0, &vec![2], RefreshPolicy::NoRefresh)
+            .await
+            .unwrap();
+
+        let requests = requests.lock();
+        assert_eq!(requests.len(), 3);
+        for request in requests.iter() {
+            let line = request.lines().next().unwrap();
+            assert!(line.contains("routing=7"), "{line}");
+        }
+    }
+
+    #[tokio::test]
+    async fn stored_documents_are_read_back() {
+        let (store, requests) = open_store(
+            r#"{"_id":"1:2","found":true,"_source":{"document_id":2,"account_id":1,"body":["hello"],"body_en":["hello"],"header":[{"name":"subject","value":"Hi"}],"subject.keyword":"Hi"}}"#,
+            None,
+            "",
+        )
+        .await;
+        requests.lock().clear();
+
+        let document = store.fts_get(1, 0, 2).await.unwrap().unwrap();
+        assert_eq!(document.document_id, 2);
+        assert_eq!(document.body, ["hello"]);
+        assert_eq!(document.body_lang["body_en"], ["hello"]);
+        assert_eq!(document.header[0].value, "Hi");
+        assert_eq!(document.subject.as_deref(), Some("Hi"));
+        // Fields missing from the source are left empty
+        assert!(document.keywords.is_empty());
+        assert!(requests
+            .lock()
+            .pop()
+            .unwrap()
+            .starts_with("GET /stalwart_email/_doc/1%3A2"));
+    }
+
+    #[tokio::test]
+    async fn multi_queries_keep_input_order() {
+        let (store, requests) = open_store(
+            r#"{"responses":[{"hits":{"hits":[{"fields":{"document_id":[3]}},{"fields":{"document_id":[1]}}]}},{},{"hits":{"hits":[{"fields":{"document_id":[7]}}]}}]}"#,
+            None,
+            "",
+        )
+        .await;
+        requests.lock().clear();
+
+        assert!(store
+            .fts_multi_query::<u8>(vec![])
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(requests.lock().is_empty());
+
+        let results = store
+            .fts_multi_query(vec![
+                (
+                    1,
+                    0,
+                    vec![FtsFilter::<u8>::has_keyword(Field::Keyword, "a")],
+                ),
+                (
+                    2,
+                    0,
+                    vec![FtsFilter::<u8>::has_keyword(Field::Keyword, "b")],
+                ),
+                (
+                    1,
+                    0,
+                    vec![FtsFilter::<u8>::has_keyword(Field::Keyword, "c")],
+                ),
+            ])
+            .await
+            .unwrap();
+        // Queries without matches have the hits filtered out of their response
+        assert_eq!(results, vec![vec![3, 1], vec![], vec![7]]);
+        assert_eq!(requests.lock().len(), 1);
+        assert!(requests.lock()[0].starts_with("POST /_msearch"));
+
+        // A response per query is expected
+        let (store, _) = open_store(r#"{"responses":[]}"#, None, "").await;
+        assert!(store
+            .fts_multi_query(vec![(
+                1,
+                0,
+                vec![FtsFilter::<u8>::has_keyword(Field::Keyword, "a")]
+            )])
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn large_removals_are_chunked() {
+        let (store, requests) = open_store(r#"{"deleted":65536}"#, None, "").await;
+        requests.lock().clear();
+
+        let document_ids = (0..100_000).collect::<Vec<u32>>();
+        store
+            .fts_remove(1, 0, &document_ids, RefreshPolicy::Immediate)
+            .await
+            .unwrap();
+
+        // Only the last chunk refreshes the index
+        let requests = requests.lock().clone();
+        assert_eq!(requests.len(), 2);
+        assert!(requests
+            .iter()
+            .all(|request| request.starts_with("POST /stalwart_email/_delete_by_query")));
+        assert!(!requests[0].contains("refresh=true"));
+        assert!(requests[1].contains("refresh=true"));
+    }
+
+    #[tokio::test]
+    async fn minimum_should_match_is_applied_to_text_conditions() {
+        let (port, requests) = fake_cluster(r#"{"hits":{"hits":[]}}"#, None).await;
+        let open = |minimum_should_match: &'static str| async move {
+            let mut config = store_config(port, minimum_should_match);
+            let store = ElasticSearchStore::open(&mut config, ("store", "elastic")).await;
+            (store, config.errors)
+        };
+        let filters = || {
+            vec![
+                FtsFilter::<u8>::has_text(
+                    Field::Header(1),
+                    "quarterly budget review",
+                    Language::None,
+                ),
+                FtsFilter::<u8>::has_text(Field::Body, "quarterly budget review", Language::None),
+                FtsFilter::<u8>::has_header("subject", "quarterly budget"),
+            ]
+        };
+
+        // All terms are optional by default
+        let (store, _) = open("").await;
+        let store = store.unwrap();
+        let query = store.build_query(&[1], filters(), true);
+        let conditions = &query["bool"]["must"];
+        assert_eq!(
+            conditions[1]["nested"]["query"]["bool"]["must"][1]["match"]["header.value"],
+            "quarterly budget review"
+        );
+        assert!(conditions[2]["multi_match"]["minimum_should_match"].is_null());
+
+        // A configured value requires most terms of text conditions only
+        let (store, _) = open("query.minimum-should-match = \"2<75%\"\n").await;
+        let store = store.unwrap();
+        let query = store.build_query(&[1], filters(), true);
+        let conditions = &query["bool"]["must"];
+        assert_eq!(
+            conditions[1]["nested"]["query"]["bool"]["must"][1]["match"]["header.value"]
+                ["minimum_should_match"],
+            "2<75%"
+        );
+        assert_eq!(
+            conditions[2]["multi_match"]["minimum_should_match"],
+            "2<75%"
+        );
+        assert_eq!(
+            conditions[3]["nested"]["query"]["bool"]["must"][1]["match"]["header.value"],
+            "quarterly budget"
+        );
+
+        // Searches can require every term
+        requests.lock().clear();
+        let min_match = |minimum_should_match| QueryOptions {
+            minimum_should_match: Some(minimum_should_match),
+            ..Default::default()
+        };
+        store
+            .fts_query_with(1, 0, filters(), min_match("100%"))
+            .await
+            .unwrap();
+        assert_eq!(requests.lock().len(), 1);
+        assert!(store
+            .fts_query_with(1, 0, filters(), min_match("most"))
+            .await
+            .is_err());
+
+        let (_, errors) = open("query.minimum-should-match = \"75\"\n").await;
+        assert!(errors.is_empty());
+        let (_, errors) = open("query.minimum-should-match = \"3<\"\n").await;
+        assert!(!errors.is_empty());
+    }
+
+    #[test]
+    fn minimum_should_match_syntax() {
+        for value in ["3", "-2", "75%", "-25%", "3<90%", "2<-25% 9<-3"] {
+            assert!(super::is_minimum_should_match(value), "{value}");
+        }
+        for value in ["", "75%%", "a", "3 4", "<90%", "3<", "2<-25% 9"] {
+            assert!(!super::is_minimum_should_match(value), "{value}");
+        }
+    }
+
+    #[tokio::test]
+    async fn patterns_match_whole_addresses() {
+        let (store, _) = open_store("{}", None, "index.headers.address = [\"1\"]\n").await;
+
+        let query = store.build_query(
+            &[1],
+            vec![
+                FtsFilter::<u8>::has_prefix(Field::Header(1), "Alice"),
+                FtsFilter::<u8>::has_wildcard(Field::Header(2), "*voice"),
+                FtsFilter::<u8>::has_prefix(Field::Keyword, "$label"),
+            ],
+            true,
+        );
+        let conditions = &query["bool"]["must"];
+        let pattern = |pos: usize| &conditions[pos]["nested"]["query"]["bool"]["must"][1];
+        assert_eq!(pattern(1)["prefix"]["header.address"]["value"], "alice");
+        assert!(pattern(1)["prefix"]["header.address"]["rewrite"].is_null());
+        // Leading wildcards are capped
+        assert_eq!(pattern(2)["wildcard"]["header.value"]["value"], "*voice");
+        assert_eq!(
+            pattern(2)["wildcard"]["header.value"]["rewrite"],
+            "top_terms_1000"
+        );
+        assert_eq!(conditions[3]["prefix"]["keywords"]["value"], "$label");
+    }
+
+    #[tokio::test]
+    async fn force_merge_runs_in_the_background() {
+        let (store, requests) = open_store(r#"{"task":"node:42"}"#, None, "").await;
+        requests.lock().clear();
+
+        assert_eq!(store.fts_force_merge(Some(0), 0).await.unwrap(), "node:42");
+        assert_eq!(store.fts_force_merge(None, 1).await.unwrap(), "node:42");
+        // Collections that are not indexed can't be merged
+        assert!(store.fts_force_merge(Some(1), 0).await.is_err());
+
+        let requests = requests.lock().clone();
+        assert_eq!(requests.len(), 2);
+        assert!(requests[0].starts_with("POST /stalwart_email/_forcemerge?"));
+        assert!(requests[0].contains("only_expunge_deletes=true"));
+        assert!(!requests[0].contains("max_num_segments"));
+        assert!(requests[0].contains("wait_for_completion=false"));
+        assert!(requests[1].starts_with("POST /stalwart_email/_forcemerge?"));
+        assert!(requests[1].contains("max_num_segments=1"));
+        assert!(!requests[1].contains("only_expunge_deletes"));
+    }
+
+    #[tokio::test]
+    async fn account_footprint_sums_sizes() {
+        let open = |body: &'static str| async move {
+            let (store, requests) = open_store(body, None, "").await;
+            requests.lock().clear();
+            (store, requests)
+        };
+
+        let (store, requests) =
+            open(r#"{"hits":{"total":{"value":3}},"aggregations":{"size":{"value":12345.0}}}"#)
+                .await;
+        assert_eq!(store.fts_account_footprint(1).await.unwrap(), (3, 12345));
+        assert!(requests
+            .lock()
+            .pop()
+            .unwrap()
+            .starts_with("POST /stalwart_email/_search?"));
+
+        // Accounts without documents have an empty footprint
+        let (store, _) =
+            open(r#"{"hits":{"total":{"value":0}},"aggregations":{"size":{"value":0.0}}}"#).await;
+        assert_eq!(store.fts_account_footprint(2).await.unwrap(), (0, 0));
+        let (store, _) = open("{}").await;
+        assert_eq!(store.fts_account_footprint(2).await.unwrap(), (0, 0));
+    }
+
+    #[tokio::test]
+    async fn body_preprocessor_runs_before_indexing() {
+        let (store, _) = open_store("{}", None, "index.max-field-length = 10\n").await;
+        let store = store.with_body_preprocessor(|text| {
+            text.lines()
+                .filter(|line| !line.starts_with('>'))
+                .collect::<Vec<_>>()
+                .join("\n")
+        });
+
+        let mut document = FtsDocument::<u8>::with_default_language(Language::English)
+            .with_account_id(1)
+            .with_received_at(0);
+        document.index_tokenized(Field::Header(1), "> Re: hello");
+        document.index_tokenized(Field::Body, "> On Monday Bob wrote:\n> hi");
+        document.index_tokenized(Field::Body, "> quoted\nThanks, see you");
+        let document = store.build_document(document);
+
+        // Fully quoted parts are skipped and the length limit applies to what is left
+        assert_eq!(document.body, vec!["Thanks,"]);
+        assert_eq!(document.header[0].value, "> Re: hello");
+    }
+
+    #[tokio::test]
+    async fn archived_snapshots_are_searched() {
+        let (port, requests) = fake_cluster(
+            r#"{"accepted":true,"snapshots":[{"state":"SUCCESS","indices":["other","stalwart_email_v3"]}]}"#,
+            None,
+        )
+        .await;
+        let open = |repository: &'static str| store_config(port, repository);
+
+        // Archiving requires a repository
+        let store = ElasticSearchStore::open(&mut open(""), ("store", "elastic"))
+            .await
+            .unwrap();
+        assert!(store.fts_snapshot_collection(0).await.is_err());
+
+        let store = ElasticSearchStore::open(
+            &mut open("snapshot.repository = \"archive\"\n"),
+            ("store", "elastic"),
+        )
+        .await
+        .unwrap();
+        requests.lock().clear();
+        let snapshot = store.fts_snapshot_collection(0).await.unwrap();
+        assert!(snapshot.starts_with("stalwart_email_"));
+        assert_eq!(
+            store.fts_mount_snapshot(0, &snapshot).await.unwrap(),
+            format!(
+                "stalwart_email_archive_{}",
+                snapshot.strip_prefix("stalwart_email_").unwrap()
+            )
+        );
+        store
+            .fts_query(
+                1,
+                0,
+                vec![FtsFilter::<u8>::has_keyword(Field::Keyword, "a")],
+                true,
+            )
+            .await
+            .unwrap();
+
+        let requests = requests.lock().clone();
+        assert_eq!(requests.len(), 4);
+        assert!(requests[0].starts_with(&format!("POST /_snapshot/archive/{snapshot}?")));
+        assert!(requests[1].starts_with(&format!("GET /_snapshot/archive/{snapshot} ")));
+        assert!(requests[2].starts_with(&format!(
+            "POST /_snapshot/archive/{snapshot}/_mount?wait_for_completion=true&storage=full_copy "
+        )));
+        assert!(
+            requests[3].starts_with("POST /stalwart_email,stalwart_email_archive_*/_search"),
+            "{}",
+            requests[3]
+        );
+    }
+
+    #[cfg(feature = "explain")]
+    #[tokio::test]
+    async fn scores_are_explained() {
+        let open = |body: &'static str| async move {
+            let (store, requests) = open_store(body, None, "").await;
+            requests.lock().clear();
+            (store, requests)
+        };
+
+        let (store, requests) = open(concat!(
+            r#"{"matched":true,"explanation":{"value":1.5,"description":"sum of:","#,
+            r#""details":[{"value":1.5,"description":"weight(body:invoice)","details":[]},"#,
+            r#"{"value":0.0,"description":"match on required clause"}]}}"#
+        ))
+        .await;
+        let (matched, explanation) = store
+            .fts_explain(
+                1,
+                0,
+                2,
+                vec![FtsFilter::<u8>::has_english_text(Field::Body, "invoice")],
+            )
+            .await
+            .unwrap();
+        assert!(matched);
+        assert_eq!(explanation.value, 1.5);
+        assert_eq!(explanation.details.len(), 2);
+        assert_eq!(explanation.details[0].description, "weight(body:invoice)");
+        assert!(explanation.details[1].details.is_empty());
+        assert!(requests
+            .lock()
+            .pop()
+            .unwrap()
+            .starts_with("POST /stalwart_email/_explain/1%3A2"));
+    }
+
+    #[tokio::test]
+    async fn deadlines_bound_requests() {
+        let (store, requests) = open_store(r#"{"hits":{"hits":[]}}"#, None, "").await;
+        requests.lock().clear();
+        let filters = || vec![FtsFilter::<u8>::has_english_text(Field::Body, "invoice")];
+
+        // Nothing is sent once the deadline has passed
+        let deadline = Instant::now();
+        let until = |deadline| QueryOptions {
+            deadline: Some(deadline),
+            ..Default::default()
+        };
+        assert!(matches!(
+            store.fts_query_with(1, 0, filters(), until(deadline)).await,
+            Err(crate::Error::Timeout)
+        ));
+        let document = FtsDocument::<u8>::with_default_language(Language::English)
+            .with_account_id(1)
+            .with_collection(0u8)
+            .with_document_id(2);
+        assert!(matches!(
+            store
+                .fts_index_until(document, RefreshPolicy::default(), deadline)
+                .await,
+            Err(crate::Error::Timeout)
+        ));
+        assert!(requests.lock().is_empty());
+
+        // The time left is passed on to the cluster
+        store
+            .fts_query_with(
+                1,
+                0,
+                filters(),
+                until(Instant::now() + Duration::from_secs(60)),
+            )
+            .await
+            .unwrap();
+        let request = requests.lock().pop().unwrap();
+        let timeout = request
+            .split_once("&timeout=")
+            .or_else(|| request.split_once("?timeout="))
+            .and_then(|(_, timeout)| timeout.split_once("ms"))
+            .and_then(|(timeout, _)| timeout.parse::<u64>().ok())
+            .unwrap();
+        assert!(timeout > 0 && timeout <= 30_000, "{request}");
+    }
+
+    #[tokio::test]
+    async fn concurrent_requests_are_limited() {
+        let (store, requests) = open_store(
+            r#"{"hits":{"hits":[]}}"#,
+            Some("POST /stalwart_email/_search"),
+            concat!("timeout.request = \"500ms\"\n", "concurrency = 1\n"),
+        )
+        .await;
+        requests.lock().clear();
+        let document = || {
+            FtsDocument::<u8>::with_default_language(Language::English)
+                .with_account_id(1)
+                .with_collection(0u8)
+                .with_document_id(2)
+        };
+
+        // The stalled search holds the only slot until it times out
+        let filters = vec![FtsFilter::<u8>::has_english_text(Field::Body, "invoice")];
+        let (search, _) = tokio::join!(store.fts_query(1, 0, filters, true), async {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            assert_eq!(store.metrics().in_flight_requests, 1);
+            assert!(tokio::time::timeout(
+                Duration::from_millis(100),
+                store.fts_index(document(), RefreshPolicy::default())
+            )
+            .await
+            .is_err());
+        });
+        assert!(matches!(search, Err(crate::Error::Timeout)));
+        assert!(requests.lock().is_empty());
+
+        store
+            .fts_index(document(), RefreshPolicy::default())
+            .await
+            .unwrap();
+        assert_eq!(store.metrics().in_flight_requests, 0);
+        assert!(requests
+            .lock()
+            .iter()
+            .any(|request| request.contains("/_doc/1%3A2")));
+    }
+
+    #[tokio::test]
+    async fn keywords_are_renamed_per_account() {
+        let (store, requests) = open_store(
+            r#"{"total":3,"updated":3,"noops":0,"failures":[]}"#,
+            None,
+            "",
+        )
+        .await;
+        requests.lock().clear();
+
+        assert_eq!(
+            store
+                .fts_rename_keyword(1, "$label", "$renamed")
+                .await
+                .unwrap(),
+            3
+        );
+        assert!(requests
+            .lock()
+            .pop()
+            .unwrap()
+            .starts_with("POST /stalwart_email/_update_by_query?"));
+        assert!(store.fts_rename_keyword(1, "", "$renamed").await.is_err());
+        assert!(requests.lock().is_empty());
+    }
+
+    #[tokio::test]
+    async fn cached_results_expire_on_writes() {
+        let (store, requests) =
+            open_store(r#"{"hits":{"hits":[]}}"#, None, "query.cache.size = 100\n").await;
+        requests.lock().clear();
+        let filters = || vec![FtsFilter::<u8>::has_english_text(Field::Body, "invoice")];
+        let searches = || {
+            requests
+                .lock()
+                .iter()
+                .filter(|request| request.contains("/_search"))
+                .count()
+        };
+
+        store.fts_query(1, 0, filters(), true).await.unwrap();
+        store.fts_query(1, 0, filters(), true).await.unwrap();
+        store.fts_query(2, 0, filters(), true).await.unwrap();
+        assert_eq!(searches(), 2);
+
+        // Writes to another account keep the results cached
+        let document = |account_id| {
+            FtsDocument::<u8>::with_default_language(Language::English)
+                .with_account_id(account_id)
+                .with_collection(0u8)
+                .with_document_id(2)
+        };
+        store
+            .fts_index(document(2), RefreshPolicy::default())
+            .await
+            .unwrap();
+        store.fts_query(1, 0, filters(), true).await.unwrap();
+        assert_eq!(searches(), 2);
+
+        store
+            .fts_index(document(1), RefreshPolicy::default())
+            .await
+            .unwrap();
+        store.fts_query(1, 0, filters(), true).await.unwrap();
+        assert_eq!(searches(), 3);
+        store
+            .fts_remove(1, 0, &vec![2u32], RefreshPolicy::default())
+            .await
+            .unwrap();
+        store.fts_query(1, 0, filters(), true).await.unwrap();
+        assert_eq!(searches(), 4);
+
+        let metrics = store.metrics();
+        assert_eq!((metrics.cache_hits, metrics.cache_misses), (2, 4));
+
+        // Searches combining the terms differently are cached separately
+        let options = QueryOptions {
+            operator: Some(QueryOperator::And),
+            ..Default::default()
+        };
+        store
+            .fts_query_with(1, 0, filters(), options)
+            .await
+            .unwrap();
+        store
+            .fts_query_with(1, 0, filters(), options)
+            .await
+            .unwrap();
+        assert_eq!(searches(), 5);
+    }
+
+    #[tokio::test]
+    async fn default_operator_combines_text_terms() {
+        let (port, requests) = fake_cluster(r#"{"hits":{"hits":[]}}"#, None).await;
+        let open = |operator: &'static str| async move {
+            let mut config = store_config(port, operator);
+            let store = ElasticSearchStore::open(&mut config, ("store", "elastic")).await;
+            (store, config.errors)
+        };
+        let filters = || {
+            vec![
+                FtsFilter::<u8>::has_text(Field::Header(1), "budget review", Language::None),
+                FtsFilter::<u8>::has_text(Field::Body, "budget review", Language::None),
+                FtsFilter::<u8>::has_text(Field::Keyword, "budget review", Language::None),
+                FtsFilter::<u8>::has_english_text(Field::Body, "\"budget review\""),
+            ]
+        };
+        let operators = |query: &serde_json::Value| {
+            let conditions = &query["bool"]["must"];
+            [
+                conditions[1]["nested"]["query"]["bool"]["must"][1]["match"]["header.value"]
+                    ["operator"]
+                    .clone(),
+                conditions[2]["multi_match"]["operator"].clone(),
+                conditions[3]["match"]["keywords"]["operator"].clone(),
+                conditions[4]["multi_match"]["operator"].clone(),
+            ]
+        };
+
+        // Any term matches by default
+        let (store, _) = open("").await;
+        let store = store.unwrap();
+        let query = store.build_query(&[1], filters(), true);
+        assert!(operators(&query).iter().all(|operator| operator.is_null()));
+        assert_eq!(
+            query["bool"]["must"][1]["nested"]["query"]["bool"]["must"][1]["match"]["header.value"],
+            "budget review"
+        );
+
+        // All terms of text conditions are required, phrases are left as they are
+        let (store, _) = open("query.default-operator = \"and\"\n").await;
+        let store = store.unwrap();
+        let query = store.build_query(&[1], filters(), true);
+        assert_eq!(
+            operators(&query),
+            [
+                "and".into(),
+                "and".into(),
+                "and".into(),
+                serde_json::Value::Null
+            ]
+        );
+
+        // Searches can override the default
+        requests.lock().clear();
+        store
+            .fts_query_with(
+                1,
+                0,
+                filters(),
+                QueryOptions {
+                    operator: Some(QueryOperator::Or),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(requests.lock().len(), 1);
+
+        let (store, errors) = open("query.default-operator = \"xor\"\n").await;
+        assert!(store.is_none());
+        assert!(!errors.is_empty());
+    }
+
+    #[tokio::test]
+    async fn bulk_progress_is_reported_per_request() {
+        let (store, requests) = open_store(r#"{"errors":false,"items":[]}"#, None, "").await;
+        requests.lock().clear();
+        let documents = (0..3)
+            .map(|document_id| {
+                FtsDocument::<u8>::with_default_language(Language::English)
+                    .with_account_id(1)
+                    .with_collection(0u8)
+                    .with_document_id(document_id)
+            })
+            .collect();
+
+        // Each document exceeds the payload size and is sent on its own
+        let mut reports = Vec::new();
+        let failed = store
+            .fts_index_bulk_with_progress(documents, 1, |progress| reports.push(progress))
+            .await
+            .unwrap();
+        assert!(failed.is_empty());
+        assert_eq!(requests.lock().len(), 3);
+        assert_eq!(
+            reports
+                .iter()
+                .map(|progress| (progress.processed, progress.succeeded, progress.failed))
+                .collect::<Vec<_>>(),
+            vec![(1, 1, 0), (2, 2, 0), (3, 3, 0)]
+        );
+    }
+
+    #[tokio::test]
+    async fn deep_pages_search_archives() {
+        let (store, requests) = open_store(
+            r#"{"id":"pit","hits":{"total":{"value":3},"hits":[]}}"#,
+            None,
+            "snapshot.repository = \"archive\"\n",
+        )
+        .await;
+        requests.lock().clear();
+
+        let page = store
+            .fts_query_page(
+                1,
+                0u8,
+                vec![FtsFilter::<u8>::has_keyword(Field::Keyword, "a")],
+                usize::MAX,
+                10,
+            )
+            .await
+            .unwrap();
+        assert_eq!(page.total, 3);
+        assert!(page.document_ids.is_empty());
+
+        // The matches are counted and paged through on the same indices
+        let requests = requests.lock();
+        assert!(
+            requests[0].starts_with("POST /stalwart_email,stalwart_email_archive_*/_search"),
+            "{}",
+            requests[0]
+        );
+        assert!(
+            requests[1].starts_with("POST /stalwart_email,stalwart_email_archive_*/_pit"),
+            "{}",
+            requests[1]
+        );
+    }
+
+    #[tokio::test]
+    async fn opensearch_searches_are_scrolled() {
+        let (store, requests) = open_store(
+            r#"{"version":{"distribution":"opensearch","number":"2.11.0"},"_scroll_id":"s","hits":{"hits":[]}}"#,
+            None,
+            "",
+        )
+        .await;
+        requests.lock().clear();
+
+        let stream = store
+            .fts_query_all(
+                1,
+                0u8,
+                vec![FtsFilter::<u8>::has_keyword(Field::Keyword, "a")],
+            )
+            .await
+            .unwrap();
+        assert!(futures::StreamExt::collect::<Vec<_>>(stream)
+            .await
+            .is_empty());
+
+        // OpenSearch has no point in time API compatible with ElasticSearch
+        let requests = requests.lock();
+        assert_eq!(requests.len(), 3, "{requests:?}");
+        assert!(
+            requests[0].starts_with("POST /stalwart_email/_search?scroll=1m"),
+            "{}",
+            requests[0]
+        );
+        assert!(
+            requests[1].starts_with("POST /_search/scroll "),
+            "{}",
+            requests[1]
+        );
+        assert!(
+            requests[2].starts_with("DELETE /_search/scroll "),
+            "{}",
+            requests[2]
+        );
+    }
+
+    #[tokio::test]
+    async fn bulk_writes_are_versioned() {
+        let (store, requests) = open_store(
+            r#"{"errors":true,"items":[{"index":{"status":409,"error":{"type":"version_conflict_engine_exception","reason":"stale"}}}]}"#,
+            None,
+            concat!("buffer.enable = true\n", "buffer.batch-size = 1\n"),
+        )
+        .await;
+        requests.lock().clear();
+        let document = || {
+            FtsDocument::<u8>::with_default_language(Language::English)
+                .with_account_id(1)
+                .with_document_id(2)
+                .with_version(7)
+        };
+
+        // Stale versions are skipped rather than reported as failures
+        let failed = store.fts_index_bulk(vec![document()], 1024).await.unwrap();
+        assert!(failed.is_empty());
+        store.fts_index_buffered(document()).await.unwrap();
+
+        let requests = requests.lock();
+        assert_eq!(requests.len(), 2);
+        for request in requests.iter() {
+            assert!(request.starts_with("POST /_bulk"), "{request}");
+            assert!(
+                request.contains(r#""version":7,"version_type":"external""#),
+                "{request}"
+            );
+        }
+        assert_eq!(store.metrics().pending_operations, 0);
+    }
+}
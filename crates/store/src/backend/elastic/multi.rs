@@ -0,0 +1,256 @@
+/*
+ * Copyright (c) 2023 Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Mail Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::{
+    fmt::Display,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use futures::future::{join, join_all};
+use roaring::RoaringBitmap;
+
+use crate::{
+    dispatch::DocumentSet,
+    fts::{index::FtsDocument, FtsFilter},
+};
+
+use super::backend::FtsBackend;
+
+/// Writes to several full-text stores at once so a new store can be filled while
+/// the current one keeps serving searches, for example when migrating from the
+/// internal store to ElasticSearch. Searches only read from the primary and a
+/// failed write is only reported when the primary failed, failures of secondary
+/// stores are logged and counted.
+pub struct MultiFtsStore<P: FtsBackend, S: FtsBackend> {
+    primary: P,
+    secondaries: Vec<S>,
+    secondary_failures: AtomicU64,
+}
+
+impl<P: FtsBackend, S: FtsBackend> MultiFtsStore<P, S> {
+    pub fn new(primary: P) -> Self {
+        Self {
+            primary,
+            secondaries: Vec::new(),
+            secondary_failures: AtomicU64::new(0),
+        }
+    }
+
+    pub fn with_secondary(mut self, secondary: S) -> Self {
+        self.secondaries.push(secondary);
+        self
+    }
+
+    pub fn primary(&self) -> &P {
+        &self.primary
+    }
+
+    pub fn secondaries(&self) -> &[S] {
+        &self.secondaries
+    }
+
+    /// Number of writes that failed on a secondary store, once non zero the
+    /// secondary stores are out of sync and have to be reindexed before cutover.
+    pub fn secondary_failures(&self) -> u64 {
+        self.secondary_failures.load(Ordering::Relaxed)
+    }
+
+    /// Indexes a document in all stores, returning the result of each store with
+    /// the primary first.
+    pub async fn index_all<T: Into<u8> + Display + Clone + std::fmt::Debug + Send + Sync>(
+        &self,
+        document: FtsDocument<'_, T>,
+    ) -> Vec<crate::Result<()>> {
+        let (primary, secondaries) = join(
+            self.primary.fts_index(document.clone()),
+            join_all(
+                self.secondaries
+                    .iter()
+                    .map(|secondary| secondary.fts_index(document.clone())),
+            ),
+        )
+        .await;
+        self.results(primary, secondaries, "index")
+    }
+
+    /// Removes documents from all stores, returning the result of each store with
+    /// the primary first.
+    pub async fn remove_all<D: DocumentSet>(
+        &self,
+        account_id: u32,
+        collection: u8,
+        document_ids: &D,
+    ) -> Vec<crate::Result<()>> {
+        let (primary, secondaries) = join(
+            self.primary
+                .fts_remove(account_id, collection, document_ids),
+            join_all(
+                self.secondaries
+                    .iter()
+                    .map(|secondary| secondary.fts_remove(account_id, collection, document_ids)),
+            ),
+        )
+        .await;
+        self.results(primary, secondaries, "remove")
+    }
+
+    fn results(
+        &self,
+        primary: crate::Result<()>,
+        secondaries: Vec<crate::Result<()>>,
+        operation: &str,
+    ) -> Vec<crate::Result<()>> {
+        for (pos, result) in secondaries.iter().enumerate() {
+            if let Err(err) = result {
+                self.secondary_failures.fetch_add(1, Ordering::Relaxed);
+                tracing::warn!(
+                    context = "fts",
+                    event = "error",
+                    secondary = pos,
+                    operation = operation,
+                    reason = ?err,
+                    "Write to secondary full-text store failed"
+                );
+            }
+        }
+
+        let mut results = Vec::with_capacity(secondaries.len() + 1);
+        results.push(primary);
+        results.extend(secondaries);
+        results
+    }
+}
+
+impl<P: FtsBackend, S: FtsBackend> FtsBackend for MultiFtsStore<P, S> {
+    async fn fts_index<T: Into<u8> + Display + Clone + std::fmt::Debug + Send + Sync>(
+        &self,
+        document: FtsDocument<'_, T>,
+    ) -> crate::Result<()> {
+        self.index_all(document).await.swap_remove(0)
+    }
+
+    async fn fts_query<T: Into<u8> + Display + Clone + std::fmt::Debug + Send + Sync>(
+        &self,
+        account_id: u32,
+        collection: u8,
+        filters: Vec<FtsFilter<T>>,
+        include_attachments: bool,
+    ) -> crate::Result<RoaringBitmap> {
+        self.primary
+            .fts_query(account_id, collection, filters, include_attachments)
+            .await
+    }
+
+    async fn fts_remove(
+        &self,
+        account_id: u32,
+        collection: u8,
+        document_ids: &impl DocumentSet,
+    ) -> crate::Result<()> {
+        self.remove_all(account_id, collection, document_ids)
+            .await
+            .swap_remove(0)
+    }
+
+    async fn fts_remove_all(&self, account_id: u32) -> crate::Result<()> {
+        let (primary, secondaries) = join(
+            self.primary.fts_remove_all(account_id),
+            join_all(
+                self.secondaries
+                    .iter()
+                    .map(|secondary| secondary.fts_remove_all(account_id)),
+            ),
+        )
+        .await;
+        self.results(primary, secondaries, "remove_all")
+            .swap_remove(0)
+    }
+}
+
+#[cfg(all(test, feature = "test-util"))]
+mod tests {
+    use std::fmt::Display;
+
+    use nlp::language::Language;
+    use roaring::RoaringBitmap;
+
+    use crate::{
+        backend::elastic::{backend::FtsBackend, mock::MockFtsStore},
+        dispatch::DocumentSet,
+        fts::{index::FtsDocument, Field, FtsFilter},
+    };
+
+    use super::MultiFtsStore;
+
+    struct FailingStore;
+
+    impl FtsBackend for FailingStore {
+        async fn fts_index<T: Into<u8> + Display + Clone + std::fmt::Debug + Send + Sync>(
+            &self,
+            _: FtsDocument<'_, T>,
+        ) -> crate::Result<()> {
+            Err(crate::Error::InternalError("unavailable".to_string()))
+        }
+
+        async fn fts_query<T: Into<u8> + Display + Clone + std::fmt::Debug + Send + Sync>(
+            &self,
+            _: u32,
+            _: u8,
+            _: Vec<FtsFilter<T>>,
+            _: bool,
+        ) -> crate::Result<RoaringBitmap> {
+            Err(crate::Error::InternalError("unavailable".to_string()))
+        }
+
+        async fn fts_remove(&self, _: u32, _: u8, _: &impl DocumentSet) -> crate::Result<()> {
+            Err(crate::Error::InternalError("unavailable".to_string()))
+        }
+
+        async fn fts_remove_all(&self, _: u32) -> crate::Result<()> {
+            Err(crate::Error::InternalError("unavailable".to_string()))
+        }
+    }
+
+    #[tokio::test]
+    async fn secondary_failures_are_tolerated() {
+        let store = MultiFtsStore::new(MockFtsStore::new()).with_secondary(FailingStore);
+
+        let mut document = FtsDocument::<u8>::with_default_language(Language::English)
+            .with_account_id(1)
+            .with_document_id(2);
+        document.index(Field::Body, "Quarterly report", Language::English);
+        let results = store.index_all(document.clone()).await;
+        assert!(results[0].is_ok());
+        assert!(results[1].is_err());
+        store.fts_index(document).await.unwrap();
+        assert_eq!(store.secondary_failures(), 2);
+
+        let filters = vec![FtsFilter::<u8>::has_english_text(Field::Body, "report")];
+        let results = store.fts_query(1, 0, filters, true).await.unwrap();
+        assert_eq!(results.iter().collect::<Vec<_>>(), vec![2]);
+
+        store.fts_remove(1, 0, &vec![2]).await.unwrap();
+        assert!(store.primary().is_empty());
+        assert_eq!(store.secondary_failures(), 3);
+    }
+}
@@ -0,0 +1,188 @@
+/*
+ * Copyright (c) 2023 Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Mail Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use ahash::AHashMap;
+use parking_lot::Mutex;
+
+#[derive(Debug, Clone)]
+pub(crate) enum PendingOperation {
+    Index {
+        account_id: u32,
+        document_id: u32,
+        source: String,
+        create: bool,
+        routing: Option<String>,
+        version: Option<u64>,
+    },
+    Remove {
+        account_id: u32,
+        collection: u8,
+        document_id: u32,
+    },
+}
+
+// Operations that failed while the cluster was unreachable, keyed by index and
+// document id so only the latest operation on a document is replayed.
+pub(crate) struct PendingQueue {
+    max_operations: usize,
+    operations: Mutex<AHashMap<(String, String), PendingOperation>>,
+    draining: AtomicBool,
+}
+
+impl PendingQueue {
+    pub fn new(max_operations: usize) -> Self {
+        PendingQueue {
+            max_operations,
+            operations: Mutex::new(AHashMap::new()),
+            draining: AtomicBool::new(false),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.operations.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.operations.lock().is_empty()
+    }
+
+    pub fn insert(&self, index: &str, id: &str, operation: PendingOperation) {
+        let mut operations = self.operations.lock();
+        let key = (index.to_string(), id.to_string());
+        if operations.len() < self.max_operations || operations.contains_key(&key) {
+            operations.insert(key, operation);
+        } else if self.max_operations > 0 {
+            tracing::warn!(
+                context = "elasticsearch",
+                event = "pending-full",
+                index = index,
+                id = id,
+                "Pending operations queue is full, dropping operation"
+            );
+        }
+    }
+
+    // A successful operation supersedes any pending one on the same document
+    pub fn remove(&self, index: &str, id: &str) {
+        let mut operations = self.operations.lock();
+        if !operations.is_empty() {
+            operations.remove(&(index.to_string(), id.to_string()));
+        }
+    }
+
+    pub fn remove_index(&self, index: &str) {
+        self.operations
+            .lock()
+            .retain(|(operation_index, _), _| operation_index != index);
+    }
+
+    pub fn take(&self, max_operations: usize) -> Vec<((String, String), PendingOperation)> {
+        let mut operations = self.operations.lock();
+        let keys = operations
+            .keys()
+            .take(max_operations)
+            .cloned()
+            .collect::<Vec<_>>();
+        keys.into_iter()
+            .filter_map(|key| operations.remove_entry(&key))
+            .collect()
+    }
+
+    // Operations queued while replaying are newer and take precedence
+    pub fn restore(&self, pending: Vec<((String, String), PendingOperation)>) {
+        let mut operations = self.operations.lock();
+        for (key, operation) in pending {
+            operations.entry(key).or_insert(operation);
+        }
+    }
+
+    pub fn try_drain(&self) -> bool {
+        !self.draining.swap(true, Ordering::AcqRel)
+    }
+
+    pub fn drain_done(&self) {
+        self.draining.store(false, Ordering::Release);
+    }
+}
+
+// Runs once dropped unless disarmed, used to record operations whose outcome is
+// unknown because the request failed or the calling task was cancelled mid-request.
+pub(crate) struct PendingGuard<F: FnOnce()> {
+    record: Option<F>,
+}
+
+impl<F: FnOnce()> PendingGuard<F> {
+    pub fn new(record: F) -> Self {
+        PendingGuard {
+            record: Some(record),
+        }
+    }
+
+    pub fn disarm(mut self) {
+        self.record = None;
+    }
+}
+
+impl<F: FnOnce()> Drop for PendingGuard<F> {
+    fn drop(&mut self) {
+        if let Some(record) = self.record.take() {
+            record();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PendingOperation, PendingQueue};
+
+    fn remove(document_id: u32) -> PendingOperation {
+        PendingOperation::Remove {
+            account_id: 1,
+            collection: 0,
+            document_id,
+        }
+    }
+
+    #[test]
+    fn operations_are_deduplicated() {
+        let queue = PendingQueue::new(2);
+        queue.insert("index", "1:1", remove(1));
+        queue.insert("index", "1:1", remove(1));
+        queue.insert("index", "1:2", remove(2));
+        assert_eq!(queue.len(), 2);
+
+        // Full queues only accept operations on documents already queued
+        queue.insert("index", "1:3", remove(3));
+        assert_eq!(queue.len(), 2);
+
+        queue.remove("index", "1:1");
+        let batch = queue.take(10);
+        assert_eq!(batch.len(), 1);
+        assert!(queue.is_empty());
+
+        queue.restore(batch);
+        assert_eq!(queue.len(), 1);
+    }
+}
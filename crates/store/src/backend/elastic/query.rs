@@ -21,56 +21,1254 @@
  * for more details.
 */
 
-use std::{borrow::Cow, fmt::Display};
+use std::{borrow::Cow, collections::VecDeque, fmt::Display, sync::Arc, time::Instant};
 
-use elasticsearch::SearchParts;
+use ahash::{AHashMap, AHashSet};
+#[cfg(feature = "explain")]
+use elasticsearch::ExplainParts;
+use elasticsearch::{
+    http::StatusCode, ClearScrollParts, CountParts, Elasticsearch, ExistsParts, GetParts,
+    MgetParts, MsearchParts, OpenPointInTimeParts, ScrollParts, SearchParts,
+};
+use futures::{Stream, StreamExt};
+use nlp::language::Language;
 use roaring::RoaringBitmap;
 use serde_json::{json, Value};
 
 use crate::fts::{Field, FtsFilter};
 
-use super::{ElasticSearchStore, INDEX_NAMES};
+use super::{
+    assert_success, bare_address, cache::QueryCache, cluster::log_skipped_clusters,
+    compat::CompatibleRequest, document_key, envelope_address, fold_diacritics, index::Document,
+    is_minimum_should_match, language_code, message_id, metrics::Operation, time_left,
+    ElasticError, ElasticSearchStore, Flavor, HealthStatus, INDEX_NAMES, LANGUAGE_ANALYZERS,
+};
+
+const PAGE_SIZE: usize = 1000;
+// Default value of the "index.max_result_window" setting
+const MAX_RESULT_WINDOW: usize = 10000;
+const SUGGEST_SIZE: usize = 5;
+const PIT_KEEP_ALIVE: &str = "1m";
+// Terms a leading wildcard query expands to at most
+const MAX_PATTERN_TERMS: usize = 1000;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct QueryHit {
+    pub document_id: u32,
+    pub score: f32,
+    // Requested fields present in the document source
+    pub fields: serde_json::Map<String, Value>,
+}
+
+/// A step of the score computation, the value of an explanation is computed
+/// from the values of its details as stated by its description.
+#[cfg(feature = "explain")]
+#[derive(Debug, Clone, Default, PartialEq, serde::Deserialize)]
+pub struct Explanation {
+    pub value: f32,
+    pub description: String,
+    #[serde(default)]
+    pub details: Vec<Explanation>,
+}
+
+/// How the terms of free-text conditions are combined. Requiring all the terms
+/// makes multi-word searches more precise, at the cost of recall: messages that
+/// only contain some of the words, for example misspelled or differently inflected
+/// ones, no longer match.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum QueryOperator {
+    #[default]
+    Or,
+    And,
+}
+
+/// How a search is run, the defaults are those of `fts_query` including the
+/// attachments.
+#[derive(Debug, Clone, Copy)]
+pub struct QueryOptions<'x> {
+    /// Gives up once the deadline has passed. The time left is used as both the
+    /// request timeout and the search timeout of the cluster, and searches the
+    /// cluster could not complete in time fail instead of returning partial
+    /// results. Nothing is sent when the deadline has already passed.
+    pub deadline: Option<Instant>,
+    /// Overrides `query.default-operator`.
+    pub operator: Option<QueryOperator>,
+    /// Overrides `query.minimum-should-match`, in the ElasticSearch syntax: a
+    /// number of terms ("2"), a percentage of the terms rounded down ("75%"), a
+    /// negative number or percentage of terms allowed to be missing ("-1",
+    /// "-25%"), or conditions such as "2<75%" that require all terms up to two
+    /// terms and 75% above.
+    pub minimum_should_match: Option<&'x str>,
+    /// Conditions on attachments never match when false.
+    pub include_attachments: bool,
+}
+
+impl Default for QueryOptions<'_> {
+    fn default() -> Self {
+        Self {
+            deadline: None,
+            operator: None,
+            minimum_should_match: None,
+            include_attachments: true,
+        }
+    }
+}
+
+// How the terms of each `Contains` condition have to match
+#[derive(Debug, Clone, Copy, Default)]
+struct TextMatch<'x> {
+    operator: QueryOperator,
+    minimum_should_match: Option<&'x str>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortField {
+    Subject,
+    ReceivedAt,
+    Size,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct QueryPage {
+    pub document_ids: Vec<u32>,
+    pub total: u64,
+}
+
+// Pages through the hits of a search with a point in time, or with a scroll on
+// OpenSearch
+struct SearchCursor<'x> {
+    store: &'x ElasticSearchStore,
+    client: Arc<Elasticsearch>,
+    // Point in time or scroll id, taken once released
+    id: Option<String>,
+    scroll: bool,
+    // Sent with every page along with the point in time and the last sort values
+    body: Value,
+    deadline: Option<Instant>,
+    search_after: Option<Value>,
+    buffer: VecDeque<QueryHit>,
+}
 
 impl ElasticSearchStore {
+    /// Returns the matching documents. Attachment text is often noisy, so searches
+    /// can exclude it by setting `include_attachments` to false, in which case
+    /// conditions on attachments never match. Results are served from the query
+    /// cache when enabled, until the account is written to or they expire.
+    ///
+    /// While the cluster is unreachable, searches are answered from the fallback
+    /// index when enabled, which only holds the documents that failed to be
+    /// indexed and matches text as substrings.
     pub async fn fts_query<T: Into<u8> + Display + Clone + std::fmt::Debug>(
         &self,
         account_id: u32,
         collection: impl Into<u8>,
         filters: Vec<FtsFilter<T>>,
+        include_attachments: bool,
+    ) -> crate::Result<RoaringBitmap> {
+        self.fts_query_with(
+            account_id,
+            collection,
+            filters,
+            QueryOptions {
+                include_attachments,
+                ..Default::default()
+            },
+        )
+        .await
+    }
+
+    /// Like `fts_query`, with a deadline or overriding how the terms of text
+    /// conditions are combined as set in `options`.
+    pub async fn fts_query_with<T: Into<u8> + Display + Clone + std::fmt::Debug>(
+        &self,
+        account_id: u32,
+        collection: impl Into<u8>,
+        filters: Vec<FtsFilter<T>>,
+        options: QueryOptions<'_>,
     ) -> crate::Result<RoaringBitmap> {
+        if let Some(minimum_should_match) = options
+            .minimum_should_match
+            .filter(|value| !is_minimum_should_match(value))
+        {
+            return Err(crate::Error::InternalError(format!(
+                "Invalid minimum_should_match value {minimum_should_match:?}"
+            )));
+        }
+        let collection = collection.into();
+        let cached = self.cache.as_ref().map(|cache| {
+            let key = QueryCache::key(account_id, collection, &filters, &options);
+            (cache, key, cache.generation(account_id))
+        });
+        if let Some(document_ids) = cached.as_ref().and_then(|(cache, key, _)| cache.get(key)) {
+            return Ok(document_ids);
+        }
+
+        let fallback_filters = self.fallback.as_ref().map(|_| filters.clone());
+        let document_ids: RoaringBitmap = match self
+            .query_scored(account_id, collection, filters, None, false, &options)
+            .await
+        {
+            Ok(hits) => hits
+                .into_iter()
+                .map(|(document_id, _)| document_id)
+                .collect(),
+            Err(err) => {
+                return match (&self.fallback, fallback_filters) {
+                    (Some(fallback), Some(filters))
+                        if self.health() == HealthStatus::Unreachable =>
+                    {
+                        tracing::debug!(
+                            context = "elasticsearch",
+                            event = "fallback",
+                            account_id = account_id,
+                            "Searching the fallback index while ElasticSearch is unreachable"
+                        );
+                        Ok(fallback.lock().query(
+                            account_id,
+                            collection,
+                            filters,
+                            options.include_attachments,
+                        ))
+                    }
+                    _ => Err(err),
+                };
+            }
+        };
+        if let Some((cache, key, generation)) = cached {
+            cache.insert(key, generation, document_ids.clone());
+        }
+        Ok(document_ids)
+    }
+
+    /// Returns the matching documents with their relevance score, best matches first.
+    /// When a recency boost is configured and `boost_recent` is set, recent messages
+    /// rank higher and `min_score` applies to the boosted score.
+    pub async fn fts_query_scored<T: Into<u8> + Display + Clone + std::fmt::Debug>(
+        &self,
+        account_id: u32,
+        collection: impl Into<u8>,
+        filters: Vec<FtsFilter<T>>,
+        min_score: Option<f32>,
+        include_attachments: bool,
+        boost_recent: bool,
+    ) -> crate::Result<Vec<(u32, f32)>> {
+        self.query_scored(
+            account_id,
+            collection.into(),
+            filters,
+            min_score,
+            boost_recent,
+            &QueryOptions {
+                include_attachments,
+                ..Default::default()
+            },
+        )
+        .await
+    }
+
+    async fn query_scored<T: Into<u8> + Display + Clone + std::fmt::Debug>(
+        &self,
+        account_id: u32,
+        collection: u8,
+        filters: Vec<FtsFilter<T>>,
+        min_score: Option<f32>,
+        boost_recent: bool,
+        options: &QueryOptions<'_>,
+    ) -> crate::Result<Vec<(u32, f32)>> {
+        let mut query = self.build_query_with(
+            &[account_id],
+            filters,
+            options.include_attachments,
+            TextMatch {
+                operator: options.operator.unwrap_or(self.default_operator),
+                minimum_should_match: options
+                    .minimum_should_match
+                    .or(self.minimum_should_match.as_deref()),
+            },
+        );
+        if boost_recent {
+            query = self.boost_recent(query);
+        }
+        Ok(self
+            .search_hits(
+                account_id,
+                collection,
+                query,
+                min_score,
+                &[],
+                None,
+                options.deadline,
+            )
+            .await?
+            .into_iter()
+            .map(|hit| (hit.document_id, hit.score))
+            .collect())
+    }
+
+    /// Like `fts_query_scored`, also returning the requested fields of each match,
+    /// for example the subject to display a snippet. Only request the fields that
+    /// are needed, each one is read from the stored source of every match. Recent
+    /// messages are boosted when configured.
+    pub async fn fts_query_fields<T: Into<u8> + Display + Clone + std::fmt::Debug>(
+        &self,
+        account_id: u32,
+        collection: impl Into<u8>,
+        filters: Vec<FtsFilter<T>>,
+        fields: &[&str],
+    ) -> crate::Result<Vec<QueryHit>> {
+        let query = self.boost_recent(self.build_query(&[account_id], filters, true));
+        self.search_hits(
+            account_id,
+            collection.into(),
+            query,
+            None,
+            fields,
+            None,
+            None,
+        )
+        .await
+    }
+
+    /// Returns the matching documents ordered by a field instead of relevance.
+    /// Documents without a value for the field, such as messages without a
+    /// subject or indexed before subjects were, are always sorted last.
+    pub async fn fts_query_sorted<T: Into<u8> + Display + Clone + std::fmt::Debug>(
+        &self,
+        account_id: u32,
+        collection: impl Into<u8>,
+        filters: Vec<FtsFilter<T>>,
+        sort: SortField,
+        ascending: bool,
+    ) -> crate::Result<Vec<u32>> {
+        let query = self.build_query(&[account_id], filters, true);
+        let (field, unmapped_type) = match sort {
+            SortField::Subject => ("subject.keyword", "keyword"),
+            SortField::ReceivedAt => ("received_at", "date"),
+            SortField::Size => ("size", "long"),
+        };
+        // Ties are broken by document id so pages are stable
+        let sort = json!([
+            {
+                field: {
+                    "order": if ascending { "asc" } else { "desc" },
+                    "missing": "_last",
+                    "unmapped_type": unmapped_type
+                }
+            },
+            { "document_id": "asc" }
+        ]);
+        Ok(self
+            .search_hits(
+                account_id,
+                collection.into(),
+                query,
+                None,
+                &[],
+                Some(sort),
+                None,
+            )
+            .await?
+            .into_iter()
+            .map(|hit| hit.document_id)
+            .collect())
+    }
+
+    // Multiplies the relevance by 1 + weight * decay, where the gaussian decay is 1
+    // for a message received now and 0.5 at the scale. Queries made only of filters
+    // score zero and are left as they are.
+    fn boost_recent(&self, query: Value) -> Value {
+        match &self.recency {
+            Some(recency) => json!({
+                "function_score": {
+                    "query": query,
+                    "functions": [
+                        { "weight": 1 },
+                        {
+                            "gauss": {
+                                "received_at": {
+                                    "origin": "now",
+                                    "scale": format!("{}s", recency.scale.as_secs().max(1)),
+                                    "decay": 0.5
+                                }
+                            },
+                            "weight": recency.weight
+                        }
+                    ],
+                    "score_mode": "sum",
+                    "boost_mode": "multiply"
+                }
+            }),
+            None => query,
+        }
+    }
+
+    // Document ids are read from doc values and the response is filtered down to
+    // scores and ids, which skips loading the source and drops the index name and
+    // id of each hit. A typical hit shrinks from 98 to 53 bytes. Searches matching
+    // more documents than the result window are read again through a point in time.
+    #[allow(clippy::too_many_arguments)]
+    async fn search_hits(
+        &self,
+        account_id: u32,
+        collection: u8,
+        query: Value,
+        min_score: Option<f32>,
+        fields: &[&str],
+        sort: Option<Value>,
+        deadline: Option<Instant>,
+    ) -> crate::Result<Vec<QueryHit>> {
+        let timeout = time_left(deadline, self.request_timeout)?;
+        let search_timeout = deadline.map(|_| format!("{}ms", timeout.as_millis()));
+        let index = self.search_indices(collection, &[account_id])?;
+        let index = [index.as_str()];
+        let mut body = json!({
+            "query": query,
+            "_source": if fields.is_empty() { Value::Bool(false) } else { json!(fields) },
+            "docvalue_fields": ["document_id"]
+        });
+        if let Some(min_score) = min_score {
+            body["min_score"] = min_score.into();
+        }
+        if let Some(sort) = &sort {
+            body["sort"] = sort.clone();
+        }
+        let mut query = body.clone();
+        query["size"] = MAX_RESULT_WINDOW.into();
+        let mut filter_path = vec!["hits.hits._score", "hits.hits.fields"];
+        if !fields.is_empty() {
+            filter_path.push("hits.hits._source");
+        }
+        if !self.clusters.is_empty() {
+            filter_path.push("_clusters.skipped");
+        }
+        if search_timeout.is_some() {
+            filter_path.push("timed_out");
+        }
+        let routing = self.routing([collection], &[account_id]);
+        let routing = routing.as_deref();
+        let client = self.client();
+        let response = self
+            .send_with_retry(Operation::Search, || {
+                let request = client.search(SearchParts::Index(&index));
+                let request = match &routing {
+                    Some(routing) => request.routing(std::slice::from_ref(routing)),
+                    None => request,
+                };
+                let request = match &search_timeout {
+                    Some(search_timeout) => request.timeout(search_timeout),
+                    None => request,
+                };
+                request
+                    .filter_path(&filter_path)
+                    .request_timeout(timeout)
+                    .body(&query)
+                    .compatible_with(self.compatible_with)
+                    .send()
+            })
+            .await?;
+        let json: Value = assert_success(response, "Failed to search documents")
+            .await?
+            .json()
+            .await?;
+        log_skipped_clusters(&json);
+        if json["timed_out"].as_bool().unwrap_or_default() {
+            return Err(crate::Error::Timeout);
+        }
+
+        // Hits are returned in sort order or by descending score, the hits array is
+        // filtered out of the response when nothing matched
+        let hits = json["hits"]["hits"]
+            .as_array()
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+        if hits.len() < MAX_RESULT_WINDOW {
+            return hits.iter().map(query_hit).collect();
+        }
+
+        tracing::debug!(
+            context = "elasticsearch",
+            event = "paginate",
+            account_id = account_id,
+            "Result window exceeded, paginating with search_after"
+        );
+        body["sort"] = sort.unwrap_or_else(|| json!([{ "_score": "desc" }]));
+        let mut cursor = self
+            .open_cursor(&[account_id], collection, body, deadline)
+            .await?;
+        let mut hits = Vec::with_capacity(MAX_RESULT_WINDOW);
+        while cursor.next_page().await? {
+            hits.extend(cursor.buffer.drain(..));
+        }
+        cursor.close().await;
+        Ok(hits)
+    }
+
+    /// Returns a page of matching documents, best matches first, along with the
+    /// total number of matches.
+    pub async fn fts_query_page<T: Into<u8> + Display + Clone + std::fmt::Debug>(
+        &self,
+        account_id: u32,
+        collection: impl Into<u8>,
+        filters: Vec<FtsFilter<T>>,
+        from: usize,
+        size: usize,
+    ) -> crate::Result<QueryPage> {
+        let collection = collection.into();
+        let query = self.build_query(&[account_id], filters, true);
+        // Pages beyond the result window are read through a point in time, the
+        // search then only counts the matches
+        let deep = from.saturating_add(size) > MAX_RESULT_WINDOW;
+
+        let index = self.search_indices(collection, &[account_id])?;
+        let index = [index.as_str()];
+        let routing = self.routing([collection], &[account_id]);
+        let routing = routing.as_deref();
+        let body = json!({
+            "query": &query,
+            "from": if deep { 0 } else { from },
+            "size": if deep { 0 } else { size },
+            "track_total_hits": true,
+            "_source": ["document_id"]
+        });
+        let client = self.client();
+        let response = self
+            .send_with_retry(Operation::Search, || {
+                let request = client.search(SearchParts::Index(&index));
+                let request = match &routing {
+                    Some(routing) => request.routing(std::slice::from_ref(routing)),
+                    None => request,
+                };
+                request
+                    .request_timeout(self.request_timeout)
+                    .body(&body)
+                    .compatible_with(self.compatible_with)
+                    .send()
+            })
+            .await?;
+        let json: Value = assert_success(response, "Failed to search documents")
+            .await?
+            .json()
+            .await?;
+        log_skipped_clusters(&json);
+        let total = json["hits"]["total"]["value"].as_u64().unwrap_or(0);
+
+        if deep {
+            tracing::debug!(
+                context = "elasticsearch",
+                event = "paginate",
+                from = from,
+                size = size,
+                "Result window exceeded, paginating with search_after"
+            );
+            let stream = self
+                .fts_query_all_with_query(&[account_id], collection, query)
+                .await?
+                .skip(from)
+                .take(size);
+            let mut stream = std::pin::pin!(stream);
+            let mut document_ids = Vec::with_capacity(size.min(MAX_RESULT_WINDOW));
+            while let Some(document_id) = stream.next().await {
+                document_ids.push(document_id?);
+            }
+
+            return Ok(QueryPage {
+                document_ids,
+                total,
+            });
+        }
+
+        Ok(QueryPage {
+            document_ids: json["hits"]["hits"]
+                .as_array()
+                .ok_or_else(|| {
+                    crate::Error::InternalError("Invalid response from ElasticSearch".to_string())
+                })?
+                .iter()
+                .map(|hit| {
+                    hit["_source"]["document_id"]
+                        .as_u64()
+                        .map(|document_id| document_id as u32)
+                        .ok_or_else(|| {
+                            crate::Error::InternalError(
+                                "Invalid response from ElasticSearch".to_string(),
+                            )
+                        })
+                })
+                .collect::<crate::Result<Vec<_>>>()?,
+            total,
+        })
+    }
+
+    /// Searches documents returning the best matching document of each thread.
+    pub async fn fts_query_threads<T: Into<u8> + Display + Clone + std::fmt::Debug>(
+        &self,
+        account_id: u32,
+        collection: impl Into<u8>,
+        filters: Vec<FtsFilter<T>>,
+    ) -> crate::Result<Vec<(Option<u32>, u32)>> {
+        let collection = collection.into();
+        let index = self.search_indices(collection, &[account_id])?;
+        let index = [index.as_str()];
+        let routing = self.routing([collection], &[account_id]);
+        let routing = routing.as_deref();
+        let query = json!({
+            "query": self.build_query(&[account_id], filters, true),
+            "size": 10000,
+            "_source": ["document_id", "thread_id"],
+            "collapse": { "field": "thread" }
+        });
+        let client = self.client();
+        let response = self
+            .send_with_retry(Operation::Search, || {
+                let request = client.search(SearchParts::Index(&index));
+                let request = match &routing {
+                    Some(routing) => request.routing(std::slice::from_ref(routing)),
+                    None => request,
+                };
+                request
+                    .request_timeout(self.request_timeout)
+                    .body(&query)
+                    .compatible_with(self.compatible_with)
+                    .send()
+            })
+            .await?;
+        let json: Value = assert_success(response, "Failed to search documents")
+            .await?
+            .json()
+            .await?;
+        log_skipped_clusters(&json);
+
+        json["hits"]["hits"]
+            .as_array()
+            .ok_or_else(|| {
+                crate::Error::InternalError("Invalid response from ElasticSearch".to_string())
+            })?
+            .iter()
+            .map(|hit| {
+                let document_id = hit["_source"]["document_id"].as_u64().ok_or_else(|| {
+                    crate::Error::InternalError("Invalid response from ElasticSearch".to_string())
+                })? as u32;
+                let thread_id = hit["_source"]["thread_id"].as_u64().map(|id| id as u32);
+                Ok((thread_id, document_id))
+            })
+            .collect()
+    }
+
+    /// Searches the documents of several accounts, grouping the results by account.
+    pub async fn fts_query_accounts<T: Into<u8> + Display + Clone + std::fmt::Debug>(
+        &self,
+        account_ids: &[u32],
+        collection: impl Into<u8>,
+        filters: Vec<FtsFilter<T>>,
+    ) -> crate::Result<AHashMap<u32, RoaringBitmap>> {
+        let mut results: AHashMap<u32, RoaringBitmap> = AHashMap::new();
+        if account_ids.is_empty() {
+            return Ok(results);
+        }
+
+        // Accounts living on other clusters are searched with cross-cluster search
+        let collection = collection.into();
+        let index = self.search_indices(collection, account_ids)?;
+        let index = [index.as_str()];
+        let routing = self.routing([collection], account_ids);
+        let routing = routing.as_deref();
+        let query = json!({
+            "query": self.build_query(account_ids, filters, true),
+            "size": 10000,
+            "_source": ["account_id", "document_id"]
+        });
+        let client = self.client();
+        let response = self
+            .send_with_retry(Operation::Search, || {
+                let request = client.search(SearchParts::Index(&index));
+                let request = match &routing {
+                    Some(routing) => request.routing(std::slice::from_ref(routing)),
+                    None => request,
+                };
+                request
+                    .request_timeout(self.request_timeout)
+                    .body(&query)
+                    .compatible_with(self.compatible_with)
+                    .send()
+            })
+            .await?;
+        let json: Value = assert_success(response, "Failed to search documents")
+            .await?
+            .json()
+            .await?;
+        log_skipped_clusters(&json);
+
+        for hit in json["hits"]["hits"].as_array().ok_or_else(|| {
+            crate::Error::InternalError("Invalid response from ElasticSearch".to_string())
+        })? {
+            match (
+                hit["_source"]["account_id"].as_u64(),
+                hit["_source"]["document_id"].as_u64(),
+            ) {
+                (Some(account_id), Some(document_id)) => {
+                    results
+                        .entry(account_id as u32)
+                        .or_default()
+                        .insert(document_id as u32);
+                }
+                _ => {
+                    return Err(crate::Error::InternalError(
+                        "Invalid response from ElasticSearch".to_string(),
+                    ))
+                }
+            }
+        }
+
+        Ok(results)
+    }
+
+    /// Runs several searches in a single `_msearch` round trip, returning the
+    /// matching documents of each query in input order. Every query is scoped to
+    /// its own account, and fails the whole call when it fails.
+    pub async fn fts_multi_query<T: Into<u8> + Display + Clone + std::fmt::Debug>(
+        &self,
+        queries: Vec<(u32, u8, Vec<FtsFilter<T>>)>,
+    ) -> crate::Result<Vec<Vec<u32>>> {
+        if queries.is_empty() {
+            return Ok(vec![]);
+        }
+
+        // Each search is a header line naming the index and routing followed by its body
+        let mut lines = Vec::with_capacity(queries.len() * 2);
+        for (account_id, collection, filters) in queries {
+            let mut header = json!({ "index": self.search_indices(collection, &[account_id])? });
+            if let Some(routing) = self.routing([collection], &[account_id]) {
+                header["routing"] = routing.into();
+            }
+            lines.push(header.to_string());
+            lines.push(
+                json!({
+                    "query": self.build_query(&[account_id], filters, true),
+                    "size": 10000,
+                    "_source": false,
+                    "docvalue_fields": ["document_id"]
+                })
+                .to_string(),
+            );
+        }
+
+        let client = self.client();
+        let response = self
+            .send_with_retry(Operation::Search, || {
+                client
+                    .msearch(MsearchParts::None)
+                    .filter_path(&[
+                        "responses.hits.hits.fields",
+                        "responses.error.reason",
+                        "responses._clusters.skipped",
+                    ])
+                    .request_timeout(self.request_timeout)
+                    .body(lines.clone())
+                    .compatible_with(self.compatible_with)
+                    .send()
+            })
+            .await?;
+        let json: Value = assert_success(response, "Failed to search documents")
+            .await?
+            .json()
+            .await?;
+
+        let responses = json["responses"]
+            .as_array()
+            .filter(|responses| responses.len() * 2 == lines.len())
+            .ok_or_else(|| {
+                crate::Error::InternalError("Invalid response from ElasticSearch".to_string())
+            })?;
+        responses
+            .iter()
+            .map(|response| {
+                if let Some(reason) = response["error"]["reason"].as_str() {
+                    return Err(crate::Error::InternalError(format!(
+                        "Failed to search documents: {reason}"
+                    )));
+                }
+                log_skipped_clusters(response);
+
+                response["hits"]["hits"]
+                    .as_array()
+                    .map(Vec::as_slice)
+                    .unwrap_or_default()
+                    .iter()
+                    .map(|hit| {
+                        hit["fields"]["document_id"][0]
+                            .as_u64()
+                            .map(|document_id| document_id as u32)
+                            .ok_or_else(|| {
+                                crate::Error::InternalError(
+                                    "Invalid response from ElasticSearch".to_string(),
+                                )
+                            })
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// Returns every document matching the filters, paging through the results
+    /// with a point in time, or a scroll on OpenSearch, so the search result
+    /// window limit does not apply.
+    pub async fn fts_query_all<T: Into<u8> + Display + Clone + std::fmt::Debug>(
+        &self,
+        account_id: u32,
+        collection: impl Into<u8>,
+        filters: Vec<FtsFilter<T>>,
+    ) -> crate::Result<impl Stream<Item = crate::Result<u32>> + '_> {
+        self.fts_query_all_with_query(
+            &[account_id],
+            collection.into(),
+            self.build_query(&[account_id], filters, true),
+        )
+        .await
+    }
+
+    async fn fts_query_all_with_query(
+        &self,
+        account_ids: &[u32],
+        collection: u8,
+        query: Value,
+    ) -> crate::Result<impl Stream<Item = crate::Result<u32>> + '_> {
+        let body = json!({
+            "query": query,
+            "_source": false,
+            "docvalue_fields": ["document_id"],
+            // Same order as a regular search, so pages can continue from one to the other
+            "sort": [{ "_score": "desc" }]
+        });
+        let cursor = self
+            .open_cursor(account_ids, collection, body, None)
+            .await?;
+
+        Ok(futures::stream::unfold(Some(cursor), |cursor| async move {
+            let mut cursor = cursor?;
+            if cursor.buffer.is_empty() {
+                match cursor.next_page().await {
+                    Ok(true) => {}
+                    Ok(false) => {
+                        cursor.close().await;
+                        return None;
+                    }
+                    Err(err) => return Some((Err(err), None)),
+                }
+            }
+            let document_id = cursor.buffer.pop_front()?.document_id;
+            Some((Ok(document_id), Some(cursor)))
+        }))
+    }
+
+    // Opens a point in time to page through the hits of a search body, which has
+    // to be sorted. Ties are broken by shard and document so pages are stable.
+    // OpenSearch has a different point in time API and can't sort by shard
+    // document, so its searches are scrolled instead.
+    async fn open_cursor(
+        &self,
+        account_ids: &[u32],
+        collection: u8,
+        mut body: Value,
+        deadline: Option<Instant>,
+    ) -> crate::Result<SearchCursor<'_>> {
+        let timeout = time_left(deadline, self.request_timeout)?;
+        let index = self.search_indices(collection, account_ids)?;
+        let index = [index.as_str()];
+        // Searches through the point in time are limited to the shards it was opened on
+        let routing = self.routing([collection], account_ids);
+        let client = self.client();
+        if self.flavor() == Flavor::OpenSearch {
+            return self
+                .open_scroll(client, &index, routing, body, deadline)
+                .await;
+        }
+        let response = self
+            .send_with_retry(Operation::Search, || {
+                let request = client.open_point_in_time(OpenPointInTimeParts::Index(&index));
+                let request = match &routing {
+                    Some(routing) => request.routing(routing),
+                    None => request,
+                };
+                request
+                    .request_timeout(timeout)
+                    .keep_alive(PIT_KEEP_ALIVE)
+                    .compatible_with(self.compatible_with)
+                    .send()
+            })
+            .await?;
+        let json: Value = assert_success(response, "Failed to open point in time")
+            .await?
+            .json()
+            .await?;
+        let pit_id = json["id"]
+            .as_str()
+            .map(|id| id.to_string())
+            .ok_or_else(|| {
+                crate::Error::InternalError("Invalid response from ElasticSearch".to_string())
+            })?;
+
+        if let Some(sort) = body["sort"].as_array_mut() {
+            sort.push(json!({ "_shard_doc": "asc" }));
+        }
+        Ok(SearchCursor {
+            store: self,
+            client,
+            id: Some(pit_id),
+            scroll: false,
+            body,
+            deadline,
+            search_after: None,
+            buffer: VecDeque::new(),
+        })
+    }
+
+    // The first page of a scroll is returned by the search opening it
+    async fn open_scroll(
+        &self,
+        client: Arc<Elasticsearch>,
+        index: &[&str],
+        routing: Option<String>,
+        mut body: Value,
+        deadline: Option<Instant>,
+    ) -> crate::Result<SearchCursor<'_>> {
+        let timeout = time_left(deadline, self.request_timeout)?;
+        body["size"] = PAGE_SIZE.into();
+        if deadline.is_some() {
+            body["timeout"] = format!("{}ms", timeout.as_millis()).into();
+        }
+        let routing = routing.as_deref();
+        let response = self
+            .send_with_retry(Operation::Search, || {
+                let request = client.search(SearchParts::Index(index));
+                let request = match &routing {
+                    Some(routing) => request.routing(std::slice::from_ref(routing)),
+                    None => request,
+                };
+                request
+                    .scroll(PIT_KEEP_ALIVE)
+                    .request_timeout(timeout)
+                    .body(&body)
+                    .compatible_with(self.compatible_with)
+                    .send()
+            })
+            .await?;
+        let json: Value = assert_success(response, "Failed to search documents")
+            .await?
+            .json()
+            .await?;
+        let mut cursor = SearchCursor {
+            store: self,
+            client,
+            id: json["_scroll_id"].as_str().map(|id| id.to_string()),
+            scroll: true,
+            body: Value::Null,
+            deadline,
+            search_after: None,
+            buffer: VecDeque::new(),
+        };
+        if json["timed_out"].as_bool().unwrap_or_default() {
+            cursor.close().await;
+            return Err(crate::Error::Timeout);
+        }
+        cursor.push_hits(&json)?;
+        Ok(cursor)
+    }
+
+    pub async fn fts_query_highlight<T: Into<u8> + Display + Clone + std::fmt::Debug>(
+        &self,
+        account_id: u32,
+        collection: impl Into<u8>,
+        filters: Vec<FtsFilter<T>>,
+        fragment_size: usize,
+        max_fragments: usize,
+    ) -> crate::Result<AHashMap<u32, Vec<String>>> {
+        let collection = collection.into();
+        let index = self.search_indices(collection, &[account_id])?;
+        let index = [index.as_str()];
+        let routing = self.routing([collection], &[account_id]);
+        let routing = routing.as_deref();
+        let mut build_query = self.build_query(&[account_id], filters, true);
+        highlight_headers(&mut build_query, fragment_size, max_fragments, &mut 0);
+        let query = json!({
+                "query": build_query,
+                "size": 10000,
+                "_source": ["document_id"],
+                "highlight": {
+                    "fragment_size": fragment_size,
+                    "number_of_fragments": max_fragments,
+                    "fields": {
+                        "body": {},
+                        "body_*": {}
+                    }
+                }
+        });
+        let client = self.client();
+        let response = self
+            .send_with_retry(Operation::Search, || {
+                let request = client.search(SearchParts::Index(&index));
+                let request = match &routing {
+                    Some(routing) => request.routing(std::slice::from_ref(routing)),
+                    None => request,
+                };
+                request
+                    .request_timeout(self.request_timeout)
+                    .body(&query)
+                    .compatible_with(self.compatible_with)
+                    .send()
+            })
+            .await?;
+        let json: Value = assert_success(response, "Failed to search documents")
+            .await?
+            .json()
+            .await?;
+        let mut results = AHashMap::new();
+
+        for hit in json["hits"]["hits"].as_array().ok_or_else(|| {
+            crate::Error::InternalError("Invalid response from ElasticSearch".to_string())
+        })? {
+            let document_id = hit["_source"]["document_id"].as_u64().ok_or_else(|| {
+                crate::Error::InternalError("Invalid response from ElasticSearch".to_string())
+            })? as u32;
+
+            // Hits without highlighted fields return no fragments, header fragments
+            // are returned with the inner hits of each header condition
+            let inner_hits = hit["inner_hits"]
+                .as_object()
+                .into_iter()
+                .flat_map(|inner_hits| inner_hits.values())
+                .flat_map(|inner_hits| inner_hits["hits"]["hits"].as_array().into_iter().flatten())
+                .map(|inner_hit| &inner_hit["highlight"]);
+            let fragments = std::iter::once(&hit["highlight"])
+                .chain(inner_hits)
+                .filter_map(|highlight| highlight.as_object())
+                .flat_map(|fields| fields.values())
+                .filter_map(|fragments| fragments.as_array())
+                .flatten()
+                .filter_map(|fragment| fragment.as_str().map(|f| f.to_string()))
+                .collect::<Vec<_>>();
+            results.insert(document_id, fragments);
+        }
+
+        Ok(results)
+    }
+
+    /// Compiles a filter expression into an ElasticSearch `bool` query.
+    ///
+    /// Filters are written in prefix notation, each `And`, `Or` or `Not` opens a
+    /// group that is closed by `End`, groups left open are closed at the end:
+    ///
+    /// - `And` becomes `bool.must`, all conditions have to match.
+    /// - `Or` becomes `bool.should`, at least one condition has to match.
+    /// - `Not` becomes `bool.must_not`, none of the conditions may match.
+    /// - `Contains` becomes a `match` query (`best_fields` `multi_match` on the body),
+    ///   matching any of its terms unless a `minimum_should_match` is configured or
+    ///   the default operator is `and`. Body text matches with `and` when all the
+    ///   terms are found in a single body field.
+    /// - `Exact` becomes a `match_phrase` query (`phrase` `multi_match` on the body).
+    /// - `Phrase` becomes a `match_phrase` query allowing `slop` positions between terms.
+    /// - `SizeRange` becomes an inclusive `range` query on `size`.
+    /// - `InMailbox` becomes a `terms` query on `mailbox_ids`.
+    /// - `Prefix` and `Wildcard` become `prefix` and `wildcard` queries on the terms
+    ///   of the field, whole addresses for address headers.
+    /// - `EnvelopeFrom` and `EnvelopeTo` become a `term` query on the bare address in
+    ///   `envelope_from` and `envelope_to`.
+    /// - `HasAttachment` becomes a `term` query on `has_attachment`, documents indexed
+    ///   before the field was added match neither value until they are reindexed.
+    /// - `Flag` becomes a `term` query on the boolean field of the system flag, with
+    ///   the same caveat for documents indexed before.
+    /// - `References` becomes a `term` query on `references`, the message ids of the
+    ///   `In-Reply-To` and `References` headers.
+    /// - `Keyword` becomes a `term` query on `keywords` and a `match_phrase` query
+    ///   on analyzed fields.
+    /// - `Header` becomes a `match` query on the value of the named header.
+    ///
+    /// Conditions on attachments become `match_none` when `include_attachments` is false.
+    ///
+    /// Header conditions are `nested` queries matching both `header.name` and
+    /// `header.value` on the same header entry, so conditions on different headers
+    /// are matched independently. Address headers are matched on `header.address`
+    /// when the text contains an email address.
+    ///
+    /// The account condition is always added to the outermost group, so no filter
+    /// can match documents from other accounts.
+    pub(crate) fn build_query<T: Into<u8> + Display + Clone + std::fmt::Debug>(
+        &self,
+        account_ids: &[u32],
+        filters: Vec<FtsFilter<T>>,
+        include_attachments: bool,
+    ) -> Value {
+        self.build_query_with(
+            account_ids,
+            filters,
+            include_attachments,
+            TextMatch {
+                operator: self.default_operator,
+                minimum_should_match: self.minimum_should_match.as_deref(),
+            },
+        )
+    }
+
+    // Like `build_query`, combining the terms of each `Contains` condition as set
+    // by `text_match` instead of the configured defaults
+    fn build_query_with<T: Into<u8> + Display + Clone + std::fmt::Debug>(
+        &self,
+        account_ids: &[u32],
+        filters: Vec<FtsFilter<T>>,
+        include_attachments: bool,
+        text_match: TextMatch<'_>,
+    ) -> Value {
         let mut stack: Vec<(FtsFilter<T>, Vec<Value>)> = vec![];
-        let mut conditions = vec![json!({ "match": { "account_id": account_id } })];
+        // An empty terms query matches no documents
+        let mut conditions = vec![json!({ "terms": { "account_id": account_ids } })];
         let mut logical_op = FtsFilter::And;
 
         for filter in filters {
-            let is_exact = matches!(filter, FtsFilter::Exact { .. });
+            let match_type = match &filter {
+                FtsFilter::Contains { .. } => "match",
+                FtsFilter::Keyword {
+                    field: Field::Keyword,
+                    ..
+                } => "term",
+                // Keywords on analyzed fields have to match all their tokens in order
+                _ => "match_phrase",
+            };
+            let language = match &filter {
+                FtsFilter::Exact { language, .. }
+                | FtsFilter::Contains { language, .. }
+                | FtsFilter::Phrase { language, .. } => *language,
+                _ => Language::None,
+            };
+            let slop = match &filter {
+                FtsFilter::Phrase { slop, .. } => *slop,
+                _ => 0,
+            };
+            let text_match = match &filter {
+                FtsFilter::Contains { .. } => text_match,
+                _ => TextMatch::default(),
+            };
             match filter {
                 FtsFilter::Exact { field, text, .. }
                 | FtsFilter::Contains { field, text, .. }
-                | FtsFilter::Keyword { field, text, .. } => {
-                    let match_type = if is_exact { "term" } else { "match" };
-
-                    if let Field::Header(name) = field {
-                        conditions.push(json!({"bool": {
-                          "must": [
-                            {
-                              "term": {
-                                "header.name": name.to_string()
-                              }
-                            },
-                            {
-                                match_type: {
-                                "header.value": text
-                              }
+                | FtsFilter::Keyword { field, text, .. }
+                | FtsFilter::Phrase { field, text, .. } => {
+                    if matches!(field, Field::Attachment) && !include_attachments {
+                        conditions.push(json!({ "match_none": {} }));
+                    } else if let Field::Header(name) = field {
+                        conditions.push(self.header_query(
+                            name.to_string(),
+                            match_type,
+                            text,
+                            slop,
+                            text_match,
+                        ));
+                    } else if matches!(field, Field::Body) {
+                        // Body text is stored under a language specific field when
+                        // an analyzer is available for the language
+                        let lang_field = language_code(language)
+                            .map(|code| format!("body_{code}"))
+                            .unwrap_or_else(|| "body_*".to_string());
+                        conditions.push(if match_type == "match_phrase" {
+                            json!({
+                                "multi_match": {
+                                    "query": text,
+                                    "fields": ["body", lang_field],
+                                    "type": "phrase",
+                                    "slop": slop
+                                }
+                            })
+                        } else {
+                            let mut query = json!({
+                                "multi_match": {
+                                    "query": text,
+                                    "fields": ["body", lang_field],
+                                    "type": "best_fields"
+                                }
+                            });
+                            if text_match.operator == QueryOperator::And {
+                                query["multi_match"]["operator"] = "and".into();
+                            }
+                            if let Some(minimum_should_match) = text_match.minimum_should_match {
+                                query["multi_match"]["minimum_should_match"] =
+                                    minimum_should_match.into();
                             }
-                          ]
-                        }}));
+                            query
+                        });
+                    } else if matches!(field, Field::Keyword) {
+                        // Keywords are lowercased and folded by the index normalizer
+                        let mut text = text.to_lowercase();
+                        if self.fold_diacritics {
+                            text = fold_diacritics(&text).into_owned();
+                        }
+                        conditions.push(text_query(
+                            match_type,
+                            &field.name(),
+                            text,
+                            slop,
+                            text_match,
+                        ));
                     } else {
-                        conditions.push(json!({
-                            match_type: { field.name(): text }
-                        }));
+                        conditions.push(text_query(
+                            match_type,
+                            &field.name(),
+                            text,
+                            slop,
+                            text_match,
+                        ));
                     }
                 }
+                FtsFilter::Header { name, value } => {
+                    conditions.push(self.header_query(
+                        name,
+                        "match",
+                        value,
+                        0,
+                        TextMatch::default(),
+                    ));
+                }
+                FtsFilter::Prefix { field, value } => {
+                    conditions.push(self.pattern_query(
+                        "prefix",
+                        field,
+                        value,
+                        include_attachments,
+                    ));
+                }
+                FtsFilter::Wildcard { field, value } => {
+                    conditions.push(self.pattern_query(
+                        "wildcard",
+                        field,
+                        value,
+                        include_attachments,
+                    ));
+                }
+                FtsFilter::InMailbox(mailbox_id) => {
+                    conditions.push(json!({ "terms": { "mailbox_ids": [mailbox_id] } }));
+                }
+                FtsFilter::EnvelopeFrom(address) => {
+                    conditions.push(envelope_query("envelope_from", &address));
+                }
+                FtsFilter::EnvelopeTo(address) => {
+                    conditions.push(envelope_query("envelope_to", &address));
+                }
+                FtsFilter::HasAttachment(has_attachment) => {
+                    conditions.push(json!({ "term": { "has_attachment": has_attachment } }));
+                }
+                FtsFilter::Flag(flag, is_set) => {
+                    conditions.push(json!({ "term": { flag.name(): is_set } }));
+                }
+                FtsFilter::References(id) => {
+                    conditions.push(json!({ "term": { "references": message_id(&id) } }));
+                }
+                FtsFilter::SizeRange { min, max } => {
+                    let mut range = serde_json::Map::new();
+                    if let Some(min) = min {
+                        range.insert("gte".to_string(), min.into());
+                    }
+                    if let Some(max) = max {
+                        range.insert("lte".to_string(), max.into());
+                    }
+                    conditions.push(json!({ "range": { "size": range } }));
+                }
                 FtsFilter::And | FtsFilter::Or | FtsFilter::Not => {
                     stack.push((logical_op, conditions));
                     logical_op = filter;
@@ -78,60 +1276,661 @@ impl ElasticSearchStore {
                 }
                 FtsFilter::End => {
                     if let Some((prev_logical_op, mut prev_conditions)) = stack.pop() {
-                        if !conditions.is_empty() {
-                            match logical_op {
-                                FtsFilter::And => {
-                                    prev_conditions.push(json!({ "bool": { "must": conditions } }));
-                                }
-                                FtsFilter::Or => {
-                                    prev_conditions
-                                        .push(json!({ "bool": { "should": conditions } }));
-                                }
-                                FtsFilter::Not => {
-                                    prev_conditions
-                                        .push(json!({ "bool": { "must_not": conditions } }));
+                        close_group(logical_op, conditions, &mut prev_conditions);
+                        logical_op = prev_logical_op;
+                        conditions = prev_conditions;
+                    }
+                }
+            }
+        }
+
+        // Close any groups left open
+        while let Some((prev_logical_op, mut prev_conditions)) = stack.pop() {
+            close_group(logical_op, conditions, &mut prev_conditions);
+            logical_op = prev_logical_op;
+            conditions = prev_conditions;
+        }
+
+        json!({
+            "bool": {
+                "must": conditions,
+            }
+        })
+    }
+
+    /// Returns corrected versions of a query, ranked by score. Suggestions are
+    /// generated from the terms of all accounts, so only those matching at least
+    /// one document of the account are returned.
+    pub async fn fts_suggest(
+        &self,
+        account_id: u32,
+        collection: u8,
+        text: &str,
+    ) -> crate::Result<Vec<String>> {
+        let index = self.search_indices(collection, &[account_id])?;
+        let index = [index.as_str()];
+        let routing = self.routing([collection], &[account_id]);
+        let routing = routing.as_deref();
+        let suggester = |field: &str| {
+            let phrase = json!({ "match_phrase": { field: "{{suggestion}}" } });
+            // Headers are nested documents, which the collate query has to wrap
+            let phrase = match field.split_once('.') {
+                Some((path, _)) => json!({ "nested": { "path": path, "query": phrase } }),
+                None => phrase,
+            };
+            json!({
+                "text": text,
+                "phrase": {
+                    "field": field,
+                    "size": SUGGEST_SIZE,
+                    "direct_generator": [{ "field": field, "suggest_mode": "always" }],
+                    "collate": {
+                        "query": {
+                            "source": {
+                                "bool": {
+                                    "must": [
+                                        phrase,
+                                        { "term": { "account_id": account_id } }
+                                    ]
                                 }
-                                _ => unreachable!(),
                             }
                         }
-                        logical_op = prev_logical_op;
-                        conditions = prev_conditions;
                     }
                 }
+            })
+        };
+        let query = json!({
+            "size": 0,
+            "query": { "term": { "account_id": account_id } },
+            "suggest": {
+                "body": suggester("body"),
+                "header": suggester("header.value")
             }
+        });
+        let client = self.client();
+        let response = self
+            .send_with_retry(Operation::Search, || {
+                let request = client.search(SearchParts::Index(&index));
+                let request = match &routing {
+                    Some(routing) => request.routing(std::slice::from_ref(routing)),
+                    None => request,
+                };
+                request
+                    .request_timeout(self.request_timeout)
+                    .body(&query)
+                    .compatible_with(self.compatible_with)
+                    .send()
+            })
+            .await?;
+        let json: Value = assert_success(response, "Failed to obtain suggestions")
+            .await?
+            .json()
+            .await?;
+
+        let mut suggestions = ["body", "header"]
+            .iter()
+            .flat_map(|name| json["suggest"][name].as_array().into_iter().flatten())
+            .flat_map(|entry| entry["options"].as_array().into_iter().flatten())
+            .filter_map(|option| {
+                option["text"]
+                    .as_str()
+                    .zip(option["score"].as_f64())
+                    .map(|(text, score)| (text.to_string(), score))
+            })
+            .collect::<Vec<_>>();
+        suggestions.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+        let text = text.to_lowercase();
+        let mut seen = AHashSet::new();
+        Ok(suggestions
+            .into_iter()
+            .filter(|(suggestion, _)| *suggestion != text && seen.insert(suggestion.clone()))
+            .map(|(suggestion, _)| suggestion)
+            .take(SUGGEST_SIZE)
+            .collect())
+    }
+
+    /// Counts the documents of each keyword, keywords are returned lowercased.
+    pub async fn fts_keyword_counts(
+        &self,
+        account_id: u32,
+        collection: u8,
+        max_buckets: usize,
+    ) -> crate::Result<Vec<(String, u64)>> {
+        let index = self.search_indices(collection, &[account_id])?;
+        let index = [index.as_str()];
+        let routing = self.routing([collection], &[account_id]);
+        let routing = routing.as_deref();
+        let query = json!({
+            "query": {
+                "bool": {
+                    "must": [
+                        { "match": { "account_id": account_id } },
+                    ]
+                }
+            },
+            "size": 0,
+            "aggs": {
+                "keywords": {
+                    "terms": {
+                        "field": "keywords",
+                        "size": max_buckets
+                    }
+                }
+            }
+        });
+        let client = self.client();
+        let response = self
+            .send_with_retry(Operation::Search, || {
+                let request = client.search(SearchParts::Index(&index));
+                let request = match &routing {
+                    Some(routing) => request.routing(std::slice::from_ref(routing)),
+                    None => request,
+                };
+                request
+                    .request_timeout(self.request_timeout)
+                    .body(&query)
+                    .compatible_with(self.compatible_with)
+                    .send()
+            })
+            .await?;
+        let json: Value = assert_success(response, "Failed to aggregate keywords")
+            .await?
+            .json()
+            .await?;
+
+        json["aggregations"]["keywords"]["buckets"]
+            .as_array()
+            .ok_or_else(|| {
+                crate::Error::InternalError("Invalid response from ElasticSearch".to_string())
+            })?
+            .iter()
+            .map(|bucket| {
+                bucket["key"]
+                    .as_str()
+                    .zip(bucket["doc_count"].as_u64())
+                    .map(|(keyword, count)| (keyword.to_string(), count))
+                    .ok_or_else(|| {
+                        crate::Error::InternalError(
+                            "Invalid response from ElasticSearch".to_string(),
+                        )
+                    })
+            })
+            .collect()
+    }
+
+    pub async fn fts_count(&self, account_id: u32, collection: Option<u8>) -> crate::Result<u64> {
+        let (index_names, routing) = if let Some(collection) = collection {
+            (
+                vec![self.search_index(collection)?],
+                self.routing([collection], &[account_id]),
+            )
+        } else {
+            (
+                self.index_names(),
+                self.routing(0..INDEX_NAMES.len() as u8, &[account_id]),
+            )
+        };
+        let index_names = index_names.iter().map(String::as_str).collect::<Vec<_>>();
+
+        self.count_query(
+            account_id,
+            routing,
+            &index_names,
+            json!({
+                "bool": {
+                    "must": [
+                        { "match": { "account_id": account_id } },
+                    ]
+                }
+            }),
+        )
+        .await
+    }
+
+    /// Returns the number of documents of an account across all collections and
+    /// the total size of the indexed messages, summed from their `size` field.
+    /// Documents indexed without a size are counted but add no bytes. Accounts
+    /// without documents have a footprint of zero.
+    pub async fn fts_account_footprint(&self, account_id: u32) -> crate::Result<(u64, u64)> {
+        let index_names = self.index_names();
+        let index_names = index_names.iter().map(String::as_str).collect::<Vec<_>>();
+        let routing = self.routing(0..INDEX_NAMES.len() as u8, &[account_id]);
+        let routing = routing.as_deref();
+        let query = json!({
+            "query": { "term": { "account_id": account_id } },
+            "size": 0,
+            "track_total_hits": true,
+            "aggs": {
+                "size": { "sum": { "field": "size" } }
+            }
+        });
+        let client = self.client_for(account_id);
+        let response = self
+            .send_with_retry(Operation::Search, || {
+                let request = client.search(SearchParts::Index(&index_names));
+                let request = match &routing {
+                    Some(routing) => request.routing(std::slice::from_ref(routing)),
+                    None => request,
+                };
+                request
+                    .ignore_unavailable(true)
+                    .allow_no_indices(true)
+                    .filter_path(&["hits.total.value", "aggregations.size.value"])
+                    .request_timeout(self.request_timeout)
+                    .body(&query)
+                    .compatible_with(self.compatible_with)
+                    .send()
+            })
+            .await?;
+        if response.status_code() == StatusCode::NOT_FOUND {
+            return Ok((0, 0));
         }
+        let json: Value = assert_success(response, "Failed to obtain account footprint")
+            .await?
+            .json()
+            .await?;
 
-        // TODO implement pagination
+        // Sums are returned as floating point numbers
+        Ok((
+            json["hits"]["total"]["value"].as_u64().unwrap_or(0),
+            json["aggregations"]["size"]["value"]
+                .as_f64()
+                .map_or(0, |size| size as u64),
+        ))
+    }
+
+    fn header_query(
+        &self,
+        name: String,
+        match_type: &str,
+        text: String,
+        slop: u32,
+        text_match: TextMatch<'_>,
+    ) -> Value {
+        // Addresses are matched as a whole against the address analyzer
+        let (value_field, text) =
+            if text.contains('@') && self.address_headers.contains(&name.to_ascii_lowercase()) {
+                ("header.address", bare_address(&text).to_string())
+            } else {
+                ("header.value", text)
+            };
+
+        json!({"nested": {
+          "path": "header",
+          "query": {"bool": {
+            "must": [
+              {
+                "term": {
+                  "header.name": {
+                    "value": name,
+                    "case_insensitive": true
+                  }
+                }
+              },
+              text_query(match_type, value_field, text, slop, text_match)
+            ]
+          }}
+        }})
+    }
+
+    // Patterns are not analyzed, so they are lowercased to match the indexed terms.
+    // Address headers match whole addresses, "alice*" matches "alice@example.com",
+    // other fields match single words. Leading wildcards have to visit every term of
+    // the field, their expansion is capped to the most frequent terms.
+    fn pattern_query<T: Into<u8> + Display + Clone + std::fmt::Debug>(
+        &self,
+        query_type: &str,
+        field: Field<T>,
+        value: String,
+        include_attachments: bool,
+    ) -> Value {
+        let mut pattern = json!({ "value": value.to_lowercase() });
+        if query_type == "wildcard" && value.starts_with(['*', '?']) {
+            tracing::warn!(
+                context = "elasticsearch",
+                event = "slow-query",
+                pattern = value,
+                "Leading wildcard queries are expensive, limiting the terms matched"
+            );
+            pattern["rewrite"] = format!("top_terms_{MAX_PATTERN_TERMS}").into();
+        }
+
+        match field {
+            Field::Attachment if !include_attachments => json!({ "match_none": {} }),
+            Field::Header(name) => {
+                let name = name.to_string();
+                let value_field = if self.address_headers.contains(&name.to_ascii_lowercase()) {
+                    "header.address"
+                } else {
+                    "header.value"
+                };
+                json!({"nested": {
+                  "path": "header",
+                  "query": {"bool": {
+                    "must": [
+                      {
+                        "term": {
+                          "header.name": {
+                            "value": name,
+                            "case_insensitive": true
+                          }
+                        }
+                      },
+                      { query_type: { value_field: pattern } }
+                    ]
+                  }}
+                }})
+            }
+            // Body text is stored under the field of its language
+            Field::Body => json!({
+                "bool": {
+                    "should": std::iter::once("body".to_string())
+                        .chain(
+                            LANGUAGE_ANALYZERS
+                                .iter()
+                                .map(|(_, code, _)| format!("body_{code}")),
+                        )
+                        .map(|field| json!({ query_type: { field: &pattern } }))
+                        .collect::<Vec<_>>(),
+                    "minimum_should_match": 1
+                }
+            }),
+            field => json!({ query_type: { field.name(): pattern } }),
+        }
+    }
+
+    /// Returns whether a document is indexed, a missing index contains no documents.
+    pub async fn fts_exists(
+        &self,
+        account_id: u32,
+        collection: u8,
+        document_id: u32,
+    ) -> crate::Result<bool> {
+        let index = self.search_index(collection)?;
+
+        // Documents in data streams can't be looked up by id without the backing index
+        if self.is_data_stream(collection) {
+            return self
+                .count_query(
+                    account_id,
+                    self.routing([collection], &[account_id]),
+                    &[index.as_str()],
+                    json!({
+                        "bool": {
+                            "must": [
+                                { "term": { "account_id": account_id } },
+                                { "term": { "document_id": document_id } }
+                            ]
+                        }
+                    }),
+                )
+                .await
+                .map(|count| count > 0);
+        }
+
+        let id = document_key(account_id, document_id);
+        let routing = self.routing([collection], &[account_id]);
+        let client = self.client_for(account_id);
+        let response = self
+            .send_with_retry(Operation::Search, || {
+                let request = client.exists(ExistsParts::IndexId(&index, &id));
+                let request = match &routing {
+                    Some(routing) => request.routing(routing),
+                    None => request,
+                };
+                request
+                    .request_timeout(self.request_timeout)
+                    .compatible_with(self.compatible_with)
+                    .send()
+            })
+            .await?;
+
+        match response.status_code() {
+            StatusCode::OK => Ok(true),
+            StatusCode::NOT_FOUND => Ok(false),
+            _ => Err(crate::Error::InternalError(format!(
+                "Failed to check document: {}",
+                ElasticError::from_response(response).await
+            ))),
+        }
+    }
+
+    /// Returns the document as stored by ElasticSearch, for inspecting what was
+    /// indexed for a message. Fields excluded from the stored source are empty.
+    pub async fn fts_get(
+        &self,
+        account_id: u32,
+        collection: u8,
+        document_id: u32,
+    ) -> crate::Result<Option<Document<'static>>> {
+        let index = self.search_index(collection)?;
+
+        // Documents in data streams can't be looked up by id without the backing index
+        if self.is_data_stream(collection) {
+            let query = json!({
+                "bool": {
+                    "filter": [
+                        { "term": { "account_id": account_id } },
+                        { "term": { "document_id": document_id } }
+                    ]
+                }
+            });
+            return self
+                .search_hits(account_id, collection, query, None, &["*"], None, None)
+                .await?
+                .into_iter()
+                .next()
+                .map(|hit| serde_json::from_value(Value::Object(hit.fields)))
+                .transpose()
+                .map_err(Into::into);
+        }
+
+        let id = document_key(account_id, document_id);
+        let routing = self.routing([collection], &[account_id]);
+        let client = self.client_for(account_id);
         let response = self
-            .index
-            .search(SearchParts::Index(&[
-                INDEX_NAMES[collection.into() as usize]
-            ]))
-            .body(json!({
-                "query": {
+            .send_with_retry(Operation::Search, || {
+                let request = client.get(GetParts::IndexId(&index, &id));
+                let request = match &routing {
+                    Some(routing) => request.routing(routing),
+                    None => request,
+                };
+                request
+                    .request_timeout(self.request_timeout)
+                    .compatible_with(self.compatible_with)
+                    .send()
+            })
+            .await?;
+        if response.status_code() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let mut json: Value = assert_success(response, "Failed to obtain document")
+            .await?
+            .json()
+            .await?;
+
+        match json["_source"].take() {
+            Value::Null => Ok(None),
+            source => serde_json::from_value(source).map(Some).map_err(Into::into),
+        }
+    }
+
+    /// Returns whether each document is indexed, in the order of `document_ids`, so
+    /// migrations can skip the documents they already sent. A missing index
+    /// contains no documents.
+    pub async fn fts_exists_bulk(
+        &self,
+        account_id: u32,
+        collection: u8,
+        document_ids: &[u32],
+    ) -> crate::Result<Vec<bool>> {
+        let index = self.search_index(collection)?;
+        let mut exists = Vec::with_capacity(document_ids.len());
+
+        // Documents in data streams can't be looked up by id without the backing index
+        if self.is_data_stream(collection) {
+            for chunk in document_ids.chunks(MAX_RESULT_WINDOW) {
+                let query = json!({
                     "bool": {
-                        "must": conditions,
+                        "filter": [
+                            { "term": { "account_id": account_id } },
+                            { "terms": { "document_id": chunk } }
+                        ]
                     }
-                },
-                "size": 10000,
-                "_source": ["document_id"]
-            }))
-            .send()
-            .await?
-            .error_for_status_code()?;
+                });
+                let found = self
+                    .search_hits(account_id, collection, query, None, &[], None, None)
+                    .await?
+                    .into_iter()
+                    .map(|hit| hit.document_id)
+                    .collect::<AHashSet<_>>();
+                exists.extend(chunk.iter().map(|document_id| found.contains(document_id)));
+            }
+            return Ok(exists);
+        }
 
-        let json: Value = response.json().await?;
-        let mut results = RoaringBitmap::new();
+        let index = [index.as_str()];
+        let routing = self.routing([collection], &[account_id]);
+        for chunk in document_ids.chunks(PAGE_SIZE) {
+            let body = json!({
+                "ids": chunk
+                    .iter()
+                    .map(|document_id| document_key(account_id, *document_id))
+                    .collect::<Vec<_>>()
+            });
+            let client = self.client_for(account_id);
+            let response = self
+                .send_with_retry(Operation::Search, || {
+                    let request = client.mget(MgetParts::Index(index[0]));
+                    let request = match &routing {
+                        Some(routing) => request.routing(routing),
+                        None => request,
+                    };
+                    request
+                        ._source(&["false"])
+                        .filter_path(&["docs.found"])
+                        .request_timeout(self.request_timeout)
+                        .body(&body)
+                        .compatible_with(self.compatible_with)
+                        .send()
+                })
+                .await?;
+            if response.status_code() == StatusCode::NOT_FOUND {
+                exists.resize(document_ids.len(), false);
+                return Ok(exists);
+            }
+            let json: Value = assert_success(response, "Failed to check documents")
+                .await?
+                .json()
+                .await?;
 
-        for hit in json["hits"]["hits"].as_array().ok_or_else(|| {
+            // Documents are returned in request order, those in a missing index
+            // have an error instead of "found"
+            let docs = json["docs"]
+                .as_array()
+                .map(Vec::as_slice)
+                .unwrap_or_default();
+            exists.extend(
+                (0..chunk.len()).map(|pos| docs.get(pos).is_some_and(|doc| doc["found"] == true)),
+            );
+        }
+
+        Ok(exists)
+    }
+
+    // Counts are sent to the home cluster of the account, the count API does not
+    // support cross-cluster search
+    pub(super) async fn count_query(
+        &self,
+        account_id: u32,
+        routing: Option<String>,
+        index_names: &[&str],
+        query: Value,
+    ) -> crate::Result<u64> {
+        let query = json!({ "query": query });
+        let routing = routing.as_deref();
+        let client = self.client_for(account_id);
+        let response = self
+            .send_with_retry(Operation::Search, || {
+                let request = client.count(CountParts::Index(index_names));
+                let request = match &routing {
+                    Some(routing) => request.routing(std::slice::from_ref(routing)),
+                    None => request,
+                };
+                request
+                    .request_timeout(self.request_timeout)
+                    .body(&query)
+                    .compatible_with(self.compatible_with)
+                    .send()
+            })
+            .await?;
+        let json: Value = assert_success(response, "Failed to count documents")
+            .await?
+            .json()
+            .await?;
+        json["count"].as_u64().ok_or_else(|| {
             crate::Error::InternalError("Invalid response from ElasticSearch".to_string())
-        })? {
-            results.insert(hit["_source"]["document_id"].as_u64().ok_or_else(|| {
-                crate::Error::InternalError("Invalid response from ElasticSearch".to_string())
-            })? as u32);
+        })
+    }
+}
+
+#[cfg(feature = "explain")]
+impl ElasticSearchStore {
+    /// Explains how a document scores against the filters, or why it does not
+    /// match them, returning whether it matched and the scoring breakdown. Each
+    /// explanation is computed by re-running the query on the document, so this
+    /// is meant for tuning relevance and not for serving searches. Documents that
+    /// are not indexed and collections stored in data streams are an error.
+    pub async fn fts_explain<T: Into<u8> + Display + Clone + std::fmt::Debug>(
+        &self,
+        account_id: u32,
+        collection: u8,
+        document_id: u32,
+        filters: Vec<FtsFilter<T>>,
+    ) -> crate::Result<(bool, Explanation)> {
+        let index = self.search_index(collection)?;
+        if self.is_data_stream(collection) {
+            return Err(crate::Error::InternalError(
+                "Documents in data streams can't be explained".to_string(),
+            ));
         }
 
-        Ok(results)
+        // Searches boost recent messages, so explanations include the boost as well
+        let query = json!({
+            "query": self.boost_recent(self.build_query(&[account_id], filters, true))
+        });
+        let id = document_key(account_id, document_id);
+        let routing = self.routing([collection], &[account_id]);
+        let client = self.client_for(account_id);
+        let response = self
+            .send_with_retry(Operation::Search, || {
+                let request = client.explain(ExplainParts::IndexId(&index, &id));
+                let request = match &routing {
+                    Some(routing) => request.routing(routing),
+                    None => request,
+                };
+                request
+                    .request_timeout(self.request_timeout)
+                    .body(&query)
+                    .compatible_with(self.compatible_with)
+                    .send()
+            })
+            .await?;
+        if response.status_code() == StatusCode::NOT_FOUND {
+            return Err(crate::Error::InternalError(format!(
+                "Document {document_id} of account {account_id} is not indexed"
+            )));
+        }
+        let mut json: Value = assert_success(response, "Failed to explain document")
+            .await?
+            .json()
+            .await?;
+
+        Ok((
+            json["matched"].as_bool().unwrap_or(false),
+            serde_json::from_value(json["explanation"].take())?,
+        ))
     }
 }
 
@@ -140,8 +1939,264 @@ impl<T: Into<u8> + Display + Clone + std::fmt::Debug> Field<T> {
         match self {
             Field::Header(name) => format!("header.{name}").into(),
             Field::Body => "body".into(),
-            Field::Attachment => "attachment".into(),
-            Field::Keyword => "keyword".into(),
+            Field::Attachment => "attachments".into(),
+            Field::Keyword => "keywords".into(),
+        }
+    }
+}
+
+// Highlights of nested fields are only returned through inner hits, which need a
+// unique name for each header condition of the query
+fn highlight_headers(
+    query: &mut Value,
+    fragment_size: usize,
+    max_fragments: usize,
+    count: &mut usize,
+) {
+    match query {
+        Value::Object(object) => {
+            if let Some(nested) = object.get_mut("nested") {
+                *count += 1;
+                nested["inner_hits"] = json!({
+                    "name": format!("header_{count}"),
+                    "_source": false,
+                    "highlight": {
+                        "fragment_size": fragment_size,
+                        "number_of_fragments": max_fragments,
+                        "fields": { "header.value": {}, "header.address": {} }
+                    }
+                });
+            } else {
+                for value in object.values_mut() {
+                    highlight_headers(value, fragment_size, max_fragments, count);
+                }
+            }
+        }
+        Value::Array(values) => {
+            for value in values {
+                highlight_headers(value, fragment_size, max_fragments, count);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn text_query(
+    match_type: &str,
+    field: &str,
+    text: String,
+    slop: u32,
+    text_match: TextMatch<'_>,
+) -> Value {
+    if match_type == "match_phrase" && slop > 0 {
+        json!({ match_type: { field: { "query": text, "slop": slop } } })
+    } else if match_type == "match"
+        && (text_match.operator == QueryOperator::And || text_match.minimum_should_match.is_some())
+    {
+        let mut query = json!({ "query": text });
+        if text_match.operator == QueryOperator::And {
+            query["operator"] = "and".into();
+        }
+        if let Some(minimum_should_match) = text_match.minimum_should_match {
+            query["minimum_should_match"] = minimum_should_match.into();
+        }
+        json!({ match_type: { field: query } })
+    } else {
+        json!({ match_type: { field: text } })
+    }
+}
+
+// Envelope addresses are stored bare and lowercase, the null sender matches nothing
+fn envelope_query(field: &str, address: &str) -> Value {
+    match envelope_address(address) {
+        Some(address) => json!({ "term": { field: address } }),
+        None => json!({ "match_none": {} }),
+    }
+}
+
+fn close_group<T: Into<u8> + Display + Clone + std::fmt::Debug>(
+    logical_op: FtsFilter<T>,
+    conditions: Vec<Value>,
+    parent: &mut Vec<Value>,
+) {
+    if !conditions.is_empty() {
+        match logical_op {
+            FtsFilter::And => {
+                parent.push(json!({ "bool": { "must": conditions } }));
+            }
+            FtsFilter::Or => {
+                parent.push(json!({ "bool": { "should": conditions, "minimum_should_match": 1 } }));
+            }
+            FtsFilter::Not => {
+                parent.push(json!({ "bool": { "must_not": conditions } }));
+            }
+            _ => unreachable!(),
         }
     }
 }
+
+impl SearchCursor<'_> {
+    async fn next_page(&mut self) -> crate::Result<bool> {
+        if self.scroll {
+            return self.next_scroll_page().await;
+        }
+        let timeout = time_left(self.deadline, self.store.request_timeout)?;
+        let mut request = self.body.clone();
+        request["size"] = PAGE_SIZE.into();
+        request["pit"] = json!({
+            "id": &self.id,
+            "keep_alive": PIT_KEEP_ALIVE
+        });
+        if self.deadline.is_some() {
+            request["timeout"] = format!("{}ms", timeout.as_millis()).into();
+        }
+        if let Some(search_after) = self.search_after.take() {
+            request["search_after"] = search_after;
+        }
+
+        let response = self
+            .store
+            .send_with_retry(Operation::Search, || {
+                self.client
+                    .search(SearchParts::None)
+                    .request_timeout(timeout)
+                    .body(&request)
+                    .compatible_with(self.store.compatible_with)
+                    .send()
+            })
+            .await?;
+        let json: Value = assert_success(response, "Failed to search documents")
+            .await?
+            .json()
+            .await?;
+        if json["timed_out"].as_bool().unwrap_or_default() {
+            return Err(crate::Error::Timeout);
+        }
+
+        // The point in time id may change between requests
+        if let Some(pit_id) = json["pit_id"].as_str() {
+            self.id = Some(pit_id.to_string());
+        }
+        self.push_hits(&json)?;
+
+        Ok(!self.buffer.is_empty())
+    }
+
+    // Hits left from the page opening the scroll are returned before the next one
+    async fn next_scroll_page(&mut self) -> crate::Result<bool> {
+        if !self.buffer.is_empty() {
+            return Ok(true);
+        }
+        let Some(scroll_id) = &self.id else {
+            return Ok(false);
+        };
+        let timeout = time_left(self.deadline, self.store.request_timeout)?;
+        let request = json!({ "scroll": PIT_KEEP_ALIVE, "scroll_id": scroll_id });
+        let response = self
+            .store
+            .send_with_retry(Operation::Search, || {
+                self.client
+                    .scroll(ScrollParts::None)
+                    .request_timeout(timeout)
+                    .body(&request)
+                    .compatible_with(self.store.compatible_with)
+                    .send()
+            })
+            .await?;
+        let json: Value = assert_success(response, "Failed to search documents")
+            .await?
+            .json()
+            .await?;
+        if json["timed_out"].as_bool().unwrap_or_default() {
+            return Err(crate::Error::Timeout);
+        }
+
+        // The scroll id may change between requests
+        if let Some(scroll_id) = json["_scroll_id"].as_str() {
+            self.id = Some(scroll_id.to_string());
+        }
+        self.push_hits(&json)?;
+
+        Ok(!self.buffer.is_empty())
+    }
+
+    fn push_hits(&mut self, json: &Value) -> crate::Result<()> {
+        let hits = json["hits"]["hits"].as_array().ok_or_else(|| {
+            crate::Error::InternalError("Invalid response from ElasticSearch".to_string())
+        })?;
+        for hit in hits {
+            self.buffer.push_back(query_hit(hit)?);
+        }
+        self.search_after = hits.last().map(|hit| hit["sort"].clone());
+        Ok(())
+    }
+
+    async fn close(&mut self) {
+        if let Some(id) = self.id.take() {
+            if let Err(err) =
+                release_cursor(&self.client, id, self.scroll, self.store.compatible_with).await
+            {
+                tracing::debug!(
+                    context = "elasticsearch",
+                    event = "error",
+                    reason = %err,
+                    "Failed to close point in time"
+                );
+            }
+        }
+    }
+}
+
+impl Drop for SearchCursor<'_> {
+    fn drop(&mut self) {
+        // Streams dropped before completion release the point in time in the background
+        if let (Some(id), Ok(handle)) = (self.id.take(), tokio::runtime::Handle::try_current()) {
+            let client = self.client.clone();
+            let scroll = self.scroll;
+            let compatible_with = self.store.compatible_with;
+            handle.spawn(async move {
+                let _ = release_cursor(&client, id, scroll, compatible_with).await;
+            });
+        }
+    }
+}
+
+fn query_hit(hit: &Value) -> crate::Result<QueryHit> {
+    let document_id = hit["fields"]["document_id"][0].as_u64().ok_or_else(|| {
+        crate::Error::InternalError("Invalid response from ElasticSearch".to_string())
+    })? as u32;
+    Ok(QueryHit {
+        document_id,
+        score: hit["_score"].as_f64().unwrap_or(0.0) as f32,
+        fields: hit["_source"].as_object().cloned().unwrap_or_default(),
+    })
+}
+
+async fn release_cursor(
+    client: &Elasticsearch,
+    id: String,
+    scroll: bool,
+    compatible_with: Option<u8>,
+) -> crate::Result<()> {
+    let response = if scroll {
+        client
+            .clear_scroll(ClearScrollParts::None)
+            .body(json!({ "scroll_id": [id] }))
+            .compatible_with(compatible_with)
+            .send()
+            .await?
+    } else {
+        client
+            .close_point_in_time()
+            .body(json!({ "id": id }))
+            .compatible_with(compatible_with)
+            .send()
+            .await?
+    };
+    let context = if scroll {
+        "Failed to clear scroll"
+    } else {
+        "Failed to close point in time"
+    };
+    assert_success(response, context).await.map(|_| ())
+}
@@ -328,6 +328,7 @@ impl From<crate::Error> for String {
         match err {
             crate::Error::InternalError(err) => err,
             crate::Error::AssertValueFailed => unimplemented!(),
+            crate::Error::Timeout => "Request timed out".to_string(),
         }
     }
 }
@@ -32,6 +32,9 @@ use crate::{
 
 use super::DocumentSet;
 
+#[cfg(feature = "elastic")]
+use crate::backend::elastic::RefreshPolicy;
+
 impl FtsStore {
     pub async fn index<T: Into<u8> + Display + Clone + std::fmt::Debug>(
         &self,
@@ -40,7 +43,9 @@ impl FtsStore {
         match self {
             FtsStore::Store(store) => store.fts_index(document).await,
             #[cfg(feature = "elastic")]
-            FtsStore::ElasticSearch(store) => store.fts_index(document).await,
+            FtsStore::ElasticSearch(store) => {
+                store.fts_index(document, RefreshPolicy::default()).await
+            }
         }
     }
 
@@ -54,7 +59,7 @@ impl FtsStore {
             FtsStore::Store(store) => store.fts_query(account_id, collection, filters).await,
             #[cfg(feature = "elastic")]
             FtsStore::ElasticSearch(store) => {
-                store.fts_query(account_id, collection, filters).await
+                store.fts_query(account_id, collection, filters, true).await
             }
         }
     }
@@ -69,7 +74,14 @@ impl FtsStore {
             FtsStore::Store(store) => store.fts_remove(account_id, collection, document_ids).await,
             #[cfg(feature = "elastic")]
             FtsStore::ElasticSearch(store) => {
-                store.fts_remove(account_id, collection, document_ids).await
+                store
+                    .fts_remove(
+                        account_id,
+                        collection,
+                        document_ids,
+                        RefreshPolicy::default(),
+                    )
+                    .await
             }
         }
     }
@@ -46,37 +46,67 @@ use crate::{
 use super::{postings::Postings, Field};
 pub const TERM_INDEX_VERSION: u8 = 1;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub(crate) struct Text<'x, T: Into<u8> + Display + Clone + std::fmt::Debug> {
     pub field: Field<T>,
     pub text: Cow<'x, str>,
     pub typ: Type,
+    // Text from a message embedded as a message/rfc822 part
+    pub embedded: bool,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub(crate) enum Type {
     Text(Language),
     Tokenize,
     Keyword,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
+pub(crate) struct AttachmentInfo<'x> {
+    pub filename: Option<Cow<'x, str>>,
+    pub content_type: Option<Cow<'x, str>>,
+}
+
+#[derive(Debug, Clone)]
 pub struct FtsDocument<'x, T: Into<u8> + Display + Clone + std::fmt::Debug> {
     pub(crate) parts: Vec<Text<'x, T>>,
+    pub(crate) attachments: Vec<AttachmentInfo<'x>>,
+    // Contents of attachments without text, for stores that extract it themselves
+    pub(crate) attachment_data: Vec<Cow<'x, [u8]>>,
     pub(crate) default_language: Language,
     pub(crate) account_id: u32,
     pub(crate) collection: u8,
     pub(crate) document_id: u32,
+    pub(crate) received_at: Option<i64>,
+    pub(crate) size: Option<u64>,
+    pub(crate) thread_id: Option<u32>,
+    pub(crate) mailbox_ids: Vec<u32>,
+    // SMTP envelope addresses, which may differ from the message headers
+    pub(crate) envelope_from: Option<Cow<'x, str>>,
+    pub(crate) envelope_to: Vec<Cow<'x, str>>,
+    pub(crate) version: Option<u64>,
+    pub(crate) embedded: bool,
 }
 
 impl<'x, T: Into<u8> + Display + Clone + std::fmt::Debug> FtsDocument<'x, T> {
     pub fn with_default_language(default_language: Language) -> FtsDocument<'x, T> {
         FtsDocument {
             parts: vec![],
+            attachments: vec![],
+            attachment_data: vec![],
             default_language,
             account_id: 0,
             document_id: 0,
             collection: 0,
+            received_at: None,
+            size: None,
+            thread_id: None,
+            mailbox_ids: vec![],
+            envelope_from: None,
+            envelope_to: vec![],
+            version: None,
+            embedded: false,
         }
     }
 
@@ -95,11 +125,50 @@ impl<'x, T: Into<u8> + Display + Clone + std::fmt::Debug> FtsDocument<'x, T> {
         self
     }
 
+    pub fn with_received_at(mut self, received_at: i64) -> Self {
+        self.received_at = Some(received_at);
+        self
+    }
+
+    pub fn with_size(mut self, size: u64) -> Self {
+        self.size = Some(size);
+        self
+    }
+
+    pub fn with_thread_id(mut self, thread_id: u32) -> Self {
+        self.thread_id = Some(thread_id);
+        self
+    }
+
+    pub fn with_mailbox_ids(mut self, mailbox_ids: impl IntoIterator<Item = u32>) -> Self {
+        self.mailbox_ids = mailbox_ids.into_iter().collect();
+        self
+    }
+
+    pub fn with_envelope_from(mut self, envelope_from: impl Into<Cow<'x, str>>) -> Self {
+        self.envelope_from = Some(envelope_from.into());
+        self
+    }
+
+    pub fn with_envelope_to(
+        mut self,
+        envelope_to: impl IntoIterator<Item = impl Into<Cow<'x, str>>>,
+    ) -> Self {
+        self.envelope_to = envelope_to.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn with_version(mut self, version: u64) -> Self {
+        self.version = Some(version);
+        self
+    }
+
     pub fn index(&mut self, field: Field<T>, text: impl Into<Cow<'x, str>>, language: Language) {
         self.parts.push(Text {
             field,
             text: text.into(),
             typ: Type::Text(language),
+            embedded: self.embedded,
         });
     }
 
@@ -108,6 +177,7 @@ impl<'x, T: Into<u8> + Display + Clone + std::fmt::Debug> FtsDocument<'x, T> {
             field,
             text: text.into(),
             typ: Type::Tokenize,
+            embedded: self.embedded,
         });
     }
 
@@ -116,8 +186,36 @@ impl<'x, T: Into<u8> + Display + Clone + std::fmt::Debug> FtsDocument<'x, T> {
             field,
             text: text.into(),
             typ: Type::Keyword,
+            embedded: self.embedded,
         });
     }
+
+    /// Marks the text indexed from now on as belonging to an embedded message.
+    pub fn set_embedded(&mut self, embedded: bool) {
+        self.embedded = embedded;
+    }
+
+    pub fn index_attachment(
+        &mut self,
+        filename: Option<Cow<'x, str>>,
+        content_type: Option<Cow<'x, str>>,
+    ) {
+        if filename.is_some() || content_type.is_some() {
+            self.attachments.push(AttachmentInfo {
+                filename,
+                content_type,
+            });
+        }
+    }
+
+    /// Adds the raw contents of a binary attachment, which is only indexed by the
+    /// ElasticSearch store when attachment text is extracted by an ingest pipeline.
+    pub fn index_attachment_data(&mut self, data: impl Into<Cow<'x, [u8]>>) {
+        let data = data.into();
+        if !data.is_empty() {
+            self.attachment_data.push(data);
+        }
+    }
 }
 
 impl<T: Into<u8> + Display + Clone + std::fmt::Debug> From<Field<T>> for u8 {
@@ -141,7 +239,11 @@ impl Store {
         let mut parts = Vec::new();
         let mut position = 0;
 
-        for text in document.parts {
+        for mut text in document.parts {
+            // Embedded messages are searchable as attachment text
+            if text.embedded && !matches!(text.field, Field::Keyword) {
+                text.field = Field::Attachment;
+            }
             match text.typ {
                 Type::Text(language) => {
                     let language = if language == Language::Unknown {
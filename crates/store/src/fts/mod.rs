@@ -37,7 +37,17 @@ pub enum Field<T: Into<u8> + Display + Clone + std::fmt::Debug> {
     Keyword,
 }
 
-#[derive(Debug, PartialEq, Eq)]
+// IMAP system flags, indexed as keywords named after their JMAP counterpart
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum SystemFlag {
+    Seen,
+    Answered,
+    Flagged,
+    Draft,
+    Deleted,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum FtsFilter<T: Into<u8> + Display + Clone + std::fmt::Debug> {
     Exact {
         field: Field<T>,
@@ -53,6 +63,40 @@ pub enum FtsFilter<T: Into<u8> + Display + Clone + std::fmt::Debug> {
         field: Field<T>,
         text: String,
     },
+    Phrase {
+        field: Field<T>,
+        text: String,
+        language: Language,
+        slop: u32,
+    },
+    // Terms starting with the value, or matching a pattern where `*` matches any
+    // characters and `?` a single character
+    Prefix {
+        field: Field<T>,
+        value: String,
+    },
+    Wildcard {
+        field: Field<T>,
+        value: String,
+    },
+    SizeRange {
+        min: Option<u64>,
+        max: Option<u64>,
+    },
+    // Header names are matched case insensitively
+    Header {
+        name: String,
+        value: String,
+    },
+    InMailbox(u32),
+    // SMTP envelope sender (MAIL FROM) and recipients (RCPT TO)
+    EnvelopeFrom(String),
+    EnvelopeTo(String),
+    HasAttachment(bool),
+    // Messages with the system flag set, or without it when false
+    Flag(SystemFlag, bool),
+    // Messages replying to or referencing the message id
+    References(String),
     And,
     Or,
     Not,
@@ -103,11 +147,97 @@ impl<T: Into<u8> + Display + Clone + std::fmt::Debug> FtsFilter<T> {
         }
     }
 
+    pub fn has_phrase(
+        field: Field<T>,
+        text: impl Into<String>,
+        language: Language,
+        slop: u32,
+    ) -> Self {
+        FtsFilter::Phrase {
+            field,
+            text: text.into(),
+            language,
+            slop,
+        }
+    }
+
+    pub fn has_prefix(field: Field<T>, value: impl Into<String>) -> Self {
+        FtsFilter::Prefix {
+            field,
+            value: value.into(),
+        }
+    }
+
+    pub fn has_wildcard(field: Field<T>, value: impl Into<String>) -> Self {
+        FtsFilter::Wildcard {
+            field,
+            value: value.into(),
+        }
+    }
+
+    pub fn has_header(name: impl Into<String>, value: impl Into<String>) -> Self {
+        FtsFilter::Header {
+            name: name.into(),
+            value: value.into(),
+        }
+    }
+
+    pub fn has_envelope_from(address: impl Into<String>) -> Self {
+        FtsFilter::EnvelopeFrom(address.into())
+    }
+
+    pub fn has_envelope_to(address: impl Into<String>) -> Self {
+        FtsFilter::EnvelopeTo(address.into())
+    }
+
+    pub fn has_attachment(has_attachment: bool) -> Self {
+        FtsFilter::HasAttachment(has_attachment)
+    }
+
+    pub fn has_flag(flag: SystemFlag, is_set: bool) -> Self {
+        FtsFilter::Flag(flag, is_set)
+    }
+
+    pub fn has_reference(message_id: impl Into<String>) -> Self {
+        FtsFilter::References(message_id.into())
+    }
+
     pub fn has_english_text(field: Field<T>, text: impl Into<String>) -> Self {
         Self::has_text(field, text, Language::English)
     }
 }
 
+impl SystemFlag {
+    pub const ALL: [SystemFlag; 5] = [
+        SystemFlag::Seen,
+        SystemFlag::Answered,
+        SystemFlag::Flagged,
+        SystemFlag::Draft,
+        SystemFlag::Deleted,
+    ];
+
+    /// Parses a JMAP keyword such as `$seen` or an IMAP flag such as `\Seen`,
+    /// regardless of case.
+    pub fn parse(keyword: &str) -> Option<Self> {
+        let name = keyword
+            .strip_prefix('$')
+            .or_else(|| keyword.strip_prefix('\\'))?;
+        SystemFlag::ALL
+            .into_iter()
+            .find(|flag| flag.name().eq_ignore_ascii_case(name))
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            SystemFlag::Seen => "seen",
+            SystemFlag::Answered => "answered",
+            SystemFlag::Flagged => "flagged",
+            SystemFlag::Draft => "draft",
+            SystemFlag::Deleted => "deleted",
+        }
+    }
+}
+
 #[derive(Clone, Copy)]
 pub enum FilterType {
     And,
@@ -78,10 +78,18 @@ impl Store {
         let mut token_count = AHashMap::new();
         for filter in filters {
             let filter = match filter {
+                // Term positions are only stored for adjacent tokens, so phrases
+                // are matched exactly regardless of the slop
                 FtsFilter::Exact {
                     field,
                     text,
                     language,
+                }
+                | FtsFilter::Phrase {
+                    field,
+                    text,
+                    language,
+                    ..
                 } => {
                     let mut tokens = Vec::new();
                     let field = TokenType::word(field.into());
@@ -127,6 +135,47 @@ impl Store {
                         token: hash,
                     }
                 }
+                FtsFilter::Prefix { .. } | FtsFilter::Wildcard { .. } => {
+                    return Err(crate::Error::InternalError(
+                        "Prefix and wildcard filters are not supported by the full-text store"
+                            .to_string(),
+                    ));
+                }
+                FtsFilter::SizeRange { .. } => {
+                    return Err(crate::Error::InternalError(
+                        "Size filters are not supported by the full-text store".to_string(),
+                    ));
+                }
+                FtsFilter::Header { .. } => {
+                    return Err(crate::Error::InternalError(
+                        "Header name filters are not supported by the full-text store".to_string(),
+                    ));
+                }
+                FtsFilter::InMailbox(_) => {
+                    return Err(crate::Error::InternalError(
+                        "Mailbox filters are not supported by the full-text store".to_string(),
+                    ));
+                }
+                FtsFilter::EnvelopeFrom(_) | FtsFilter::EnvelopeTo(_) => {
+                    return Err(crate::Error::InternalError(
+                        "Envelope filters are not supported by the full-text store".to_string(),
+                    ));
+                }
+                FtsFilter::References(_) => {
+                    return Err(crate::Error::InternalError(
+                        "Reference filters are not supported by the full-text store".to_string(),
+                    ));
+                }
+                FtsFilter::Flag(..) => {
+                    return Err(crate::Error::InternalError(
+                        "Flag filters are not supported by the full-text store".to_string(),
+                    ));
+                }
+                FtsFilter::HasAttachment(_) => {
+                    return Err(crate::Error::InternalError(
+                        "Attachment filters are not supported by the full-text store".to_string(),
+                    ));
+                }
                 FtsFilter::And => FtsTokenized::And,
                 FtsFilter::Or => FtsTokenized::Or,
                 FtsFilter::Not => FtsTokenized::Not,
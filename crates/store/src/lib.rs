@@ -149,6 +149,7 @@ impl Default for BlobClass {
 pub enum Error {
     InternalError(String),
     AssertValueFailed,
+    Timeout,
 }
 
 impl std::error::Error for Error {}
@@ -158,6 +159,7 @@ impl Display for Error {
         match self {
             Error::InternalError(msg) => write!(f, "Internal Error: {}", msg),
             Error::AssertValueFailed => write!(f, "Transaction failed: Hash mismatch"),
+            Error::Timeout => write!(f, "Request timed out"),
         }
     }
 }